@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde_json::{json, Value};
+use thiserror::Error;
+use crate::config::SchemaRegistryConfig;
+use crate::debezium::{change_op, row_images};
+use crate::executor::{DmlMessage, FieldMeta};
+
+/// Confluent wire format 的第一个字节
+const MAGIC_BYTE: u8 = 0;
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("请求schema注册中心失败: {0}")]
+    Transport(String),
+    /// 409为和已有版本不兼容，422为schema无效
+    #[error("schema注册中心拒绝了schema({status}): {body}，列类型变化需要把subject的兼容级别改为NONE")]
+    Rejected { status: u16, body: String },
+    #[error("schema注册中心的响应无效: {0}")]
+    Response(String),
+    #[error("AVRO格式需要配置schema_registry")]
+    NotConfigured,
+}
+
+#[derive(Error, Debug)]
+pub enum AvroError {
+    #[error(transparent)]
+    Registry(#[from] RegistryError),
+    /// 不同的列名转换为Avro名称后相同，schema无效
+    #[error("表{table}的列{first}和{second}转换后的Avro字段名都是{name}")]
+    NameCollision { table: String, first: String, second: String, name: String },
+    /// 值和schema中的类型对不上，不能写成null丢掉数据
+    #[error("表{table}的列{column}的值{value}不能编码为Avro的{tp}")]
+    Value { table: String, column: String, value: String, tp: &'static str },
+}
+
+/// Confluent兼容的schema注册中心客户端，每个worker一个，注册过的schema缓存在本地
+pub struct SchemaRegistry {
+    url: String,
+    auth: Option<String>,
+    agent: ureq::Agent,
+    /// (subject, schema) => schema id
+    ids: HashMap<(String, String), u32>,
+}
+
+impl SchemaRegistry {
+    pub fn new(config: &SchemaRegistryConfig) -> Self {
+        let auth = config.username.as_ref().map(|user| {
+            let password = config.password.clone().unwrap_or_default();
+            format!("Basic {}", STANDARD.encode(format!("{user}:{password}")))
+        });
+        let agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build();
+        Self { url: config.url.trim_end_matches('/').to_string(), auth, agent, ids: HashMap::new() }
+    }
+
+    /// 注册schema并返回id，同样的schema重复注册会返回已有的id
+    pub fn register(&mut self, subject: &str, schema: &str) -> Result<u32, RegistryError> {
+        let cache_key = (subject.to_string(), schema.to_string());
+        if let Some(id) = self.ids.get(&cache_key) {
+            return Ok(*id);
+        }
+        let mut request = self.agent.post(&format!("{}/subjects/{subject}/versions", self.url))
+            .set("Content-Type", "application/vnd.schemaregistry.v1+json");
+        if let Some(auth) = &self.auth {
+            request = request.set("Authorization", auth);
+        }
+        let body = json!({ "schema": schema }).to_string();
+        let resp = match request.send_string(&body) {
+            Ok(resp) => resp,
+            Err(ureq::Error::Status(status, resp)) if status == 409 || status == 422 => {
+                return Err(RegistryError::Rejected { status, body: resp.into_string().unwrap_or_default() });
+            },
+            Err(err) => return Err(RegistryError::Transport(err.to_string()))
+        };
+        let text = resp.into_string().map_err(|err| RegistryError::Transport(err.to_string()))?;
+        let id = serde_json::from_str::<Value>(&text).ok()
+            .and_then(|v| v["id"].as_u64())
+            .ok_or_else(|| RegistryError::Response(text.clone()))? as u32;
+        info!("schema注册成功 subject:{subject} id:{id}");
+        self.ids.insert(cache_key, id);
+        Ok(id)
+    }
+
+    /// 注册中心不可用时一直重试，不能跳过消息；schema被拒绝或者响应无效时重试也没用，返回错误由调用方停止。
+    /// 列的类型变化(比如int改为bigint)在默认的BACKWARD兼容级别下会被拒绝，需要调整subject的兼容级别
+    pub fn schema_id(&mut self, subject: &str, schema: &str) -> Result<u32, RegistryError> {
+        loop {
+            match self.register(subject, schema) {
+                Ok(id) => return Ok(id),
+                Err(RegistryError::Transport(err)) => {
+                    warn!("请求schema注册中心失败: {err}，1秒后重试");
+                    std::thread::sleep(Duration::from_secs(1));
+                },
+                Err(err) => {
+                    error!("{err}，subject:{subject} schema:{schema}");
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// Avro的名称只能包含字母、数字和下划线，不能以数字开头
+fn avro_name(name: &str) -> String {
+    let mut s: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if !s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        s.insert(0, '_');
+    }
+    s
+}
+
+/// 数字列用Avro的数字类型，BIGINT UNSIGNED 超出long的范围，和 DECIMAL、日期时间一样用字符串
+fn column_type(meta: &FieldMeta) -> &'static str {
+    let tp = meta.field_type.to_lowercase();
+    let unsigned = meta.is_unsigned();
+    if tp.starts_with("tinyint") || tp.starts_with("smallint") || tp.starts_with("mediumint") || tp.starts_with("year") {
+        return "int";
+    }
+    if tp.starts_with("int") {
+        return if unsigned { "long" } else { "int" };
+    }
+    if tp.starts_with("bigint") {
+        return if unsigned { "string" } else { "long" };
+    }
+    // BIT(64)和未知位数的BIT可能超出long的范围
+    if tp.starts_with("bit(") && tp != "bit(64)" {
+        return "long";
+    }
+    if tp.starts_with("float") || tp.starts_with("double") || tp.starts_with("real") {
+        return "double";
+    }
+    "string"
+}
+
+/// 表的schema，前后镜像为同一个Value记录。所有列都可以为null并且默认为null，
+/// DDL增删列之后注册的新版本和旧版本相互兼容；修改列类型不兼容，注册会被拒绝
+fn envelope_schema(message: &DmlMessage, fields: &[FieldMeta]) -> Result<(String, Value), AvroError> {
+    let namespace = format!("{}.{}", avro_name(&message.database), avro_name(&message.table));
+    let mut names: HashMap<String, &String> = HashMap::new();
+    for meta in fields.iter() {
+        let name = avro_name(&meta.name);
+        if let Some(first) = names.insert(name.clone(), &meta.name) {
+            let table = format!("{}.{}", message.database, message.table);
+            return Err(AvroError::NameCollision { table, first: first.clone(), second: meta.name.clone(), name });
+        }
+    }
+    let columns: Vec<Value> = fields.iter().map(|meta| json!({
+        "name": avro_name(&meta.name),
+        "type": ["null", column_type(meta)],
+        "default": null
+    })).collect();
+    let schema = json!({
+        "type": "record",
+        "name": "Envelope",
+        "namespace": namespace,
+        "fields": [
+            { "name": "before", "type": ["null", { "type": "record", "name": "Value", "fields": columns }], "default": null },
+            { "name": "after", "type": ["null", "Value"], "default": null },
+            { "name": "op", "type": "string" },
+            { "name": "database", "type": "string" },
+            { "name": "table", "type": "string" },
+            { "name": "es", "type": "long" },
+            { "name": "ts", "type": "long" }
+        ]
+    });
+    Ok((format!("{namespace}.Envelope"), schema))
+}
+
+/// 每一行编码为一条消息：0x00 + 4字节大端序schema id + Avro二进制。
+/// subject 使用 TopicRecordNameStrategy(主题-记录全名)，同一个主题中可以有多张表
+pub fn encode(message: &DmlMessage, fields: &[FieldMeta], topic: &str, registry: &mut SchemaRegistry) -> Result<Vec<Vec<u8>>, AvroError> {
+    let (record_name, schema) = envelope_schema(message, fields)?;
+    let schema_id = registry.schema_id(&format!("{topic}-{record_name}"), &schema.to_string())?;
+    encode_rows(message, fields, schema_id)
+}
+
+fn encode_rows(message: &DmlMessage, fields: &[FieldMeta], schema_id: u32) -> Result<Vec<Vec<u8>>, AvroError> {
+    let mut messages = Vec::new();
+    for idx in 0..message.data.len() {
+        let mut buf = vec![MAGIC_BYTE];
+        buf.extend_from_slice(&schema_id.to_be_bytes());
+        let (before, after) = row_images(message, idx);
+        for image in [before, after] {
+            match image {
+                Some(row) => {
+                    write_long(&mut buf, 1);
+                    for meta in fields.iter() {
+                        let tp = column_type(meta);
+                        write_column(&mut buf, tp, row.get(&meta.name)).map_err(|value| AvroError::Value {
+                            table: format!("{}.{}", message.database, message.table), column: meta.name.clone(), value, tp
+                        })?;
+                    }
+                },
+                None => write_long(&mut buf, 0)
+            }
+        }
+        write_string(&mut buf, change_op(message));
+        write_string(&mut buf, &message.database);
+        write_string(&mut buf, &message.table);
+        write_long(&mut buf, message.es as i64);
+        write_long(&mut buf, message.ts as i64);
+        messages.push(buf);
+    }
+    Ok(messages)
+}
+
+/// int和long都是zigzag编码的变长整数
+fn write_long(buf: &mut Vec<u8>, n: i64) {
+    let mut z = ((n << 1) ^ (n >> 63)) as u64;
+    while z >= 0x80 {
+        buf.push((z as u8 & 0x7f) | 0x80);
+        z >>= 7;
+    }
+    buf.push(z as u8);
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    write_long(buf, s.len() as i64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// ["null", 类型] 的union，先写分支序号。值和列类型对不上时返回值的文本
+fn write_column(buf: &mut Vec<u8>, tp: &str, val: Option<&Value>) -> Result<(), String> {
+    let val = match val {
+        Some(Value::Null) | None => {
+            write_long(buf, 0);
+            return Ok(());
+        },
+        Some(val) => val
+    };
+    match tp {
+        "int" | "long" => {
+            let num = val.as_i64()
+                .or_else(|| val.as_str().and_then(|s| s.parse::<i64>().ok()))
+                .filter(|num| tp == "long" || i32::try_from(*num).is_ok())
+                .ok_or_else(|| val.to_string())?;
+            write_long(buf, 1);
+            write_long(buf, num);
+        },
+        "double" => {
+            let num = val.as_f64()
+                .or_else(|| val.as_str().and_then(|s| s.parse::<f64>().ok()))
+                .ok_or_else(|| val.to_string())?;
+            write_long(buf, 1);
+            buf.extend_from_slice(&num.to_le_bytes());
+        },
+        _ => {
+            write_long(buf, 1);
+            match val.as_str() {
+                Some(s) => write_string(buf, s),
+                None => write_string(buf, &val.to_string())
+            }
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn long_bytes(n: i64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_long(&mut buf, n);
+        buf
+    }
+
+    /// 只处理一个请求的schema注册中心，返回收到的请求
+    fn mock_registry(status: &'static str, body: &'static str) -> (String, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text.lines()
+                        .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + length || n == 0 {
+                        break;
+                    }
+                }
+            }
+            let resp = format!("HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+            stream.write_all(resp.as_bytes()).unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+        (url, handle)
+    }
+
+    fn registry(url: String) -> SchemaRegistry {
+        SchemaRegistry::new(&SchemaRegistryConfig{ url, username: Some("user".to_string()), password: Some("pass".to_string()) })
+    }
+
+    #[test]
+    fn write_long_is_zigzag_varint() {
+        assert_eq!(long_bytes(0), vec![0]);
+        assert_eq!(long_bytes(-1), vec![1]);
+        assert_eq!(long_bytes(1), vec![2]);
+        assert_eq!(long_bytes(-64), vec![0x7f]);
+        assert_eq!(long_bytes(64), vec![0x80, 0x01]);
+        assert_eq!(long_bytes(i64::MAX), vec![0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert_eq!(long_bytes(i64::MIN), vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+    }
+
+    #[test]
+    fn write_column_writes_union_branch() {
+        let column = |tp: &str, val: Option<Value>| {
+            let mut buf = Vec::new();
+            write_column(&mut buf, tp, val.as_ref()).unwrap();
+            buf
+        };
+        assert_eq!(column("int", None), vec![0]);
+        assert_eq!(column("string", Some(Value::Null)), vec![0]);
+        assert_eq!(column("int", Some(json!(-1))), vec![2, 1]);
+        // number_as_string 时数字是字符串
+        assert_eq!(column("long", Some(json!("64"))), vec![2, 0x80, 0x01]);
+        let mut double = vec![2];
+        double.extend_from_slice(&1.5f64.to_le_bytes());
+        assert_eq!(column("double", Some(json!("1.5"))), double);
+        assert_eq!(column("string", Some(json!("ab"))), vec![2, 4, b'a', b'b']);
+        assert_eq!(column("string", Some(json!({"a": 1}))), [vec![2, 14], br#"{"a":1}"#.to_vec()].concat());
+    }
+
+    #[test]
+    fn unencodable_value_is_an_error() {
+        let mut buf = Vec::new();
+        assert_eq!(write_column(&mut buf, "int", Some(&json!("abc"))), Err(r#""abc""#.to_string()));
+        assert_eq!(write_column(&mut buf, "int", Some(&json!(4294967295u32))), Err("4294967295".to_string()));
+        assert_eq!(write_column(&mut buf, "long", Some(&json!(u64::MAX))), Err(u64::MAX.to_string()));
+        assert!(buf.is_empty());
+
+        let field = |name: &str, tp: &str| FieldMeta{ name: name.to_string(), field_type: tp.to_string(), is_pk: false, charset: None };
+        assert_eq!(column_type(&field("b", "bit(8)")), "long");
+        // BIT(64)超出long的范围
+        assert_eq!(column_type(&field("b", "bit(64)")), "string");
+
+        let fields = vec![field("n", "int(11)")];
+        let message = DmlMessage{
+            id: 1, database: "db".to_string(), table: "t".to_string(), pkNames: None, isDdl: false,
+            r#type: "INSERT".to_string(), es: 1, ts: 2, sql: None, sqlType: HashMap::new(), mysqlType: HashMap::new(),
+            data: vec![[("n".to_string(), json!("x"))].into_iter().collect()], old: None, xid: None, commitTs: None
+        };
+        match encode_rows(&message, &fields, 1) {
+            Err(AvroError::Value { table, column, tp, .. }) => assert_eq!((table.as_str(), column.as_str(), tp), ("db.t", "n", "int")),
+            other => panic!("{other:?}")
+        }
+    }
+
+    #[test]
+    fn colliding_field_names_are_reported() {
+        let field = |name: &str| FieldMeta{ name: name.to_string(), field_type: "int(11)".to_string(), is_pk: false, charset: None };
+        let message = DmlMessage{
+            id: 1, database: "db".to_string(), table: "t".to_string(), pkNames: None, isDdl: false,
+            r#type: "INSERT".to_string(), es: 1, ts: 2, sql: None, sqlType: HashMap::new(), mysqlType: HashMap::new(),
+            data: vec![], old: None, xid: None, commitTs: None
+        };
+        assert!(envelope_schema(&message, &[field("a_b"), field("c")]).is_ok());
+        match envelope_schema(&message, &[field("a_b"), field("a-b")]) {
+            Err(AvroError::NameCollision { first, second, name, .. }) => assert_eq!((first.as_str(), second.as_str(), name.as_str()), ("a_b", "a-b", "a_b")),
+            other => panic!("{other:?}")
+        }
+    }
+
+    #[test]
+    fn encoded_rows_start_with_wire_format_header() {
+        let fields = vec![FieldMeta{ name: "id".to_string(), field_type: "int".to_string(), is_pk: true, charset: None }];
+        let message = DmlMessage{
+            id: 1, database: "db".to_string(), table: "t".to_string(), pkNames: Some(vec!["id".to_string()]), isDdl: false,
+            r#type: "INSERT".to_string(), es: 1, ts: 2, sql: None, sqlType: HashMap::new(), mysqlType: HashMap::new(),
+            data: vec![[("id".to_string(), json!(3))].into_iter().collect()], old: None, xid: None, commitTs: None
+        };
+        let messages = encode_rows(&message, &fields, 0x01020304).unwrap();
+        assert_eq!(messages.len(), 1);
+        let expected = [
+            vec![MAGIC_BYTE, 0x01, 0x02, 0x03, 0x04],
+            // before为null，after为Value{id: 3}
+            vec![0, 2, 2, 6],
+            vec![2, b'c', 4, b'd', b'b', 2, b't'],
+            vec![2, 4],
+        ].concat();
+        assert_eq!(messages[0], expected);
+    }
+
+    #[test]
+    fn register_returns_schema_id() {
+        let (url, handle) = mock_registry("200 OK", r#"{"id":42}"#);
+        let mut registry = registry(url);
+        assert_eq!(registry.schema_id("test-db.t.Envelope", r#"{"type":"string"}"#).unwrap(), 42);
+        let request = handle.join().unwrap();
+        assert!(request.starts_with("POST /subjects/test-db.t.Envelope/versions "));
+        assert!(request.contains("Basic dXNlcjpwYXNz"));
+        assert!(request.contains(r#"{"schema":"{\"type\":\"string\"}"}"#));
+        // 已注册的schema使用缓存，不再请求
+        assert_eq!(registry.schema_id("test-db.t.Envelope", r#"{"type":"string"}"#).unwrap(), 42);
+    }
+
+    #[test]
+    fn incompatible_schema_is_returned_as_error() {
+        let (url, handle) = mock_registry("409 Conflict", r#"{"error_code":409,"message":"incompatible"}"#);
+        let mut registry = registry(url);
+        match registry.schema_id("test-db.t.Envelope", r#"{"type":"string"}"#) {
+            Err(RegistryError::Rejected { status, body }) => {
+                assert_eq!(status, 409);
+                assert!(body.contains("incompatible"));
+            },
+            other => panic!("{other:?}")
+        }
+        handle.join().unwrap();
+    }
+}
@@ -1,25 +1,29 @@
-use std::collections::HashMap;
-use std::fmt::{Debug, Formatter};
-use bitflags::Flags;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Display, Formatter};
 use bytes::BytesMut;
-use nom::error::{Error, ErrorKind, VerboseError, VerboseErrorKind};
-use nom::{AsBytes, AsChar, IResult, Err as NomErr};
+use nom::error::{Error, ErrorKind};
+use nom::{AsBytes, IResult, Err as NomErr};
 use serde_json::{Value};
-use crate::mysql::{Decoder, read_fps, take_bytes, take_eof_string, take_fix_string, take_i_int3, take_i_int4, take_i_int8, take_int1, take_int2, take_int3, take_int4, take_int6, take_int8, take_int_n, take_utf8_end_of_null};
+use crate::mysql::{Decoder, Encoder, read_fps, take_bytes, take_eof_string, take_i_int3, take_i_int4, take_i_int8, take_int1, take_int2, take_int3, take_int4, take_int6, take_int8, take_int_n, take_utf8_end_of_null};
 use crate::protocal::{ VLenInt};
+use crate::json_binary::{apply_json_diff, decode_json_binary, decode_json_diff};
 use std::io::{Cursor};
 use byteorder::{BigEndian, ReadBytesExt};
+use bytes::BufMut;
+use thiserror::Error;
+use chrono::{DateTime, FixedOffset, Local};
+use crate::config::{Config, ZeroDatePolicy};
 
 
 const DIG_PER_DEC: usize = 9;
 const COMPRESSED_BYTES: [usize; 10] = [0, 1, 1, 2, 2, 3, 3, 4, 4, 4];
 
-struct DecimalVal {
-    val: String
+pub struct DecimalVal {
+    pub val: String
 }
 
 impl DecimalVal {
-    fn decode(input: &[u8], precision: u8, scale: u8) -> IResult<&[u8], Self> {
+    pub fn decode(input: &[u8], precision: u8, scale: u8) -> IResult<&[u8], Self> {
         //info!("precision:{}, scale:{}", precision, scale);
         let integral = precision - scale;
         let uncomp_intg = integral / DIG_PER_DEC as u8;
@@ -137,7 +141,6 @@ fn read_bits(input: &[u8], start: usize, count: usize) -> u32 {
 bitflags::bitflags! {
     /// https://dev.mysql.com/doc/dev/mysql-server/latest/group__group__cs__binglog__event__header__flags.html
     #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-    #[cfg_attr(feature="serde", serde::Serialize, serde::DeSerialize)]
     pub struct EventHeaderFlag: u16 {
         /// If the query depends on the thread (for example: TEMPORARY TABLE)
         const LOG_EVENT_THREAD_SPECIFIC_F=   0x4;
@@ -172,10 +175,20 @@ pub enum ColumnType {
     DATETIME = 18,             // 8
     YEAR = 13,                 // 1
     TIMESTAMP = 17,            // 4
+    // 5.6之前的旧格式，没有小数秒
+    OLD_TIMESTAMP = 7,         // 4
+    OLD_TIME = 11,             // 3
+    OLD_DATETIME = 12,         // 8
+    NEWDATE = 14,              // 3
     // above are date & time type
     CHAR = 254,                // String
     VARCHAR = 15,
-    TEXT = 252,
+    TEXT = 252,                // BLOB & TEXT
+    JSON = 245,
+    ENUM = 247,                // 在binlog中以CHAR(254)的real_type出现
+    SET = 248,
+    BIT = 16,
+    GEOMETRY = 255,
     NOT_MATCH =-1
 }
 
@@ -195,36 +208,89 @@ impl From<u8> for ColumnType {
             18=>Self::DATETIME,
             13=>Self::YEAR,
             17=>Self::TIMESTAMP,
+            7=>Self::OLD_TIMESTAMP,
+            11=>Self::OLD_TIME,
+            12=>Self::OLD_DATETIME,
+            14=>Self::NEWDATE,
             254=>Self::CHAR,
             15=>Self::VARCHAR,
-            252=>Self::TEXT,
+            249..=252=>Self::TEXT,
+            245=>Self::JSON,
+            247=>Self::ENUM,
+            248=>Self::SET,
+            16=>Self::BIT,
+            255=>Self::GEOMETRY,
             _=>Self::NOT_MATCH,
         }
     }
 }
 
 impl ColumnType {
-    fn decode_val<'a>(tp: ColumnType, input: &'a [u8], meta: &ColMeta) -> IResult<&'a [u8], Value> {
+    fn decode_val<'a>(tp: ColumnType, input: &'a [u8], meta: &ColMeta, temporal: &TemporalFormat) -> IResult<&'a [u8], ColumnValue> {
+        match tp {
+            // 字符串保留原始字节，输出时再按列的类型决定解码还是编码
+            Self::CHAR | Self::VARCHAR=>{
+                // max_length是字节数(字符数 * 字符集最大字节数)，多字节字符集的CHAR(86)以上就需要2字节的长度
+                let max_length = meta.max_length.unwrap_or(0u16);
+                let len_size = if max_length > 255u16 { 2usize } else { 1usize };
+                let (i, slen) = take_int_n(input, len_size)?;
+                let (i, bs) = take_bytes(i, slen as usize)?;
+                let mut bs = Vec::from(bs);
+                // CHAR写入binlog时去掉了末尾的填充，BINARY(n)的填充是0x00，需要补回n个字节
+                if matches!(tp, Self::CHAR) && meta.binary && bs.len() < max_length as usize {
+                    bs.resize(max_length as usize, 0u8);
+                }
+                Ok((i, ColumnValue::Bytes(bs)))
+            },
+            Self::TEXT=>{
+                let length_size = meta.length_size.unwrap_or(1u8);
+                let (i, str_len) = take_int_n(input, length_size as usize)?;
+                let (i, bs) = take_bytes(i, str_len as usize)?;
+                Ok((i, ColumnValue::Bytes(Vec::from(bs))))
+            },
+            _ => {
+                let (i, val) = Self::decode_value(tp, input, meta, temporal)?;
+                Ok((i, ColumnValue::Value(val)))
+            }
+        }
+    }
+
+    fn decode_value<'a>(tp: ColumnType, input: &'a [u8], meta: &ColMeta, temporal: &TemporalFormat) -> IResult<&'a [u8], Value> {
         match tp {
             Self::TINYINT=>{
                 let (i, val) = take_int1(input)?;
-                Ok((i, Value::from(val as u8)))
+                if meta.unsigned { Ok((i, Value::from(val))) } else { Ok((i, Value::from(val as i8))) }
             },
             Self::SMALLINT=>{
                 let (i, val) = take_int2(input)?;
-                Ok((i, Value::from(val as u16)))
+                if meta.unsigned { Ok((i, Value::from(val))) } else { Ok((i, Value::from(val as i16))) }
             },
             Self::MEDIUMINT=>{
-                let (i, val) = take_i_int3(input)?;
-                Ok((i, Value::from(val)))
+                if meta.unsigned {
+                    let (i, val) = take_int3(input)?;
+                    Ok((i, Value::from(val)))
+                } else {
+                    let (i, val) = take_i_int3(input)?;
+                    Ok((i, Value::from(val)))
+                }
             },
             Self::INT=>{
-                let (i, val) = take_i_int4(input)?;
-                Ok((i, Value::from(val)))
+                if meta.unsigned {
+                    let (i, val) = take_int4(input)?;
+                    Ok((i, Value::from(val)))
+                } else {
+                    let (i, val) = take_i_int4(input)?;
+                    Ok((i, Value::from(val)))
+                }
             },
             Self::BIGINT=>{
-                let (i, val) = take_i_int8(input)?;
-                Ok((i, Value::from(val)))
+                if meta.unsigned {
+                    let (i, val) = take_int8(input)?;
+                    Ok((i, Value::from(val)))
+                } else {
+                    let (i, val) = take_i_int8(input)?;
+                    Ok((i, Value::from(val)))
+                }
             },
             Self::FLOAT=>{
                 let (i, bs) = take_bytes(input, 4usize)?;
@@ -246,36 +312,33 @@ impl ColumnType {
                 let (i, dec) = DecimalVal::decode(input, precision as u8, decimals as u8)?;
                 Ok((i, Value::from(dec.val)))
             },
-            Self::DATE=>{
-                let (i, time) = take_int3(input)?;
-                if time == 0{
-                    Ok((i, Value::Null))
-                }else{
-                    let year = (time & ((1 << 15) - 1) << 9) >> 9;
-                    let month = (time & ((1 << 4) - 1) << 5) >> 5;
-                    let day = time & ((1 << 5) - 1);
-                    Ok((i, Value::from(format!("{:02}-{:02}-{:02}", year, month, day))))
-                }
+            Self::DATE | Self::NEWDATE=>{
+                let (i, date) = take_int3(input)?;
+                let year = date >> 9;
+                let month = (date >> 5) % (1 << 4);
+                let day = date % (1 << 5);
+                Ok((i, temporal.format_datetime(year, month, day, None)))
             },
             Self::TIME=>{
-                let (i, time) = take_bytes(input, 3usize)?;
-                let sign = read_bits(time, 0, 1);
-                let mut buffer = [0u8; 4];
-                let data = if sign < 1 {
-                    let n = u32::from_le_bytes([time[0], time[1], time[2], 0]);
-                    let n = !n + 1;
-                    let bs = u32::to_le_bytes(n);
-                    buffer.copy_from_slice(&bs);
-                    &buffer[..3]
-                }else{ time };
-                let hours= read_bits(data, 2, 10);
-                let minutes = read_bits(data, 12, 6);
-                let seconds = read_bits(data,18, 6);
-                let (i, microsecond) = read_fps(i, meta.fsp.unwrap_or(0u8))?;
-                let val = format!("{hours:02}:{minutes:02}:{seconds:02}.{microsecond}");
-                Ok((i, Value::from(val)))
+                let fsp = meta.fsp.unwrap_or(0u8);
+                let (i, packed) = take_time2_packed(input, fsp)?;
+                let negative = packed < 0;
+                let packed = packed.unsigned_abs();
+                let hms = packed >> 24;
+                let micros = (packed % (1 << 24)) as u32;
+                let hours = (hms >> 12) % (1 << 10);
+                let minutes = (hms >> 6) % (1 << 6);
+                let seconds = hms % (1 << 6);
+                Ok((i, Value::from(TemporalFormat::format_time(negative, hours as u32, minutes as u32, seconds as u32, micros, fsp))))
+            },
+            Self::OLD_TIME=>{
+                // HHMMSS 形式的整数
+                let (i, val) = take_i_int3(input)?;
+                let abs = val.unsigned_abs();
+                Ok((i, Value::from(TemporalFormat::format_time(val < 0, abs / 10000, abs / 100 % 100, abs % 100, 0, 0))))
             },
             Self::DATETIME=>{
+                let fsp = meta.fsp.unwrap_or(0u8);
                 let (i, dt) = take_bytes(input, 5usize)?;
                 let val = u64::from_be_bytes([0u8, 0u8, 0u8, dt[0], dt[1], dt[2], dt[3], dt[4]]) - 0x8000000000;
                 let d_val = val >> 17;
@@ -283,54 +346,214 @@ impl ColumnType {
                 let year = ((d_val >> 5) / 13) as u32;
                 let month = ((d_val >> 5) % 13) as u32;
                 let day = (d_val % (1 << 5)) as u32;
-                let hour = ((val >> 12) % (1 << 5)) as u32;
+                let hour = (t_val >> 12) as u32;
                 let minute = ((t_val >> 6) % (1 << 6)) as u32;
                 let second = (t_val % (1 << 6)) as u32;
-                let (i, microsecond) = read_fps(i, meta.fsp.unwrap_or(0u8))?;
-                if meta.fsp.unwrap_or(0u8) > 0u8 {
-                    let datetime_str = format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}.{microsecond:03}");
-                    Ok((i, Value::from(datetime_str)))
-                }else{
-                    let datetime_str = format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}");
-                    Ok((i, Value::from(datetime_str)))
-                }
+                let (i, microsecond) = read_fps(i, fsp)?;
+                Ok((i, temporal.format_datetime(year, month, day, Some((hour, minute, second, microsecond, fsp)))))
+            },
+            Self::OLD_DATETIME=>{
+                // YYYYMMDDHHMMSS 形式的整数
+                let (i, val) = take_int8(input)?;
+                let (date, time) = (val / 1000000, val % 1000000);
+                let (year, month, day) = ((date / 10000) as u32, (date / 100 % 100) as u32, (date % 100) as u32);
+                let (hour, minute, second) = ((time / 10000) as u32, (time / 100 % 100) as u32, (time % 100) as u32);
+                Ok((i, temporal.format_datetime(year, month, day, Some((hour, minute, second, 0, 0)))))
             },
             Self::YEAR=>{
+                // 0表示0000年
                 let (i, dt) = take_int1(input)?;
-                Ok((i, Value::from(1900u16 + dt as u16)))
+                Ok((i, Value::from(if dt == 0 { 0u16 } else { 1900u16 + dt as u16 })))
             }
             Self::TIMESTAMP=>{
+                let fsp = meta.fsp.unwrap_or(0u8);
                 let (i, bs) = take_bytes(input, 4usize)?;
-                let mut val = [0u8; 4];
-                val.copy_from_slice(bs);
-                let val = i32::from_be_bytes(val);
-                let (i, microsecond) = read_fps(i, meta.fsp.unwrap_or(0u8))?;
-                let ts = format!("{val}.{microsecond}");
-                Ok((i, Value::from(ts)))
+                let val = u32::from_be_bytes([bs[0], bs[1], bs[2], bs[3]]);
+                let (i, microsecond) = read_fps(i, fsp)?;
+                Ok((i, temporal.format_timestamp(val as i64, microsecond, fsp)))
             },
-            Self::CHAR | Self::VARCHAR=>{
-                if meta.max_length.unwrap_or(0u16) > 255u16 {
-                    let (i, slen) = take_int2(input)?;
-                    let (i, val_str) = take_fix_string(i, slen as usize)?;
-                    Ok((i, Value::from(val_str)))
-                }else{
-                    let (i, slen) = take_int1(input)?;
-                    let (i, val_str) = take_fix_string(i, slen as usize)?;
-                    Ok((i, Value::from(val_str)))
-                }
+            Self::OLD_TIMESTAMP=>{
+                let (i, val) = take_int4(input)?;
+                Ok((i, temporal.format_timestamp(val as i64, 0, 0)))
             },
-            Self::TEXT=>{
-                let length_size = meta.length_size.unwrap_or(1u8);
-                let (i, str_len) = take_int_n(input, length_size as usize)?;
-                //println!("BLOB str len:{str_len}");
-                let (i, bs) = take_bytes(i, str_len as usize)?;
-                let v = Value::from(bs);
+            Self::JSON=>{
+                let length_size = meta.length_size.unwrap_or(4u8);
+                let (i, json_len) = take_int_n(input, length_size as usize)?;
+                let (i, bs) = take_bytes(i, json_len as usize)?;
+                let (_, v) = decode_json_binary(bs)?;
                 Ok((i, v))
             },
+            Self::ENUM | Self::SET=>{
+                // ENUM为从1开始的序号，SET为位掩码，在executor中根据列定义转换为标签
+                let (i, val) = take_int_n(input, meta.size.unwrap_or(1u8) as usize)?;
+                Ok((i, Value::from(val)))
+            },
+            Self::BIT=>{
+                let (i, bs) = take_bytes(input, meta.size.unwrap_or(1u8) as usize)?;
+                let val = bs.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+                Ok((i, Value::from(val)))
+            },
+            Self::GEOMETRY=>{
+                let length_size = meta.length_size.unwrap_or(4u8);
+                let (i, geo_len) = take_int_n(input, length_size as usize)?;
+                // 4字节SRID + WKB
+                let (i, bs) = take_bytes(i, geo_len as usize)?;
+                Ok((i, Value::from(hex::encode(bs))))
+            },
             _=>Err(NomErr::Error(Error::new(input, ErrorKind::Eof)))
         }
     }
 
+    /// PARTIAL_UPDATE_ROWS_EVENT 中部分更新的 JSON 列只包含修改操作
+    fn decode_json_partial<'a>(input: &'a [u8], meta: &ColMeta) -> IResult<&'a [u8], ColumnValue> {
+        let length_size = meta.length_size.unwrap_or(4u8);
+        let (i, diff_len) = take_int_n(input, length_size as usize)?;
+        let (i, bs) = take_bytes(i, diff_len as usize)?;
+        let (_, v) = decode_json_diff(bs)?;
+        Ok((i, ColumnValue::Value(v)))
+    }
+
+}
+
+/// 一列的值，字符串和BLOB保留原始字节，生成消息时再按列的类型和配置转换
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnValue {
+    Value(Value),
+    Bytes(Vec<u8>),
+}
+
+impl Display for ColumnValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Value(val) => write!(f, "{val}"),
+            Self::Bytes(bs) => write!(f, "{}", hex::encode(bs)),
+        }
+    }
+}
+
+/// TIME2，和MySQL的 my_time_packed_from_binary 一致，返回 (时分秒<<24) + 微秒，负数表示负的时间。
+/// 负数的小数部分是反序存储的，整数部分需要加1
+fn take_time2_packed(input: &[u8], fsp: u8) -> IResult<&[u8], i64> {
+    let (i, bs) = take_bytes(input, 3usize)?;
+    let mut int_part = ((bs[0] as i64) << 16 | (bs[1] as i64) << 8 | bs[2] as i64) - 0x800000;
+    match fsp {
+        1 | 2 => {
+            let (i, frac) = take_int1(i)?;
+            let mut frac = frac as i64;
+            if int_part < 0 && frac > 0 {
+                int_part += 1;
+                frac -= 0x100;
+            }
+            Ok((i, (int_part << 24) + frac * 10000))
+        },
+        3 | 4 => {
+            let (i, frac) = take_bytes(i, 2usize)?;
+            let mut frac = u16::from_be_bytes([frac[0], frac[1]]) as i64;
+            if int_part < 0 && frac > 0 {
+                int_part += 1;
+                frac -= 0x10000;
+            }
+            Ok((i, (int_part << 24) + frac * 100))
+        },
+        5 | 6 => {
+            let (i, frac) = take_bytes(i, 3usize)?;
+            let frac = (frac[0] as i64) << 16 | (frac[1] as i64) << 8 | frac[2] as i64;
+            Ok((i, (int_part << 24) + frac))
+        },
+        _ => Ok((i, int_part << 24))
+    }
+}
+
+/// 日期时间的输出格式，日期和时间之间用空格分隔(RFC 3339)，小数秒位数和列定义的fsp一致
+#[derive(Debug, Clone)]
+pub struct TemporalFormat {
+    /// TIMESTAMP转换到的时区，None表示本机时区
+    pub time_zone: Option<FixedOffset>,
+    pub zero_date: ZeroDatePolicy,
+}
+
+impl Default for TemporalFormat {
+    fn default() -> Self {
+        Self{ time_zone: FixedOffset::east_opt(0), zero_date: ZeroDatePolicy::NULL }
+    }
+}
+
+impl TemporalFormat {
+    pub fn from_config(config: &Config) -> Self {
+        let tz = config.time_zone.clone().unwrap_or("UTC".to_string());
+        let time_zone = match Self::parse_time_zone(tz.as_str()) {
+            Some(time_zone) => time_zone,
+            None => {
+                error!("无法识别的时区:{tz}，使用UTC");
+                FixedOffset::east_opt(0)
+            }
+        };
+        Self{ time_zone, zero_date: config.zero_date.clone().unwrap_or(ZeroDatePolicy::NULL) }
+    }
+
+    /// 支持 SYSTEM(本机时区)、UTC 和 +08:00 形式的偏移
+    fn parse_time_zone(tz: &str) -> Option<Option<FixedOffset>> {
+        if tz.eq_ignore_ascii_case("SYSTEM") {
+            return Some(None);
+        }
+        if tz.eq_ignore_ascii_case("UTC") || tz == "Z" {
+            return Some(FixedOffset::east_opt(0));
+        }
+        let sign = match tz.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return None
+        };
+        let digits: String = tz[1..].chars().filter(|c| *c != ':').collect();
+        if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let hours: i32 = digits[..2].parse().ok()?;
+        let minutes: i32 = digits[2..].parse().ok()?;
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).map(Some)
+    }
+
+    fn fraction(micros: u32, fsp: u8) -> String {
+        if fsp == 0 {
+            return "".to_string();
+        }
+        let digits = format!("{micros:06}");
+        format!(".{}", &digits[..(fsp.min(6) as usize)])
+    }
+
+    fn format_time(negative: bool, hours: u32, minutes: u32, seconds: u32, micros: u32, fsp: u8) -> String {
+        let sign = if negative { "-" } else { "" };
+        format!("{sign}{hours:02}:{minutes:02}:{seconds:02}{}", Self::fraction(micros, fsp))
+    }
+
+    /// time为 (时, 分, 秒, 微秒, fsp)，DATE没有时间部分
+    fn format_datetime(&self, year: u32, month: u32, day: u32, time: Option<(u32, u32, u32, u32, u8)>) -> Value {
+        if year == 0 && month == 0 && day == 0 && self.zero_date == ZeroDatePolicy::NULL {
+            return Value::Null;
+        }
+        let date = format!("{year:04}-{month:02}-{day:02}");
+        match time {
+            Some((hour, minute, second, micros, fsp)) => Value::from(format!("{date} {}", Self::format_time(false, hour, minute, second, micros, fsp))),
+            None => Value::from(date)
+        }
+    }
+
+    /// 0表示零值时间，否则转换为配置时区的本地时间，和Canal一样不带时区偏移
+    fn format_timestamp(&self, seconds: i64, micros: u32, fsp: u8) -> Value {
+        if seconds == 0 && micros == 0 {
+            return self.format_datetime(0, 0, 0, Some((0, 0, 0, 0, fsp)));
+        }
+        let utc = match DateTime::from_timestamp(seconds, micros * 1000) {
+            Some(utc) => utc,
+            None => return Value::Null
+        };
+        let pattern = "%Y-%m-%d %H:%M:%S";
+        let datetime = match self.time_zone {
+            Some(tz) => utc.with_timezone(&tz).format(pattern).to_string(),
+            None => utc.with_timezone(&Local).format(pattern).to_string()
+        };
+        Value::from(format!("{datetime}{}", Self::fraction(micros, fsp)))
+    }
 }
 
 #[derive(Clone)]
@@ -341,7 +564,13 @@ pub struct ColMeta {
     pub length_size: Option<u8>,
     pub precision: Option<u8>,
     pub decimals: Option<u8>,
-    pub real_type: Option<u8>
+    pub real_type: Option<u8>,
+    /// BIT列的位数
+    pub bits: Option<u16>,
+    /// 整数列是否为unsigned，TABLE_MAP的类型中没有符号，需要从列信息中设置
+    pub unsigned: bool,
+    /// 字符集为binary的字符列(BINARY/VARBINARY/BLOB)
+    pub binary: bool
 }
 
 impl ColMeta {
@@ -354,6 +583,9 @@ impl ColMeta {
             precision: None,
             decimals: None,
             real_type: None,
+            bits: None,
+            unsigned: false,
+            binary: false,
         }
     }
 }
@@ -361,26 +593,35 @@ impl ColMeta {
 impl Debug for ColMeta {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut fs = f.debug_struct("Meta");
-        if self.max_length.is_some() {
-            fs.field("max_length", &self.max_length.unwrap());
+        if let Some(max_length) = self.max_length {
+            fs.field("max_length", &max_length);
         }
-        if self.size.is_some(){
-            fs.field("size", &self.size.unwrap());
+        if let Some(size) = self.size {
+            fs.field("size", &size);
         }
-        if self.fsp.is_some() {
-            fs.field("fsp", &self.fsp.unwrap());
+        if let Some(fsp) = self.fsp {
+            fs.field("fsp", &fsp);
         }
-        if self.length_size.is_some() {
-            fs.field("length_size", &self.length_size.unwrap());
+        if let Some(length_size) = self.length_size {
+            fs.field("length_size", &length_size);
         }
-        if self.precision.is_some() {
-            fs.field("precision", &self.precision.unwrap());
+        if let Some(precision) = self.precision {
+            fs.field("precision", &precision);
         }
-        if self.decimals.is_some() {
-            fs.field("decimals", &self.decimals.unwrap());
+        if let Some(decimals) = self.decimals {
+            fs.field("decimals", &decimals);
         }
-        if self.real_type.is_some() {
-            fs.field("real_type", &self.real_type.unwrap());
+        if let Some(real_type) = self.real_type {
+            fs.field("real_type", &real_type);
+        }
+        if let Some(bits) = self.bits {
+            fs.field("bits", &bits);
+        }
+        if self.unsigned {
+            fs.field("unsigned", &self.unsigned);
+        }
+        if self.binary {
+            fs.field("binary", &self.binary);
         }
         fs.finish()
     }
@@ -390,14 +631,20 @@ impl Debug for ColMeta {
 #[derive(Debug, Clone)]
 pub struct TableMap {
     pub mapping: HashMap<u64, Vec<ColumnType>>,
-    pub metas: HashMap<u64, Vec<ColMeta>>
+    pub metas: HashMap<u64, Vec<ColMeta>>,
+    pub temporal: TemporalFormat
 }
 
 impl TableMap {
     pub fn new() -> Self {
+        Self::with_temporal(TemporalFormat::default())
+    }
+
+    pub fn with_temporal(temporal: TemporalFormat) -> Self {
         Self{
             mapping: HashMap::new(),
-            metas: HashMap::new()
+            metas: HashMap::new(),
+            temporal
         }
     }
 
@@ -408,7 +655,6 @@ impl TableMap {
         for type_flag in cols_type {
             let tp = ColumnType::from(type_flag);
             let mut m = ColMeta::new();
-            types.push(tp);
             let meta = match tp {
                 ColumnType::VARCHAR=>{
                     if let Ok((ni, maxlength)) = take_int2(i){
@@ -417,86 +663,188 @@ impl TableMap {
                     }
                     m
                 },
-                ColumnType::CHAR => {
+                ColumnType::CHAR | ColumnType::ENUM | ColumnType::SET => {
+                    if let Ok((ni, bs)) = take_bytes(i, 2usize){
+                        let real_type = bs[0];
+                        if real_type == ColumnType::ENUM as u8 || real_type == ColumnType::SET as u8 {
+                            // ENUM/SET 第二个字节为存储长度
+                            m.real_type = Some(real_type);
+                            m.size = Some(bs[1]);
+                        }else{
+                            let metadata = (bs[0] as u16) << 8 | bs[1] as u16;
+                            let rs = (((metadata >> 4) & 0x300) ^ 0x300) + (metadata & 0x00FF);
+                            m.max_length = Some(rs);
+                        }
+                        i = ni;
+                    }
+                    m
+                }
+                ColumnType::BIT => {
                     if let Ok((ni, bs)) = take_bytes(i, 2usize){
-                        let metadata = (bs[0] as u16) << 8 | bs[1] as u16;
-                        let rs = (((metadata >> 4) & 0x300) ^ 0x300) + (metadata & 0x00FF);
-                        m.max_length = Some(rs);
+                        // 第一个字节为不足一字节的位数，第二个字节为整字节数
+                        let bits = bs[0];
+                        let bytes = bs[1];
+                        m.size = Some(bytes + bits.div_ceil(8));
+                        m.bits = Some(bytes as u16 * 8 + bits as u16);
+                        i = ni;
+                    }
+                    m
+                }
+                ColumnType::GEOMETRY => {
+                    if let Ok((ni, size)) = take_int1(i) {
+                        m.length_size = Some(size);
                         i = ni;
                     }
                     m
                 }
                 ColumnType::DOUBLE=>{
                     if let Ok((ni, size)) = take_int1(i) {
-                        m.size = Some(size as u8);
+                        m.size = Some(size);
                         i = ni;
                     }
                     m
                 },
                 ColumnType::FLOAT=>{
                     if let Ok((ni, size)) = take_int1(i) {
-                        m.size = Some(size as u8);
+                        m.size = Some(size);
                         i = ni
                     }
                     m
                 },
                 ColumnType::TIMESTAMP | ColumnType::DATETIME | ColumnType::TIME =>{
                     if let Ok((ni, size)) = take_int1(i) {
-                        m.fsp = Some(size as u8);
+                        m.fsp = Some(size);
                         i = ni;
                     }
                     m
                 },
-                ColumnType::TEXT=>{
+                ColumnType::TEXT | ColumnType::JSON=>{
                     if let Ok((ni, size)) = take_int1(i) {
-                        m.length_size = Some(size as u8);
+                        m.length_size = Some(size);
                         i = ni;
                     }
                     m
                 },
                 ColumnType::DECIMAL=>{
                     if let Ok((ni, size)) = take_int1(i) {
-                        m.precision = Some(size as u8);
+                        m.precision = Some(size);
                         i = ni;
                     }
                     if let Ok((ni, size)) = take_int1(i) {
-                        m.decimals = Some(size as u8);
+                        m.decimals = Some(size);
                         i = ni;
                     }
                     m
                 },
                 _=>ColMeta::new()
             };
+            let tp = match meta.real_type {
+                Some(247) => ColumnType::ENUM,
+                Some(248) => ColumnType::SET,
+                _ => tp
+            };
+            types.push(tp);
             metas.push(meta.clone());
-            //println!("{tp:?} with meta:{meta:?} rest:{i:?}");
         }
-        //println!("mapped columns:{types:?}");
-        if let Some(val) = self.mapping.insert(tb, types){
-            //println!("更新列映射");
-        }else{
-            //println!("新增列映射");
+        self.mapping.insert(tb, types);
+        self.metas.insert(tb, metas);
+    }
+
+    /// 设置每一列的符号，unsigned按列的顺序
+    pub fn set_unsigned(&mut self, tb: u64, unsigned: &[bool]) {
+        if let Some(metas) = self.metas.get_mut(&tb) {
+            for (meta, unsigned) in metas.iter_mut().zip(unsigned.iter()) {
+                meta.unsigned = *unsigned;
+            }
         }
-        if let Some(m) = self.metas.insert(tb, metas) {
-            //println!("更新列元数据")
-        }else{
-            //println!("新增列元数据")
+    }
+
+    /// 标记字符集为binary的列，和 set_unsigned 一样按列的顺序
+    pub fn set_binary(&mut self, tb: u64, binary: &[bool]) {
+        if let Some(metas) = self.metas.get_mut(&tb) {
+            for (meta, binary) in metas.iter_mut().zip(binary.iter()) {
+                meta.binary = *binary;
+            }
         }
     }
 
-    pub fn decode_column_vals<'a>(&'a mut self, input: &'a [u8], table_id: u64, col_map_len: usize) -> IResult<&[u8], Vec<Value>> {
-        let (ip, null_map1) = take_bytes(input, col_map_len)?;
-        let mut col_types = self.mapping.get_mut(&table_id).unwrap();
-        let mut values:Vec<Value> = Vec::new();
-        let mut metas = self.metas.get_mut(&table_id).unwrap();
+    /// 解码一行数据，present为行镜像中出现的列，不在镜像中的列返回None，NULL值返回Some(Value::Null)
+    pub fn decode_column_vals<'a>(&self, input: &'a [u8], table_id: u64, present: &[bool]) -> IResult<&'a [u8], Vec<Option<ColumnValue>>> {
+        self.decode_partial_column_vals(input, table_id, present, &[])
+    }
+
+    /// 表中JSON列的数量，PARTIAL_UPDATE_ROWS_EVENT 的部分更新位图每个JSON列占一位
+    pub fn json_column_count(&self, table_id: u64) -> usize {
+        match self.mapping.get(&table_id) {
+            Some(col_types) => col_types.iter().filter(|tp| matches!(tp, ColumnType::JSON)).count(),
+            None => 0
+        }
+    }
+
+    /// 部分更新的JSON列只有修改操作，应用到前镜像上还原为完整的值，和普通更新的消息格式一致。
+    /// 前镜像中没有该列(binlog_row_image=MINIMAL)时无法还原，当作不在后镜像中
+    pub fn apply_json_diffs(&self, table_id: u64, partial_json: &[bool], old_vals: &[Option<ColumnValue>], mut new_vals: Vec<Option<ColumnValue>>) -> Vec<Option<ColumnValue>> {
+        let col_types = match self.mapping.get(&table_id) {
+            Some(col_types) if !partial_json.is_empty() => col_types,
+            _ => return new_vals
+        };
+        let json_cols = col_types.iter().enumerate().filter(|(_, tp)| matches!(tp, ColumnType::JSON)).map(|(idx, _)| idx);
+        for (idx, partial) in json_cols.zip(partial_json.iter()) {
+            let diff = match new_vals.get(idx) {
+                Some(Some(ColumnValue::Value(diff @ Value::Array(_)))) if *partial => diff,
+                _ => continue
+            };
+            let full = match old_vals.get(idx) {
+                Some(Some(ColumnValue::Value(before))) => apply_json_diff(before.clone(), diff),
+                _ => None
+            };
+            if full.is_none() {
+                warn!("JSON列部分更新无法应用到前镜像，忽略第{}列: {diff}", idx + 1);
+            }
+            new_vals[idx] = full.map(ColumnValue::Value);
+        }
+        new_vals
+    }
+
+    /// partial_json 为每个JSON列是否为部分更新，为空表示都是完整的值
+    pub fn decode_partial_column_vals<'a>(&self, input: &'a [u8], table_id: u64, present: &[bool], partial_json: &[bool]) -> IResult<&'a [u8], Vec<Option<ColumnValue>>> {
+        let (col_types, metas) = match (self.mapping.get(&table_id), self.metas.get(&table_id)) {
+            (Some(col_types), Some(metas)) => (col_types, metas),
+            _ => return Err(NomErr::Error(Error::new(input, ErrorKind::Verify)))
+        };
+        // NULL位图只包含出现在镜像中的列
+        let present_count = present.iter().filter(|p| **p).count();
+        let (ip, null_bits) = take_bytes(input, present_count.div_ceil(8))?;
+        let null_map = compute_null_map(null_bits, present_count);
+        let mut values:Vec<Option<ColumnValue>> = Vec::new();
+        let mut present_idx = 0usize;
+        let mut json_idx = 0usize;
         let mut i = ip;
-        for (idx, mut col_type) in col_types.iter_mut().enumerate() {
-            let meta = metas[idx].clone();
-            //println!("{col_type:?} use meta: {meta:?} idx: {idx}");
-            let (new_i, val) = ColumnType::decode_val(col_type.clone(), i, &meta)?;
+        for (idx, col_type) in col_types.iter().enumerate() {
+            // 部分更新位图包含所有JSON列，不论是否出现在镜像中
+            let is_partial = if matches!(col_type, ColumnType::JSON) {
+                json_idx += 1;
+                partial_json.get(json_idx - 1).copied().unwrap_or(false)
+            } else { false };
+            if !present.get(idx).copied().unwrap_or(false) {
+                values.push(None);
+                continue;
+            }
+            let is_null = null_map[present_idx];
+            present_idx += 1;
+            if is_null {
+                values.push(Some(ColumnValue::Value(Value::Null)));
+                continue;
+            }
+            let (new_i, val) = if is_partial {
+                ColumnType::decode_json_partial(i, &metas[idx])?
+            } else {
+                ColumnType::decode_val(*col_type, i, &metas[idx], &self.temporal)?
+            };
             i = new_i;
-            values.push(val);
+            values.push(Some(val));
         }
-        Ok((i, values.clone()))
+        Ok((i, values))
     }
 }
 
@@ -504,7 +852,7 @@ impl TableMap {
 impl Decoder for EventHeaderFlag {
     fn decode(input: &[u8]) -> IResult<&[u8], Self> {
         let (i, flags) = take_int2(input)?;
-        if let Some(ins) = Self::from_bits(flags as u16){
+        if let Some(ins) = Self::from_bits(flags){
             Ok((i, ins))
         }else{
             Err(NomErr::Error(Error::new(input, ErrorKind::Eof)))
@@ -513,7 +861,6 @@ impl Decoder for EventHeaderFlag {
 }
 
 #[derive(Debug, Clone)]
-#[cfg_attr(feature = "serde", serde::Serialize, serde::DeSerialize)]
 pub struct EventHeader {
     pub timestamp: u32,
     pub event_type: u8,
@@ -534,7 +881,7 @@ impl Decoder for EventHeader {
         let (i, flags) = EventHeaderFlag::decode(i)?;
         Ok((i, Self {
             timestamp:timestemp,
-            event_type: event_type as u8,
+            event_type,
             server_id,
             event_size,
             log_pos,
@@ -544,7 +891,6 @@ impl Decoder for EventHeader {
 }
 
 #[derive(Debug, Clone)]
-#[cfg_attr(feature = "serde", serde::Serialize, serde::DeSerialize)]
 pub struct EventRaw {
     pub header: EventHeader,
     pub payload: Vec<u8>,
@@ -560,8 +906,103 @@ impl Decoder for EventRaw {
     }
 }
 
+impl EventRaw {
+    const CHECKSUM_LEN: usize = 4;
+
+    /// 校验并去掉末尾的checksum，payload只保留事件正文。
+    /// FORMAT_DESCRIPTION_EVENT 自带算法标识，按它自己的算法校验，其余事件按 alg 校验
+    pub fn decode_checked(input: &[u8], alg: ChecksumAlg) -> Result<Self, BinlogError> {
+        let (body, header) = EventHeader::decode(input).map_err(|_| BinlogError::Header)?;
+        let alg = if header.event_type == FormatDescriptionEvent::EVENT_TYPE {
+            FormatDescriptionEvent::checksum_alg(body)
+        } else { alg };
+        let has_checksum = alg == ChecksumAlg::CRC32 || header.event_type == FormatDescriptionEvent::EVENT_TYPE;
+        if !has_checksum {
+            return Ok(Self{ header, payload: Vec::from(body) });
+        }
+        if body.len() < Self::CHECKSUM_LEN {
+            return Err(BinlogError::Truncated{ event_type: header.event_type, log_pos: header.log_pos });
+        }
+        let (payload, checksum) = body.split_at(body.len() - Self::CHECKSUM_LEN);
+        if alg == ChecksumAlg::CRC32 {
+            let expected = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+            // 第一个字节是OK包的标识，不属于事件
+            let actual = crc32fast::hash(&input[1..input.len() - Self::CHECKSUM_LEN]);
+            if expected != actual {
+                return Err(BinlogError::ChecksumMismatch{ event_type: header.event_type, log_pos: header.log_pos, expected, actual });
+            }
+        }
+        Ok(Self{ header, payload: Vec::from(payload) })
+    }
+}
+
+/// binlog事件解析错误
+#[derive(Debug, Error)]
+pub enum BinlogError {
+    #[error("事件头解析失败")]
+    Header,
+    #[error("事件{event_type}(log_pos:{log_pos})长度不足，缺少checksum")]
+    Truncated { event_type: u8, log_pos: u32 },
+    #[error("事件{event_type}(log_pos:{log_pos})校验失败, 事件中的CRC32为{expected:#010x}, 计算结果为{actual:#010x}")]
+    ChecksumMismatch { event_type: u8, log_pos: u32, expected: u32, actual: u32 },
+}
+
+/// binlog_checksum 算法
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChecksumAlg {
+    OFF,
+    CRC32,
+}
+
+impl ChecksumAlg {
+    pub fn from_name(name: &str) -> Self {
+        if name.eq_ignore_ascii_case("CRC32") { Self::CRC32 } else { Self::OFF }
+    }
+}
+
+/// 每个binlog文件的第一个事件，dump开始时服务器也会发送一个
+#[derive(Debug, Clone)]
+pub struct FormatDescriptionEvent {
+    pub binlog_version: u16,
+    pub server_version: String,
+    pub create_timestamp: u32,
+    pub header_len: u8,
+    pub checksum_alg: ChecksumAlg,
+}
+
+impl FormatDescriptionEvent {
+    pub const EVENT_TYPE: u8 = 15;
+
+    /// 带checksum的事件正文最后5个字节为算法标识和checksum，算法标识 0:OFF 1:CRC32
+    fn checksum_alg(body: &[u8]) -> ChecksumAlg {
+        match body.len().checked_sub(5).map(|idx| body[idx]) {
+            Some(1) => ChecksumAlg::CRC32,
+            _ => ChecksumAlg::OFF
+        }
+    }
+}
+
+impl Decoder for FormatDescriptionEvent {
+    /// 输入为去掉checksum之后的正文，最后一个字节为算法标识
+    fn decode(input: &[u8]) -> IResult<&[u8], Self> {
+        let (i, binlog_version) = take_int2(input)?;
+        let (i, server_version) = take_bytes(i, 50)?;
+        let (i, create_timestamp) = take_int4(i)?;
+        let (i, header_len) = take_int1(i)?;
+        let (i, _post_header_len) = take_bytes(i, i.len().saturating_sub(1))?;
+        let (i, alg) = take_int1(i)?;
+        let server_version = String::from_utf8_lossy(server_version).trim_end_matches('\0').to_string();
+        Ok((i, Self{
+            binlog_version,
+            server_version,
+            create_timestamp,
+            header_len,
+            checksum_alg: if alg == 1 { ChecksumAlg::CRC32 } else { ChecksumAlg::OFF },
+        }))
+    }
+}
+
 #[derive(Debug, Clone)]
-#[cfg_attr(feature = "serde", serde::Serialize, serde::DeSerialize)]
 pub struct Event<P> {
     pub header: EventHeader,
     pub payload: P,
@@ -574,14 +1015,20 @@ pub struct RowEventHeader {
 }
 
 impl RowEventHeader {
-    pub const EventType: u8 = 19;
+    pub const EVENT_TYPE: u8 = 19;
+}
+
+/// v2行事件的extra data(NDB信息或8.0的分区信息)，长度包含自己的2字节
+fn take_extra_data(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (i, extra_len) = take_int2(input)?;
+    take_bytes(i, (extra_len as usize).saturating_sub(2))
 }
 
 impl Decoder for RowEventHeader {
     fn decode(input: &[u8]) -> IResult<&[u8], Self> {
         let (i, table_id) = take_int6(input)?;
         let (i, flag) = take_int2(i)?;
-        Ok((i, Self{table_id, flag:flag as u16}))
+        Ok((i, Self{table_id, flag}))
     }
 }
 
@@ -591,51 +1038,219 @@ pub struct TableMapEvent {
     pub header: RowEventHeader,
     pub schema_name: String,
     pub table_name: String,
-    pub column_count: u8,
+    pub column_count: usize,
     pub column_types: Vec<u8>,
-    pub column_metas: Vec<u8>
+    pub column_metas: Vec<u8>,
+    pub metadata: TableMapMetadata
 }
 
 impl Decoder for TableMapEvent {
     fn decode(input: &[u8]) -> IResult<&[u8], Self> {
         let (i, header) = RowEventHeader::decode(input)?;
-        let (i, schema_len) = take_int1(i)?;
+        let (i, _schema_len) = take_int1(i)?;
         let (i, schema_name) = take_utf8_end_of_null(i)?;
-        let (i, table_name_len) = take_int1(i)?;
+        let (i, _table_name_len) = take_int1(i)?;
         let (i, table_name) = take_utf8_end_of_null(i)?;
         let (i, column_count) = VLenInt::decode(i)?;
         let (i, column_map) = take_bytes(i, column_count.int() as usize)?;
-        //println!("cloumn_map: {column_map:?}");
         let (i, meta_count) = VLenInt::decode(i)?;
         let (i, meta_block) = take_bytes(i, meta_count.int() as usize)?;
-        //println!("meta_block: {meta_block:?}");
-        //println!("Rest:{i:?}");
+        let (i, _null_bits) = take_bytes(i, (column_count.int() as usize).div_ceil(8))?;
+        let (i, metadata) = TableMapMetadata::decode(i)?;
         Ok((i, Self{
             header,
             schema_name,
             table_name,
-            column_count: column_count.int() as u8,
+            column_count: column_count.int() as usize,
             column_types: Vec::from(column_map),
-            column_metas: Vec::from(meta_block)
+            column_metas: Vec::from(meta_block),
+            metadata
         }))
     }
 }
 
+/// TABLE_MAP 末尾的可选元数据，由 binlog_row_metadata 控制(8.0.1+)。
+/// MINIMAL 只有符号、字符集和空间类型，FULL 才有列名、主键和 ENUM/SET 的值。
+/// 除了列名和主键，其余字段都只按对应类型的列依次排列，需要结合列类型才能对应到具体的列
+#[derive(Debug, Clone, Default)]
+pub struct TableMapMetadata {
+    /// 数字列是否为unsigned
+    pub unsigned: Vec<bool>,
+    /// 字符列(CHAR/VARCHAR/TEXT/BLOB)的collation
+    pub charsets: ColumnCharsets,
+    /// ENUM和SET列的collation，两种列合在一起排序
+    pub enum_set_charsets: ColumnCharsets,
+    pub column_names: Vec<String>,
+    /// ENUM/SET的值保留原始字节，按列的字符集解码
+    pub set_values: Vec<Vec<Vec<u8>>>,
+    pub enum_values: Vec<Vec<Vec<u8>>>,
+    pub geometry_types: Vec<u64>,
+    /// 主键列的下标
+    pub primary_key: Vec<usize>,
+}
+
+/// DEFAULT_CHARSET 和 COLUMN_CHARSET 两种写法，服务器选择更短的一种
+#[derive(Debug, Clone, Default)]
+pub struct ColumnCharsets {
+    pub default: Option<u16>,
+    /// 和默认字符集不同的列，(列序号, collation id)
+    pub overrides: Vec<(usize, u16)>,
+    /// 每一列的collation id，和 default 二选一
+    pub columns: Vec<u16>,
+}
+
+impl ColumnCharsets {
+    /// 第 idx 个列的collation id
+    pub fn get(&self, idx: usize) -> Option<u16> {
+        if let Some(charset) = self.columns.get(idx) {
+            return Some(*charset);
+        }
+        match self.overrides.iter().find(|(col, _)| *col == idx) {
+            Some((_, charset)) => Some(*charset),
+            None => self.default
+        }
+    }
+
+    fn take_default(input: &[u8]) -> IResult<&[u8], Self> {
+        let (i, vals) = TableMapMetadata::take_packed_ints(input)?;
+        Ok((i, Self {
+            default: vals.first().map(|c| *c as u16),
+            overrides: vals[1.min(vals.len())..].chunks(2)
+                .filter(|pair| pair.len() == 2)
+                .map(|pair| (pair[0] as usize, pair[1] as u16))
+                .collect(),
+            columns: Vec::new(),
+        }))
+    }
+
+    fn take_columns(input: &[u8]) -> IResult<&[u8], Self> {
+        let (i, vals) = TableMapMetadata::take_packed_ints(input)?;
+        Ok((i, Self {
+            default: None,
+            overrides: Vec::new(),
+            columns: vals.iter().map(|c| *c as u16).collect(),
+        }))
+    }
+}
+
+impl TableMapMetadata {
+    const SIGNEDNESS: u8 = 1;
+    const DEFAULT_CHARSET: u8 = 2;
+    const COLUMN_CHARSET: u8 = 3;
+    const COLUMN_NAME: u8 = 4;
+    const SET_STR_VALUE: u8 = 5;
+    const ENUM_STR_VALUE: u8 = 6;
+    const GEOMETRY_TYPE: u8 = 7;
+    const SIMPLE_PRIMARY_KEY: u8 = 8;
+    const PRIMARY_KEY_WITH_PREFIX: u8 = 9;
+    const ENUM_AND_SET_DEFAULT_CHARSET: u8 = 10;
+    const ENUM_AND_SET_COLUMN_CHARSET: u8 = 11;
+
+    fn take_str_values(input: &[u8]) -> IResult<&[u8], Vec<Vec<Vec<u8>>>> {
+        let mut columns = Vec::new();
+        let mut i = input;
+        while !i.is_empty() {
+            let (ni, count) = VLenInt::decode(i)?;
+            let mut values = Vec::new();
+            i = ni;
+            for _ in 0..count.int() {
+                let (ni, len) = VLenInt::decode(i)?;
+                let (ni, val) = take_bytes(ni, len.int() as usize)?;
+                values.push(Vec::from(val));
+                i = ni;
+            }
+            columns.push(values);
+        }
+        Ok((i, columns))
+    }
+
+    fn take_packed_ints(input: &[u8]) -> IResult<&[u8], Vec<u64>> {
+        let mut vals = Vec::new();
+        let mut i = input;
+        while !i.is_empty() {
+            let (ni, val) = VLenInt::decode(i)?;
+            vals.push(val.int());
+            i = ni;
+        }
+        Ok((i, vals))
+    }
+}
+
+impl Decoder for TableMapMetadata {
+    /// 每一项为 类型(1字节) + 长度(packed int) + 内容，不认识的类型直接跳过
+    fn decode(input: &[u8]) -> IResult<&[u8], Self> {
+        let mut metadata = Self::default();
+        let mut i = input;
+        while !i.is_empty() {
+            let (ni, field_type) = take_int1(i)?;
+            let (ni, len) = VLenInt::decode(ni)?;
+            let (ni, field) = take_bytes(ni, len.int() as usize)?;
+            i = ni;
+            match field_type {
+                Self::SIGNEDNESS => {
+                    // 高位在前
+                    metadata.unsigned = field.iter().flat_map(|b| (0..8).rev().map(move |bit| (b >> bit) & 1 > 0)).collect();
+                },
+                Self::DEFAULT_CHARSET => {
+                    metadata.charsets = ColumnCharsets::take_default(field)?.1;
+                },
+                Self::COLUMN_CHARSET => {
+                    metadata.charsets = ColumnCharsets::take_columns(field)?.1;
+                },
+                Self::ENUM_AND_SET_DEFAULT_CHARSET => {
+                    metadata.enum_set_charsets = ColumnCharsets::take_default(field)?.1;
+                },
+                Self::ENUM_AND_SET_COLUMN_CHARSET => {
+                    metadata.enum_set_charsets = ColumnCharsets::take_columns(field)?.1;
+                },
+                Self::COLUMN_NAME => {
+                    let mut f = field;
+                    while !f.is_empty() {
+                        let (nf, len) = VLenInt::decode(f)?;
+                        let (nf, name) = take_bytes(nf, len.int() as usize)?;
+                        metadata.column_names.push(String::from_utf8_lossy(name).to_string());
+                        f = nf;
+                    }
+                },
+                Self::SET_STR_VALUE => {
+                    metadata.set_values = Self::take_str_values(field)?.1;
+                },
+                Self::ENUM_STR_VALUE => {
+                    metadata.enum_values = Self::take_str_values(field)?.1;
+                },
+                Self::GEOMETRY_TYPE => {
+                    metadata.geometry_types = Self::take_packed_ints(field)?.1;
+                },
+                Self::SIMPLE_PRIMARY_KEY => {
+                    metadata.primary_key = Self::take_packed_ints(field)?.1.iter().map(|idx| *idx as usize).collect();
+                },
+                Self::PRIMARY_KEY_WITH_PREFIX => {
+                    // (列下标, 前缀长度)
+                    let (_, vals) = Self::take_packed_ints(field)?;
+                    metadata.primary_key = vals.iter().step_by(2).map(|idx| *idx as usize).collect();
+                },
+                _ => {}
+            }
+        }
+        Ok((i, metadata))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct WriteRowEvent {
     pub header: RowEventHeader,
     pub col_count: u32,
-    pub col_map_len: usize
+    pub present: Vec<bool>
 }
 impl WriteRowEvent {
-    pub fn decode_column_multirow_vals<'a>(table_map: &TableMap, input: &'a [u8], table_id: u64, col_map_len: usize) -> IResult<&'a [u8], Vec<Vec<Value>>> {
+    pub fn decode_column_multirow_vals<'a>(table_map: &TableMap, input: &'a [u8], table_id: u64, present: &[bool]) -> IResult<&'a [u8], Vec<Vec<Option<ColumnValue>>>> {
         let mut rest_input = input;
-        let mut rows:Vec<Vec<Value>> = Vec::new();
+        let mut rows:Vec<Vec<Option<ColumnValue>>> = Vec::new();
         loop{
-            let (i, vals) = decode_column_vals(table_map.clone(), rest_input, table_id, col_map_len)?;
+            let (i, vals) = table_map.decode_column_vals(rest_input, table_id, present)?;
             rest_input = i;
             rows.push(vals);
-            if rest_input.len() <= 4 {
+            if rest_input.is_empty() {
                 break;
             }
         }
@@ -646,21 +1261,13 @@ impl WriteRowEvent {
 impl Decoder for WriteRowEvent{
     fn decode(input: &[u8]) -> IResult<&[u8], Self> {
         let (i, header) = RowEventHeader::decode(input)?;
-        let (i, extra_len) = take_int2(i)?;
-        //println!("extra len:{extra_len}");
-        let (i, _) = if extra_len > 2 {
-            //处理大于2的extra数据
-            let (i, extra_bs) = take_bytes(i, extra_len as usize)?;
-            (i, extra_bs)
-        }else{(i, i)};
+        let (i, _extra) = take_extra_data(i)?;
         let (i, column_count) = VLenInt::decode(i)?;
-        let col_map_len = (column_count.int() as u32 + 7u32)/8u32;
-        let (i, col_map) = take_bytes(i, col_map_len as usize)?;
-        //println!("rest:{:?}", i);
+        let (i, present) = take_bitmap(i, column_count.int() as usize)?;
         Ok((i, Self{
             header,
             col_count:column_count.int() as u32,
-            col_map_len: col_map_len as usize
+            present
         }))
 
     }
@@ -669,49 +1276,50 @@ impl Decoder for WriteRowEvent{
 #[derive(Debug, Clone)]
 pub struct UpdateRowEvent {
     pub header: RowEventHeader,
-    pub col_map_len: usize,
+    pub present_before: Vec<bool>,
+    pub present_after: Vec<bool>,
 }
 
 
 impl Decoder for UpdateRowEvent{
     fn decode(input: &[u8]) -> IResult<&[u8], Self> {
         let (i, header) = RowEventHeader::decode(input)?;
-        let (i, extra_len) = take_int2(i)?;
-        //println!("extra len:{extra_len}");
-        let (i, _) = if extra_len > 2 {
-            //处理大于2的extra数据
-            let (i, extra_bs) = take_bytes(i, extra_len as usize)?;
-            (i, extra_bs)
-        }else{(i, i)};
+        let (i, _extra) = take_extra_data(i)?;
         let (i, column_count) = VLenInt::decode(i)?;
-        //println!("共{}列", column_count.int());
 
-        let col_map_len = (column_count.int() as u32 + 7u32)/8u32;
-        //println!("列映射长度:{col_map_len}");
-        let (i, col_map1) = take_bytes(i, col_map_len as usize)?;
-        let (i, col_map2) = take_bytes(i, col_map_len as usize)?;
-        //println!("列映射1:{col_map1:?} 列映射2:{col_map2:?}");
+        let (i, present_before) = take_bitmap(i, column_count.int() as usize)?;
+        let (i, present_after) = take_bitmap(i, column_count.int() as usize)?;
         Ok((i, Self{
             header,
-            col_map_len: col_map_len as usize
+            present_before,
+            present_after
         }))
     }
 }
 
 impl UpdateRowEvent {
-    pub fn fetch_rows<'a>(input: &'a [u8], mut table_map: TableMap, table_id: u64, col_map_len: usize) -> IResult<&'a [u8], (Vec<Vec<Value>>, Vec<Vec<Value>>)> {
+    pub const PARTIAL_EVENT_TYPE: u8 = 39;
+    const PARTIAL_JSON_UPDATES: u64 = 1;
+
+    pub fn fetch_rows<'a>(input: &'a [u8], table_map: &TableMap, table_id: u64, present_before: &[bool], present_after: &[bool], partial: bool) -> IResult<&'a [u8], (Vec<Vec<Option<ColumnValue>>>, Vec<Vec<Option<ColumnValue>>>)> {
         let mut rest_input = input;
-        let mut old_result:Vec<Vec<Value>> = Vec::new();
-        let mut new_result:Vec<Vec<Value>> = Vec::new();
+        let mut old_result:Vec<Vec<Option<ColumnValue>>> = Vec::new();
+        let mut new_result:Vec<Vec<Option<ColumnValue>>> = Vec::new();
         loop {
-            let (i, old_vals) = decode_column_vals(table_map.clone(), rest_input, table_id, col_map_len)?;
-            //println!("old values:{old_vals:?}");
-            let (i, new_vals) = decode_column_vals(table_map.clone(), i, table_id, col_map_len)?;
-            //println!("new values:{new_vals:?}");
+            let (i, old_vals) = table_map.decode_column_vals(rest_input, table_id, present_before)?;
+            let (i, partial_json) = if partial {
+                // binlog_row_value_options，之后是JSON列的部分更新位图
+                let (i, value_options) = VLenInt::decode(i)?;
+                if value_options.int() & Self::PARTIAL_JSON_UPDATES > 0 {
+                    take_bitmap(i, table_map.json_column_count(table_id))?
+                } else { (i, Vec::new()) }
+            } else { (i, Vec::new()) };
+            let (i, new_vals) = table_map.decode_partial_column_vals(i, table_id, present_after, &partial_json)?;
+            let new_vals = table_map.apply_json_diffs(table_id, &partial_json, &old_vals, new_vals);
             rest_input = i;
             old_result.push(old_vals);
             new_result.push(new_vals);
-            if rest_input.len() <= 4{
+            if rest_input.is_empty() {
                 break;
             }
         }
@@ -721,18 +1329,18 @@ impl UpdateRowEvent {
 
 pub struct DeleteRowEvent {
     pub header: RowEventHeader,
-    pub col_map_len: usize
+    pub present: Vec<bool>
 }
 
 impl DeleteRowEvent {
-    pub fn fetch_rows<'a>(input: &'a [u8], mut table_map: TableMap, table_id: u64, col_map_len: usize) -> IResult<&'a [u8], Vec<Vec<Value>>> {
+    pub fn fetch_rows<'a>(input: &'a [u8], table_map: &TableMap, table_id: u64, present: &[bool]) -> IResult<&'a [u8], Vec<Vec<Option<ColumnValue>>>> {
         let mut rest_input = input;
-        let mut result:Vec<Vec<Value>> = Vec::new();
+        let mut result:Vec<Vec<Option<ColumnValue>>> = Vec::new();
         loop {
-            let (i, old_vals) = decode_column_vals(table_map.clone(), rest_input, table_id, col_map_len)?;
+            let (i, old_vals) = table_map.decode_column_vals(rest_input, table_id, present)?;
             rest_input = i;
             result.push(old_vals);
-            if rest_input.len() <= 4 {
+            if rest_input.is_empty() {
                 break;
             }
         }
@@ -744,21 +1352,12 @@ impl Decoder for DeleteRowEvent {
     fn decode(input: &[u8]) -> IResult<&[u8], Self> {
         //new_values
         let (i, header) = RowEventHeader::decode(input)?;
-        let (i, extra_len) = take_int2(i)?;
-        //println!("extra len:{extra_len}");
-        let (i, _) = if extra_len > 2 {
-            //处理大于2的extra数据
-            let (i, extra_bs) = take_bytes(i, extra_len as usize)?;
-            (i, extra_bs)
-        }else{(i, i)};
+        let (i, _extra) = take_extra_data(i)?;
         let (i, column_count) = VLenInt::decode(i)?;
-        let col_map_len = (column_count.int() as u32 + 7u32)/8u32;
-        let (i, col_map1) = take_bytes(i, col_map_len as usize)?;
-        //println!("col map:{col_map1:?}");
-        //println!("rest:{:?}", i);
+        let (i, present) = take_bitmap(i, column_count.int() as usize)?;
         Ok((i, Self{
             header,
-            col_map_len: col_map_len as usize
+            present
         }))
 
     }
@@ -785,8 +1384,8 @@ impl Decoder for QueryEventHeader{
             thread_id,
             timestamp,
             db_name_len: db_len as usize,
-            error_code: err_code as u16,
-            status_len: status_len as u16,
+            error_code: err_code,
+            status_len,
         }))
     }
 }
@@ -798,35 +1397,244 @@ pub struct QueryEvent {
     pub statement: String
 }
 
+/// DDL语句的类型和涉及的表，type 与 Canal 保持一致
+#[derive(Debug, Clone)]
+pub struct DdlInfo {
+    pub ddl_type: String,
+    pub tables: Vec<(String, String)>
+}
+
+impl QueryEvent {
+    pub const EVENT_TYPE: u8 = 2;
+
+    /// 解析DDL语句，非DDL返回None，库级别的DDL表名为空
+    pub fn parse_ddl(&self) -> Option<DdlInfo> {
+        let tokens = tokenize_sql(self.statement.as_str());
+        let upper: Vec<String> = tokens.iter().map(|t| t.to_uppercase()).collect();
+        let keyword = |idx: usize| upper.get(idx).map(|s| s.as_str()).unwrap_or("");
+        let mut idx = 1usize;
+        let skip = |idx: &mut usize, words: &[&str]| {
+            while words.contains(&upper.get(*idx).map(|s| s.as_str()).unwrap_or("")) {
+                *idx += 1;
+            }
+        };
+        let name_at = |idx: usize| tokens.get(idx).map(|t| split_table_name(t, self.database.as_str()));
+        let (ddl_type, tables) = match keyword(0) {
+            "CREATE" => {
+                skip(&mut idx, &["OR", "REPLACE", "TEMPORARY", "UNIQUE", "FULLTEXT", "SPATIAL"]);
+                match keyword(idx) {
+                    "TABLE" => {
+                        idx += 1;
+                        skip(&mut idx, &["IF", "NOT", "EXISTS"]);
+                        ("CREATE", name_at(idx).into_iter().collect())
+                    },
+                    "INDEX" => {
+                        let on = upper.iter().position(|t| t == "ON")?;
+                        ("CINDEX", name_at(on + 1).into_iter().collect())
+                    },
+                    "DATABASE" | "SCHEMA" => {
+                        idx += 1;
+                        skip(&mut idx, &["IF", "NOT", "EXISTS"]);
+                        ("CREATE", tokens.get(idx).map(|t| (unquote(t), "".to_string())).into_iter().collect())
+                    },
+                    _ => return None
+                }
+            },
+            "ALTER" => {
+                skip(&mut idx, &["ONLINE", "OFFLINE", "IGNORE"]);
+                if keyword(idx) != "TABLE" {
+                    return None;
+                }
+                ("ALTER", name_at(idx + 1).into_iter().collect())
+            },
+            "DROP" => {
+                skip(&mut idx, &["TEMPORARY"]);
+                match keyword(idx) {
+                    "TABLE" | "TABLES" => {
+                        idx += 1;
+                        skip(&mut idx, &["IF", "EXISTS"]);
+                        let names = tokens[idx.min(tokens.len())..].iter()
+                            .take_while(|t| !["RESTRICT", "CASCADE"].contains(&t.to_uppercase().as_str()))
+                            .map(|t| split_table_name(t, self.database.as_str()))
+                            .collect();
+                        ("ERASE", names)
+                    },
+                    "INDEX" => {
+                        let on = upper.iter().position(|t| t == "ON")?;
+                        ("DINDEX", name_at(on + 1).into_iter().collect())
+                    },
+                    "DATABASE" | "SCHEMA" => {
+                        idx += 1;
+                        skip(&mut idx, &["IF", "EXISTS"]);
+                        ("ERASE", tokens.get(idx).map(|t| (unquote(t), "".to_string())).into_iter().collect())
+                    },
+                    _ => return None
+                }
+            },
+            "RENAME" => {
+                if !["TABLE", "TABLES"].contains(&keyword(1)) {
+                    return None;
+                }
+                let names = tokens[2.min(tokens.len())..].iter()
+                    .filter(|t| t.to_uppercase() != "TO")
+                    .map(|t| split_table_name(t, self.database.as_str()))
+                    .collect();
+                ("RENAME", names)
+            },
+            "TRUNCATE" => {
+                skip(&mut idx, &["TABLE"]);
+                ("TRUNCATE", name_at(idx).into_iter().collect())
+            },
+            _ => return None
+        };
+        Some(DdlInfo{ ddl_type: ddl_type.to_string(), tables })
+    }
+}
+
+/// 按空白、逗号、括号和分号切分SQL，保留反引号中的内容，去掉注释
+fn tokenize_sql(sql: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut chars = sql.chars().peekable();
+    let mut in_quote = false;
+    while let Some(c) = chars.next() {
+        if in_quote {
+            current.push(c);
+            if c == '`' {
+                in_quote = false;
+            }
+            continue;
+        }
+        if c == '/' && chars.peek() == Some(&'*') {
+            chars.next();
+            let mut last = ' ';
+            for nc in chars.by_ref() {
+                if last == '*' && nc == '/' {
+                    break;
+                }
+                last = nc;
+            }
+            continue;
+        }
+        if c == '`' {
+            in_quote = true;
+            current.push(c);
+        }else if c.is_whitespace() || [',', '(', ')', ';'].contains(&c) {
+            if !current.is_empty() {
+                tokens.push(current.clone());
+                current.clear();
+            }
+        }else{
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn unquote(name: &str) -> String {
+    name.trim_matches('`').replace("``", "`")
+}
+
+fn split_table_name(name: &str, default_db: &str) -> (String, String) {
+    let mut in_quote = false;
+    for (idx, c) in name.char_indices() {
+        if c == '`' {
+            in_quote = !in_quote;
+        }
+        if c == '.' && !in_quote {
+            return (unquote(&name[..idx]), unquote(&name[idx + 1..]));
+        }
+    }
+    (default_db.to_string(), unquote(name))
+}
+
 impl Decoder for QueryEvent {
     fn decode(input: &[u8]) -> IResult<&[u8], Self>{
         let (i, header) = QueryEventHeader::decode(input)?;
-        //println!("Query Rest:{i:?}");
-        let (i, bs) = take_bytes(i, header.status_len as usize)?;
-        //println!("status rest:{bs:?}");
+        let (i, _bs) = take_bytes(i, header.status_len as usize)?;
         let (i, database) = take_utf8_end_of_null(i)?;
-        let (i, statement) = take_eof_string(&i[0..i.len()-4])?;
+        let (i, statement) = take_eof_string(i)?;
         Ok((i, Self{ header, database, statement }))
     }
 }
 
 
+#[derive(Debug, Clone)]
 pub struct RotateEvent {
-    pub position: u8,
+    pub position: u64,
     pub binlog_name: String
 }
 
+impl RotateEvent {
+    pub const EVENT_TYPE: u8 = 4;
+}
+
 impl Decoder for RotateEvent{
     fn decode(input: &[u8]) -> IResult<&[u8], Self> {
-        let (i, position) = take_bytes(input, 1usize)?;
+        let (i, position) = take_int8(input)?;
         let (i, binlog_name) = take_eof_string(i)?;
         Ok((i, Self{
-            position: 0,
+            position,
             binlog_name,
         }))
     }
 }
 
+/// 主库空闲时按 MASTER_HEARTBEAT_PERIOD 发送，header.log_pos 为当前位置
+#[derive(Debug, Clone)]
+pub struct HeartbeatEvent {
+    pub binlog_name: String
+}
+
+impl HeartbeatEvent {
+    pub const EVENT_TYPE: u8 = 27;
+}
+
+impl Decoder for HeartbeatEvent{
+    fn decode(input: &[u8]) -> IResult<&[u8], Self> {
+        let (i, binlog_name) = take_eof_string(input)?;
+        Ok((i, Self{ binlog_name }))
+    }
+}
+
+/// binlog_rows_query_log_events=ON 时在行事件之前记录原始SQL，开头1字节长度不准确(超过255会截断)，直接读到结尾
+#[derive(Debug, Clone)]
+pub struct RowsQueryEvent {
+    pub query: String
+}
+
+impl RowsQueryEvent {
+    pub const EVENT_TYPE: u8 = 29;
+}
+
+impl Decoder for RowsQueryEvent{
+    fn decode(input: &[u8]) -> IResult<&[u8], Self> {
+        let (i, _) = take_int1(input)?;
+        let (i, query) = take_eof_string(i)?;
+        Ok((i, Self{ query }))
+    }
+}
+
+/// 事务提交事件
+#[derive(Debug, Clone)]
+pub struct XidEvent {
+    pub xid: u64
+}
+
+impl XidEvent {
+    pub const EVENT_TYPE: u8 = 16;
+}
+
+impl Decoder for XidEvent{
+    fn decode(input: &[u8]) -> IResult<&[u8], Self> {
+        let (i, xid) = take_int8(input)?;
+        Ok((i, Self{ xid }))
+    }
+}
+
 
 fn check_bit(arr: &[u8], index: u16) -> u8 {
     let byte_index = (index / 8) as usize;
@@ -842,21 +1650,398 @@ fn compute_null_map(arr: &[u8], col_map_len: usize) -> Vec<bool> {
     map
 }
 
-pub fn decode_column_vals<'a>(mut table_map: TableMap, input: &'a [u8], table_id: u64, col_map_len: usize) -> IResult<&[u8], Vec<Value>> {
-    let (ip, null_map1) = take_bytes(input, col_map_len)?;
-    let mut values:Vec<Value> = Vec::new();
-    let mut metas = &mut table_map.metas.get_mut(&table_id).unwrap();
-    let null_map = compute_null_map(null_map1, metas.len());
-    let mut i = ip;
-    for (idx, mut col_type) in &mut table_map.mapping.get_mut(&table_id).unwrap().iter_mut().enumerate() {
-        let meta = metas[idx].clone();
-        if null_map[idx] {
-            values.push(Value::from(None::<String>));
-            continue;
+fn take_bitmap(input: &[u8], column_count: usize) -> IResult<&[u8], Vec<bool>> {
+    let (i, bs) = take_bytes(input, column_count.div_ceil(8))?;
+    Ok((i, compute_null_map(bs, column_count)))
+}
+
+/// GTID集合，区间为闭区间，如 3E11FA47-71CA-11E1-9E33-C80AA9429562:1-5:7
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GtidSet {
+    sets: BTreeMap<String, Vec<(u64, u64)>>
+}
+
+impl GtidSet {
+    pub fn new() -> Self {
+        Self{ sets: BTreeMap::new() }
+    }
+
+    /// 解析 gtid_executed 或者meta.json中保存的GTID集合，server uuid和区间无效时返回错误
+    pub fn parse(input: &str) -> Result<Self, GtidError> {
+        let mut ins = Self::new();
+        for item in input.split(',') {
+            let item: String = item.chars().filter(|c| !c.is_whitespace()).collect();
+            let mut parts = item.split(':');
+            let sid = match parts.next() {
+                Some(sid) if !sid.is_empty() => sid.to_lowercase(),
+                _ => continue
+            };
+            if sid_bytes(&sid).is_none() {
+                return Err(GtidError::Uuid(sid));
+            }
+            for interval in parts {
+                let mut range = interval.split('-');
+                let start = range.next().and_then(|n| n.parse::<u64>().ok());
+                let end = match range.next() {
+                    Some(n) => n.parse::<u64>().ok(),
+                    None => start
+                };
+                match (start, end, range.next()) {
+                    (Some(start), Some(end), None) if 0 < start && start <= end => ins.add_interval(&sid, start, end),
+                    _ => return Err(GtidError::Interval(format!("{sid}:{interval}")))
+                }
+            }
+        }
+        Ok(ins)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+
+    pub fn add(&mut self, sid: &str, gno: u64) {
+        self.add_interval(sid, gno, gno);
+    }
+
+    fn add_interval(&mut self, sid: &str, start: u64, end: u64) {
+        let intervals = self.sets.entry(sid.to_lowercase()).or_default();
+        intervals.push((start, end));
+        intervals.sort();
+        let mut merged: Vec<(u64, u64)> = Vec::new();
+        for (start, end) in intervals.iter() {
+            if let Some(last) = merged.last_mut() {
+                if *start <= last.1 + 1 {
+                    last.1 = last.1.max(*end);
+                    continue;
+                }
+            }
+            merged.push((*start, *end));
+        }
+        *intervals = merged;
+    }
+}
+
+impl Display for GtidSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let items: Vec<String> = self.sets.iter().map(|(sid, intervals)| {
+            let ranges: Vec<String> = intervals.iter().map(|(start, end)| {
+                if start == end { format!("{start}") } else { format!("{start}-{end}") }
+            }).collect();
+            format!("{sid}:{}", ranges.join(":"))
+        }).collect();
+        write!(f, "{}", items.join(","))
+    }
+}
+
+/// GTID集合解析错误
+#[derive(Debug, Error)]
+pub enum GtidError {
+    #[error("无效的GTID server uuid: {0}")]
+    Uuid(String),
+    #[error("无效的GTID区间: {0}")]
+    Interval(String),
+}
+
+fn format_sid(bs: &[u8]) -> String {
+    let hex_str = hex::encode(bs);
+    format!("{}-{}-{}-{}-{}", &hex_str[0..8], &hex_str[8..12], &hex_str[12..16], &hex_str[16..20], &hex_str[20..32])
+}
+
+/// server uuid 对应的16个字节，格式不对时返回None
+fn sid_bytes(sid: &str) -> Option<Vec<u8>> {
+    let bs = hex::decode(sid.replace('-', "")).ok()?;
+    if bs.len() == 16 { Some(bs) } else { None }
+}
+
+impl Decoder for GtidSet {
+    fn decode(input: &[u8]) -> IResult<&[u8], Self> {
+        let (mut i, n_sids) = take_int8(input)?;
+        let mut ins = Self::new();
+        for _ in 0..n_sids {
+            let (ni, sid) = take_bytes(i, 16usize)?;
+            let (ni, n_intervals) = take_int8(ni)?;
+            i = ni;
+            let sid = format_sid(sid);
+            for _ in 0..n_intervals {
+                let (ni, start) = take_int8(i)?;
+                let (ni, end) = take_int8(ni)?;
+                // 二进制格式中区间的结尾是开区间，空区间和倒序的区间都是无效数据
+                let end = match end.checked_sub(1) {
+                    Some(end) if start <= end => end,
+                    _ => return Err(NomErr::Error(Error::new(i, ErrorKind::Verify)))
+                };
+                i = ni;
+                ins.add_interval(&sid, start, end);
+            }
+        }
+        Ok((i, ins))
+    }
+}
+
+impl Encoder for GtidSet {
+    /// sid 在解析和解码时已经校验过，这里不会再出现无效的uuid
+    fn encode(&self, buf: &mut BytesMut) {
+        let sets: Vec<(Vec<u8>, &Vec<(u64, u64)>)> = self.sets.iter()
+            .filter_map(|(sid, intervals)| sid_bytes(sid).map(|bs| (bs, intervals)))
+            .collect();
+        buf.put_u64_le(sets.len() as u64);
+        for (sid_bs, intervals) in sets {
+            buf.extend_from_slice(sid_bs.as_bytes());
+            buf.put_u64_le(intervals.len() as u64);
+            for (start, end) in intervals.iter() {
+                buf.put_u64_le(*start);
+                buf.put_u64_le(*end + 1);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GtidEvent {
+    pub flags: u8,
+    pub sid: String,
+    pub gno: u64
+}
+
+impl GtidEvent {
+    pub const EVENT_TYPE: u8 = 33;
+
+    pub fn gtid(&self) -> String {
+        format!("{}:{}", self.sid, self.gno)
+    }
+}
+
+impl Decoder for GtidEvent {
+    fn decode(input: &[u8]) -> IResult<&[u8], Self> {
+        let (i, flags) = take_int1(input)?;
+        let (i, sid) = take_bytes(i, 16usize)?;
+        let (i, gno) = take_int8(i)?;
+        Ok((i, Self{
+            flags,
+            sid: format_sid(sid),
+            gno
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PreviousGtidsEvent {
+    pub gtid_set: GtidSet
+}
+
+impl PreviousGtidsEvent {
+    pub const EVENT_TYPE: u8 = 35;
+}
+
+impl Decoder for PreviousGtidsEvent {
+    fn decode(input: &[u8]) -> IResult<&[u8], Self> {
+        let (i, gtid_set) = GtidSet::decode(input)?;
+        Ok((i, Self{ gtid_set }))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// dump开始时服务器发送的ROTATE包(LOG_EVENT_ARTIFICIAL_F，log_pos为0)，带OK包标识和CRC32
+    const ROTATE_PACKET: [u8; 45] = [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x01, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x20, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x62, 0x69, 0x6e, 0x6c,
+        0x6f, 0x67, 0x2e, 0x30, 0x30, 0x30, 0x30, 0x30, 0x32, 0x53, 0x83, 0xc3, 0xf7
+    ];
+
+    #[test]
+    fn replay_rotate_packet() {
+        let ev = EventRaw::decode_checked(&ROTATE_PACKET, ChecksumAlg::CRC32).unwrap();
+        assert_eq!(ev.header.event_type, RotateEvent::EVENT_TYPE);
+        assert_eq!(ev.header.event_size, 44);
+        assert!(ev.header.flags.contains(EventHeaderFlag::LOG_EVENT_ARTIFICIAL_F));
+        let (_, rotate) = RotateEvent::decode(ev.payload.as_bytes()).unwrap();
+        assert_eq!(rotate.binlog_name, "binlog.000002");
+        assert_eq!(rotate.position, 4);
+    }
+
+    #[test]
+    fn rotate_packet_with_bad_checksum() {
+        let mut packet = ROTATE_PACKET;
+        packet[30] ^= 0xff;
+        assert!(matches!(EventRaw::decode_checked(&packet, ChecksumAlg::CRC32), Err(BinlogError::ChecksumMismatch{ .. })));
+    }
+
+    const SID: &str = "3e11fa47-71ca-11e1-9e33-c80aa9429562";
+
+    #[test]
+    fn gtid_set_parse_and_merge() {
+        let set = GtidSet::parse("3E11FA47-71CA-11E1-9E33-C80AA9429562:1-5:7:6,\n 4e11fa47-71ca-11e1-9e33-c80aa9429562:10").unwrap();
+        assert_eq!(set.to_string(), format!("{SID}:1-7,4e11fa47-71ca-11e1-9e33-c80aa9429562:10"));
+        let mut set = GtidSet::parse(&format!("{SID}:1-3:5")).unwrap();
+        set.add(SID, 4);
+        assert_eq!(set.to_string(), format!("{SID}:1-5"));
+        set.add(SID, 9);
+        assert_eq!(set.to_string(), format!("{SID}:1-5:9"));
+        assert!(GtidSet::parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn gtid_set_parse_invalid() {
+        assert!(matches!(GtidSet::parse("not-a-uuid:1-5"), Err(GtidError::Uuid(_))));
+        assert!(matches!(GtidSet::parse("3e11fa47-71ca-11e1-9e33:1"), Err(GtidError::Uuid(_))));
+        assert!(matches!(GtidSet::parse(&format!("{SID}:5-1")), Err(GtidError::Interval(_))));
+        assert!(matches!(GtidSet::parse(&format!("{SID}:0")), Err(GtidError::Interval(_))));
+        assert!(matches!(GtidSet::parse(&format!("{SID}:a-b")), Err(GtidError::Interval(_))));
+    }
+
+    #[test]
+    fn gtid_set_encode_decode() {
+        let set = GtidSet::parse(&format!("{SID}:1-5:7,4e11fa47-71ca-11e1-9e33-c80aa9429562:10")).unwrap();
+        let mut buf = BytesMut::new();
+        set.encode(&mut buf);
+        // 2个sid，每个sid 16字节uuid + 8字节区间数 + 每个区间16字节
+        assert_eq!(buf.len(), 8 + (16 + 8 + 16 * 2) + (16 + 8 + 16));
+        assert_eq!(&buf[8..24], hex::decode(SID.replace('-', "")).unwrap().as_slice());
+        // 区间结尾是开区间
+        assert_eq!(&buf[32..48], [1u64.to_le_bytes(), 6u64.to_le_bytes()].concat().as_slice());
+        let (rest, decoded) = GtidSet::decode(&buf).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, set);
+    }
+
+    #[test]
+    fn gtid_set_decode_malformed_interval() {
+        for (start, end) in [(1u64, 0u64), (5, 5), (7, 3)] {
+            let mut buf = BytesMut::new();
+            buf.put_u64_le(1);
+            buf.extend_from_slice(&hex::decode(SID.replace('-', "")).unwrap());
+            buf.put_u64_le(1);
+            buf.put_u64_le(start);
+            buf.put_u64_le(end);
+            assert!(GtidSet::decode(&buf).is_err());
         }
-        let (new_i, val) = ColumnType::decode_val(col_type.clone(), i, &meta)?;
-        i = new_i;
-        values.push(val.clone());
+        // 长度不足
+        assert!(GtidSet::decode(&[1, 0, 0, 0, 0, 0, 0, 0, 0x3e]).is_err());
+    }
+
+    #[test]
+    fn rows_query_reads_past_the_length_byte() {
+        // 长度字节只有1字节，超过255的SQL会被截断，所以读到事件结尾
+        let sql = format!("insert into t values ('{}')", "x".repeat(300));
+        let mut payload = vec![(sql.len() % 256) as u8];
+        payload.extend_from_slice(sql.as_bytes());
+        let (_, ev) = RowsQueryEvent::decode(payload.as_slice()).unwrap();
+        assert_eq!(ev.query, sql);
+    }
+
+    #[test]
+    fn rows_event_skips_extra_data() {
+        let row = [0x00u8, 0x2a, 0, 0, 0];
+        let mut payload = vec![1u8, 0, 0, 0, 0, 0, 1, 0];
+        // extra_len包含自己的2字节，后面是5字节的分区信息
+        payload.extend_from_slice(&[7, 0, 1, 3, 0, 0, 0]);
+        payload.extend_from_slice(&[1, 0x01]);
+        payload.extend_from_slice(&row);
+        let (rest, ev) = WriteRowEvent::decode(payload.as_slice()).unwrap();
+        assert_eq!(ev.col_count, 1);
+        assert_eq!(ev.present, vec![true]);
+        assert_eq!(rest, &row);
+
+        let mut payload = vec![1u8, 0, 0, 0, 0, 0, 1, 0, 7, 0, 1, 3, 0, 0, 0, 1, 0x01, 0x01];
+        payload.extend_from_slice(&row);
+        let (rest, ev) = UpdateRowEvent::decode(payload.as_slice()).unwrap();
+        assert_eq!((ev.present_before, ev.present_after), (vec![true], vec![true]));
+        assert_eq!(rest, &row);
+
+        // 没有extra data时长度为2
+        let mut payload = vec![1u8, 0, 0, 0, 0, 0, 1, 0, 2, 0, 1, 0x01];
+        payload.extend_from_slice(&row);
+        let (rest, ev) = DeleteRowEvent::decode(payload.as_slice()).unwrap();
+        assert_eq!(ev.present, vec![true]);
+        assert_eq!(rest, &row);
+    }
+
+    #[test]
+    fn table_map_with_more_than_255_columns() {
+        let mut payload = vec![1u8, 0, 0, 0, 0, 0, 1, 0];
+        payload.extend_from_slice(b"\x04test\x00\x04wide\x00");
+        // 300列的packed int为 0xfc + 2字节小端序
+        payload.extend_from_slice(&[0xfc, 0x2c, 0x01]);
+        payload.extend_from_slice(&[3u8; 300]);
+        payload.push(0);
+        payload.extend_from_slice(&[0xffu8; 38]);
+        let (_, tm) = TableMapEvent::decode(payload.as_slice()).unwrap();
+        assert_eq!(tm.schema_name, "test");
+        assert_eq!(tm.table_name, "wide");
+        assert_eq!(tm.column_count, 300);
+        assert_eq!(tm.column_types.len(), 300);
     }
-    Ok((i, values))
-}
\ No newline at end of file
+
+    fn int_meta(unsigned: bool) -> ColMeta {
+        ColMeta{ max_length: None, size: None, fsp: None, length_size: None, precision: None, decimals: None, real_type: None, bits: None, unsigned, binary: false }
+    }
+
+    fn decode_int(tp: ColumnType, bytes: &[u8], unsigned: bool) -> Value {
+        let (rest, val) = ColumnType::decode_value(tp, bytes, &int_meta(unsigned), &TemporalFormat::default()).unwrap();
+        assert!(rest.is_empty());
+        val
+    }
+
+    #[test]
+    fn timestamp_is_local_datetime_without_offset() {
+        let mut meta = int_meta(false);
+        meta.fsp = Some(3);
+        // 2024-01-02 03:04:05.678 UTC，毫秒按fsp=3存为6780
+        let bytes = [0x65, 0x93, 0x7d, 0x25, 0x1a, 0x7c];
+        let east8 = TemporalFormat{ time_zone: FixedOffset::east_opt(8 * 3600), zero_date: ZeroDatePolicy::NULL };
+        let (rest, val) = ColumnType::decode_value(ColumnType::TIMESTAMP, &bytes, &meta, &east8).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(val, Value::from("2024-01-02 11:04:05.678"));
+        let (_, val) = ColumnType::decode_value(ColumnType::TIMESTAMP, &bytes, &meta, &TemporalFormat::default()).unwrap();
+        assert_eq!(val, Value::from("2024-01-02 03:04:05.678"));
+    }
+
+    #[test]
+    fn integer_columns_min_max() {
+        // 有符号和无符号的最小值、最大值，行数据中都是小端序
+        assert_eq!(decode_int(ColumnType::TINYINT, &i8::MIN.to_le_bytes(), false), Value::from(i8::MIN));
+        assert_eq!(decode_int(ColumnType::TINYINT, &i8::MAX.to_le_bytes(), false), Value::from(i8::MAX));
+        assert_eq!(decode_int(ColumnType::TINYINT, &u8::MIN.to_le_bytes(), true), Value::from(u8::MIN));
+        assert_eq!(decode_int(ColumnType::TINYINT, &u8::MAX.to_le_bytes(), true), Value::from(u8::MAX));
+        assert_eq!(decode_int(ColumnType::SMALLINT, &i16::MIN.to_le_bytes(), false), Value::from(i16::MIN));
+        assert_eq!(decode_int(ColumnType::SMALLINT, &i16::MAX.to_le_bytes(), false), Value::from(i16::MAX));
+        assert_eq!(decode_int(ColumnType::SMALLINT, &u16::MIN.to_le_bytes(), true), Value::from(u16::MIN));
+        assert_eq!(decode_int(ColumnType::SMALLINT, &u16::MAX.to_le_bytes(), true), Value::from(u16::MAX));
+        assert_eq!(decode_int(ColumnType::MEDIUMINT, &(-8388608i32).to_le_bytes()[..3], false), Value::from(-8388608));
+        assert_eq!(decode_int(ColumnType::MEDIUMINT, &8388607i32.to_le_bytes()[..3], false), Value::from(8388607));
+        assert_eq!(decode_int(ColumnType::MEDIUMINT, &0u32.to_le_bytes()[..3], true), Value::from(0));
+        assert_eq!(decode_int(ColumnType::MEDIUMINT, &16777215u32.to_le_bytes()[..3], true), Value::from(16777215));
+        assert_eq!(decode_int(ColumnType::INT, &i32::MIN.to_le_bytes(), false), Value::from(i32::MIN));
+        assert_eq!(decode_int(ColumnType::INT, &i32::MAX.to_le_bytes(), false), Value::from(i32::MAX));
+        assert_eq!(decode_int(ColumnType::INT, &u32::MIN.to_le_bytes(), true), Value::from(u32::MIN));
+        assert_eq!(decode_int(ColumnType::INT, &u32::MAX.to_le_bytes(), true), Value::from(u32::MAX));
+        assert_eq!(decode_int(ColumnType::BIGINT, &i64::MIN.to_le_bytes(), false), Value::from(i64::MIN));
+        assert_eq!(decode_int(ColumnType::BIGINT, &i64::MAX.to_le_bytes(), false), Value::from(i64::MAX));
+        assert_eq!(decode_int(ColumnType::BIGINT, &u64::MIN.to_le_bytes(), true), Value::from(u64::MIN));
+        assert_eq!(decode_int(ColumnType::BIGINT, &u64::MAX.to_le_bytes(), true), Value::from(u64::MAX));
+    }
+
+    #[test]
+    fn blob_and_varbinary_keep_raw_bytes() {
+        let bytes = [0x00u8, 0xff, 0xfe, 0x80, b'a', 0x00];
+        // BLOB: 2字节长度 + 内容
+        let mut meta = int_meta(false);
+        meta.length_size = Some(2);
+        meta.binary = true;
+        let mut input = (bytes.len() as u16).to_le_bytes().to_vec();
+        input.extend_from_slice(&bytes);
+        let (rest, val) = ColumnType::decode_val(ColumnType::TEXT, &input, &meta, &TemporalFormat::default()).unwrap();
+        assert!(rest.is_empty());
+        assert!(matches!(val, ColumnValue::Bytes(bs) if bs == bytes));
+        // VARBINARY(16): 1字节长度
+        meta.max_length = Some(16);
+        let mut input = vec![bytes.len() as u8];
+        input.extend_from_slice(&bytes);
+        let (_, val) = ColumnType::decode_val(ColumnType::VARCHAR, &input, &meta, &TemporalFormat::default()).unwrap();
+        assert!(matches!(val, ColumnValue::Bytes(bs) if bs == bytes));
+    }
+}
@@ -0,0 +1,156 @@
+use encoding_rs::Encoding;
+
+/// collation id 对应的字符集，id 见 information_schema.COLLATIONS，
+/// TABLE_MAP 的可选元数据中只有 collation id
+pub fn collation_charset(id: u16) -> Option<&'static str> {
+    let charset = match id {
+        1 | 84 => "big5",
+        3 | 69 => "dec8",
+        4 | 80 => "cp850",
+        6 | 72 => "hp8",
+        7 | 74 => "koi8r",
+        5 | 8 | 15 | 31 | 47 | 48 | 49 | 94 => "latin1",
+        2 | 9 | 21 | 27 | 77 => "latin2",
+        10 | 82 => "swe7",
+        11 | 65 => "ascii",
+        12 | 91 => "ujis",
+        13 | 88 => "sjis",
+        16 | 71 => "hebrew",
+        18 | 89 => "tis620",
+        19 | 85 => "euckr",
+        22 | 75 => "koi8u",
+        24 | 86 => "gb2312",
+        25 | 70 => "greek",
+        26 | 34 | 44 | 66 | 99 => "cp1250",
+        28 | 87 => "gbk",
+        30 | 78 => "latin5",
+        32 | 64 => "armscii8",
+        33 | 76 | 83 | 192..=215 | 223 => "utf8mb3",
+        35 | 90 | 128..=151 | 159 => "ucs2",
+        36 | 68 => "cp866",
+        37 | 73 => "keybcs2",
+        38 | 43 => "macce",
+        39 | 53 => "macroman",
+        40 | 81 => "cp852",
+        20 | 41 | 42 | 79 => "latin7",
+        45 | 46 | 224..=247 | 255..=323 => "utf8mb4",
+        14 | 23 | 50 | 51 | 52 => "cp1251",
+        54 | 55 | 101..=124 => "utf16",
+        56 | 62 => "utf16le",
+        57 | 67 => "cp1256",
+        29 | 58 | 59 => "cp1257",
+        60 | 61 | 160..=183 => "utf32",
+        63 => "binary",
+        92 | 93 => "geostd8",
+        95 | 96 => "cp932",
+        97 | 98 => "eucjpms",
+        248..=250 => "gb18030",
+        _ => return None
+    };
+    Some(charset)
+}
+
+/// 字符集中一个字符最多的字节数(information_schema.CHARACTER_SETS 的 MAXLEN)，
+/// TABLE_MAP中CHAR/VARCHAR的长度是字节数，除以它得到定义的字符数
+pub fn max_bytes(charset: &str) -> Option<u16> {
+    let maxlen = match charset {
+        "utf8mb4" | "utf16" | "utf16le" | "utf32" | "gb18030" => 4,
+        "utf8mb3" | "ujis" | "eucjpms" => 3,
+        "ucs2" | "big5" | "sjis" | "cp932" | "euckr" | "gb2312" | "gbk" => 2,
+        "latin1" | "latin2" | "latin5" | "latin7" | "ascii" | "binary" | "dec8" | "cp850" | "hp8" | "koi8r" | "koi8u" | "swe7"
+        | "hebrew" | "tis620" | "greek" | "cp1250" | "cp1251" | "cp1256" | "cp1257" | "armscii8" | "cp866" | "keybcs2"
+        | "macce" | "macroman" | "cp852" | "geostd8" => 1,
+        _ => return None
+    };
+    Some(maxlen)
+}
+
+/// MySQL字符集对应的编码，utf8系列和不认识的字符集返回None，按UTF-8处理
+fn encoding(charset: &str) -> Option<&'static Encoding> {
+    let encoding = match charset {
+        // MySQL的latin1实际是cp1252
+        "latin1" => encoding_rs::WINDOWS_1252,
+        "latin2" => encoding_rs::ISO_8859_2,
+        "latin5" => encoding_rs::WINDOWS_1254,
+        "latin7" => encoding_rs::ISO_8859_13,
+        "greek" => encoding_rs::ISO_8859_7,
+        "hebrew" => encoding_rs::ISO_8859_8,
+        "cp1250" => encoding_rs::WINDOWS_1250,
+        "cp1251" => encoding_rs::WINDOWS_1251,
+        "cp1256" => encoding_rs::WINDOWS_1256,
+        "cp1257" => encoding_rs::WINDOWS_1257,
+        "cp866" => encoding_rs::IBM866,
+        "koi8r" => encoding_rs::KOI8_R,
+        "koi8u" => encoding_rs::KOI8_U,
+        "macroman" => encoding_rs::MACINTOSH,
+        "tis620" => encoding_rs::WINDOWS_874,
+        "gbk" | "gb2312" => encoding_rs::GBK,
+        "gb18030" => encoding_rs::GB18030,
+        "big5" => encoding_rs::BIG5,
+        "sjis" | "cp932" => encoding_rs::SHIFT_JIS,
+        "ujis" | "eucjpms" => encoding_rs::EUC_JP,
+        "euckr" => encoding_rs::EUC_KR,
+        // ucs2和utf16都是大端序
+        "ucs2" | "utf16" => encoding_rs::UTF_16BE,
+        "utf16le" => encoding_rs::UTF_16LE,
+        _ => return None
+    };
+    Some(encoding)
+}
+
+/// 按列的字符集把字节转换为UTF-8，无法转换的字节替换为U+FFFD
+pub fn decode(charset: Option<&str>, bs: &[u8]) -> String {
+    match charset {
+        Some("utf32") => decode_utf32(bs),
+        Some(charset) => match encoding(charset) {
+            Some(encoding) => encoding.decode_without_bom_handling(bs).0.to_string(),
+            None => String::from_utf8_lossy(bs).to_string()
+        },
+        None => String::from_utf8_lossy(bs).to_string()
+    }
+}
+
+/// encoding_rs 不支持UTF-32，每个字符固定4字节大端序
+fn decode_utf32(bs: &[u8]) -> String {
+    bs.chunks(4).map(|c| {
+        if c.len() < 4 {
+            return char::REPLACEMENT_CHARACTER;
+        }
+        char::from_u32(u32::from_be_bytes([c[0], c[1], c[2], c[3]])).unwrap_or(char::REPLACEMENT_CHARACTER)
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collation_ids_map_to_charsets() {
+        assert_eq!(collation_charset(8), Some("latin1"));
+        assert_eq!(collation_charset(28), Some("gbk"));
+        assert_eq!(collation_charset(33), Some("utf8mb3"));
+        assert_eq!(collation_charset(255), Some("utf8mb4"));
+        assert_eq!(collation_charset(54), Some("utf16"));
+        assert_eq!(collation_charset(63), Some("binary"));
+        assert_eq!(collation_charset(1000), None);
+        assert_eq!(max_bytes("utf8mb4"), Some(4));
+        assert_eq!(max_bytes("gbk"), Some(2));
+        assert_eq!(max_bytes("latin1"), Some(1));
+        assert_eq!(max_bytes("unknown"), None);
+    }
+
+    #[test]
+    fn decode_transcodes_to_utf8() {
+        // MySQL的latin1是cp1252，0x80为欧元符号
+        assert_eq!(decode(Some("latin1"), &[b'c', b'a', b'f', 0xe9, 0x80]), "café€");
+        assert_eq!(decode(Some("gbk"), &[0xd6, 0xd0, 0xce, 0xc4]), "中文");
+        assert_eq!(decode(Some("utf16"), &[0x4e, 0x2d, 0x00, 0x41]), "中A");
+        assert_eq!(decode(Some("utf16le"), &[0x2d, 0x4e]), "中");
+        assert_eq!(decode(Some("utf32"), &[0x00, 0x01, 0xf6, 0x00, 0x00, 0x00, 0x00, 0x41]), "😀A");
+        assert_eq!(decode(Some("utf8mb4"), "中".as_bytes()), "中");
+        // 无法转换的字节替换为U+FFFD
+        assert_eq!(decode(Some("utf8mb4"), &[0xff]), "\u{FFFD}");
+        assert_eq!(decode(Some("utf32"), &[0x00, 0x41]), "\u{FFFD}");
+    }
+}
@@ -1,9 +1,8 @@
+use std::collections::HashMap;
 use std::env;
-use dirs;
 use std::path::{Path, PathBuf};
-use nom::combinator::into;
 use serde::{Serialize, Deserialize};
-use serde_json::to_string;
+use serde_json::Value;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KafkaConfig {
@@ -23,23 +22,162 @@ pub enum MqConfig {
     REDIS(RedisConfig)
 }
 
+/// 事务模式：NONE 每个行事件单独发送；TAGGED 消息带上 xid 和 commitTs；BATCH 每个事务合并为一条消息
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TransactionMode {
+    NONE,
+    TAGGED,
+    BATCH
+}
+
+/// 行事件分发到worker的方式：ROUND_ROBIN 轮询；TABLE 同一张表进同一个worker；PRIMARY_KEY 同一行进同一个worker
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DispatchMode {
+    ROUND_ROBIN,
+    TABLE,
+    PRIMARY_KEY
+}
+
+/// 与MySQL客户端的 --ssl-mode 含义一致
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SslMode {
+    DISABLED,
+    PREFERRED,
+    REQUIRED,
+    VERIFY_CA,
+    VERIFY_IDENTITY
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SslConfig {
+    pub mode: SslMode,
+    /// CA证书(PEM)，VERIFY_CA/VERIFY_IDENTITY 时用来校验服务器证书
+    pub ca: Option<String>,
+    /// 客户端证书(PEM)和未加密的私钥(PEM)，私钥可以是PKCS#8或者PKCS#1的RSA私钥
+    pub cert: Option<String>,
+    pub key: Option<String>
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Mq {
     pub mq_name: String,
     pub mq_cfg: MqConfig
 }
 
+/// 零值日期(0000-00-00)的输出：NULL 输出null；LITERAL 原样输出 0000-00-00 00:00:00
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ZeroDatePolicy {
+    NULL,
+    LITERAL
+}
+
+/// BLOB和BINARY/VARBINARY列的输出：BASE64、HEX 或者按UTF-8解码(非法字节替换为U+FFFD)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BinaryEncoding {
+    BASE64,
+    HEX,
+    UTF8_LOSSY
+}
+
+/// 消息格式：CANAL 为Canal的FlatMessage，每条消息可以包含多行；DEBEZIUM 为Debezium的变更事件，每行一条消息；
+/// AVRO 为Confluent wire format 的Avro二进制，每行一条消息，需要配置 schema_registry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MessageFormat {
+    CANAL,
+    DEBEZIUM,
+    AVRO
+}
+
+/// Confluent兼容的schema注册中心，只支持http
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchemaRegistryConfig {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>
+}
+
+/// Kafka分区方式：DEFAULT 由Kafka根据消息key分区；TABLE 按表名哈希；PRIMARY_KEY 按主键值哈希
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PartitionStrategy {
+    DEFAULT,
+    TABLE,
+    PRIMARY_KEY
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Instance {
     pub mq: String,
     pub schemas: String,
     pub tables: String,
     pub black_list: Vec<String>,
-    pub topic: String
+    pub topic: String,
+    /// 消息key表达式，支持 ${db} ${table} ${pk} 和 ${列名}，未配置时使用主键值
+    pub key: Option<String>,
+    pub partition: Option<PartitionStrategy>,
+    pub format: Option<MessageFormat>,
+    /// DEBEZIUM格式是否带schema块，和Kafka Connect的 schemas.enable 一致
+    pub schema: Option<bool>,
+    /// DEBEZIUM格式source.name和schema名的前缀，对应Debezium的 topic.prefix，默认为topic
+    pub server_name: Option<String>
+}
+
+/// 消息的发送目的地
+#[derive(Debug, Clone)]
+pub struct Port {
+    pub mq: String,
+    pub topic: String,
+    pub key: Option<String>,
+    pub partition: PartitionStrategy,
+    pub format: MessageFormat,
+    pub schema: bool,
+    pub server_name: String
+}
+
+fn value_text(val: &Value) -> String {
+    match val.as_str() {
+        Some(s) => s.to_string(),
+        None => val.to_string()
+    }
+}
+
+impl Port {
+    /// 主键值，多个主键用逗号连接
+    pub fn pk_value(pk_names: &Option<Vec<String>>, row: &HashMap<String, Value>) -> Option<String> {
+        let pks = pk_names.as_ref()?;
+        let vals: Vec<String> = pks.iter().filter_map(|pk| row.get(pk)).map(value_text).collect();
+        if vals.is_empty() { None } else { Some(vals.join(",")) }
+    }
+
+    pub fn message_key(&self, db: &str, table: &str, pk_names: &Option<Vec<String>>, row: &HashMap<String, Value>) -> Option<String> {
+        let expr = match &self.key {
+            Some(expr) => expr,
+            None => return Self::pk_value(pk_names, row)
+        };
+        let mut key = String::new();
+        let mut rest = expr.as_str();
+        while let Some(start) = rest.find("${") {
+            key.push_str(&rest[..start]);
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break
+            };
+            let name = &rest[start + 2..end];
+            let val = match name {
+                "db" => db.to_string(),
+                "table" => table.to_string(),
+                "pk" => Self::pk_value(pk_names, row).unwrap_or_default(),
+                col => row.get(col).map(value_text).unwrap_or_default()
+            };
+            key.push_str(val.as_str());
+            rest = &rest[end + 1..];
+        }
+        key.push_str(rest);
+        Some(key)
+    }
 }
 
 impl Instance {
-    pub fn check_if_need_a_mq(&mut self, db: String, table: String) -> Option<(String, String)> {
+    pub fn check_if_need_a_mq(&mut self, db: String, table: String) -> Option<Port> {
         if match_pattern(self.schemas.as_str(), db.as_str()) {
             for p in &self.black_list{
                 if match_pattern(p.as_str(), table.as_str()){
@@ -47,19 +185,25 @@ impl Instance {
                 }
             }
             if match_pattern(self.tables.as_str(), table.as_str()) {
-                return Some((self.mq.clone(), self.topic.clone()));
+                return Some(Port{
+                    mq: self.mq.clone(),
+                    topic: self.topic.clone(),
+                    key: self.key.clone(),
+                    partition: self.partition.clone().unwrap_or(PartitionStrategy::DEFAULT),
+                    format: self.format.clone().unwrap_or(MessageFormat::CANAL),
+                    schema: self.schema.unwrap_or(false),
+                    server_name: self.server_name.clone().unwrap_or(self.topic.clone()),
+                });
             }
         }
-        return None;
+        None
     }
 }
 
 fn match_pattern(pattern: &str, input: &str) -> bool {
-    if pattern.ends_with('*') {
-        let prefix = &pattern[..pattern.len() - 1];
+    if let Some(prefix) = pattern.strip_suffix('*') {
         input.starts_with(prefix)
-    } else if pattern.starts_with('*') {
-        let suffix = &pattern[1..];
+    } else if let Some(suffix) = pattern.strip_prefix('*') {
         input.ends_with(suffix)
     } else {
         let parts: Vec<&str> = pattern.split('*').collect();
@@ -78,14 +222,34 @@ pub struct Config {
     pub max_packages: u64,
     pub user_name: String,
     pub passwd: String,
+    pub ssl: Option<SslConfig>,
     pub workers: u8,
     pub from_start: Option<bool>,
+    pub gtid_mode: Option<bool>,
+    /// 作为从库的server_id，同一个主库上不能重复
+    pub server_id: Option<u32>,
+    /// SHOW REPLICAS 中显示的主机和端口
+    pub report_host: Option<String>,
+    pub report_port: Option<u16>,
+    pub transaction_mode: Option<TransactionMode>,
+    pub dispatch: Option<DispatchMode>,
+    /// 连续重连失败多少次后退出进程(非0退出码)，交给进程管理器重启
+    pub max_reconnect_attempts: Option<u32>,
+    /// 心跳周期(秒)，超过3个周期没有收到事件或心跳就重连
+    pub heartbeat_period: Option<u32>,
+    /// TIMESTAMP列输出的时区，SYSTEM、UTC 或 +08:00 形式的偏移，默认UTC
+    pub time_zone: Option<String>,
+    pub zero_date: Option<ZeroDatePolicy>,
+    pub binary_encoding: Option<BinaryEncoding>,
+    /// 和Canal一样把数字输出为字符串，默认开启
+    pub number_as_string: Option<bool>,
+    pub schema_registry: Option<SchemaRegistryConfig>,
     pub mqs: Vec<Mq>,
     pub instances: Vec<Instance>
 }
 
 impl Config {
-    pub fn to_json(&mut self) -> String {
+    pub fn to_json(&self) -> String {
         if let Ok(j_txt) = serde_json::to_string_pretty(self) {
             j_txt
         }else{
@@ -120,8 +284,22 @@ impl Config {
             max_packages: 4294967295,
             user_name: "canal".to_string(),
             passwd: "canal".to_string(),
+            ssl: None,
             workers: 0,
             from_start: Some(false),
+            gtid_mode: Some(false),
+            server_id: Some(100),
+            report_host: None,
+            report_port: None,
+            transaction_mode: Some(TransactionMode::NONE),
+            dispatch: Some(DispatchMode::ROUND_ROBIN),
+            max_reconnect_attempts: Some(10),
+            heartbeat_period: Some(15),
+            time_zone: Some("UTC".to_string()),
+            zero_date: Some(ZeroDatePolicy::NULL),
+            binary_encoding: Some(BinaryEncoding::BASE64),
+            number_as_string: Some(true),
+            schema_registry: None,
             mqs: vec![Mq{ mq_name: "the_kafka".to_string(), mq_cfg: MqConfig::KAFKA(KafkaConfig{ brokers: "127.0.0.1:9092".to_string(), queue_buffering_max: 333 }) }],
             instances: vec![Instance{
                 mq: "the_kafka".to_string(),
@@ -129,6 +307,11 @@ impl Config {
                 tables: "s*".to_string(),
                 black_list: vec!["tb01".to_string(), "tb02".to_string()],
                 topic: "db_change".to_string(),
+                key: None,
+                partition: Some(PartitionStrategy::DEFAULT),
+                format: Some(MessageFormat::CANAL),
+                schema: None,
+                server_name: None,
             }],
         }
     }
@@ -141,19 +324,19 @@ pub fn get_abs_path(address: String) -> String{
     let absolute_path = if Path::new(&address).is_relative() {
         if address.starts_with("~"){
             return if let Some(mut path) = dirs::home_dir() {
-                path.push(&address[2..].to_string());
+                path.push(&address[2..]);
                 path.to_str().unwrap().to_string()
             } else {
                 address
             }
         }else{
             let current_dir = env::current_dir().expect("Failed to get current directory");
-            let mut path_buf = PathBuf::from(current_dir);
+            let mut path_buf = current_dir;
             path_buf.push(address);
             path_buf
         }
     } else {
         PathBuf::from(address)
     };
-    return absolute_path.to_str().unwrap().to_string();
+    absolute_path.to_str().unwrap().to_string()
 }
\ No newline at end of file
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use serde_json::{json, Map, Value};
+use crate::config::BinaryEncoding;
+use crate::executor::{DmlMessage, FieldMeta};
+
+/// Debezium source块中的binlog位置，server_id为产生事件的主库，ts_ms为binlog中事件的时间
+#[derive(Debug, Clone, Default)]
+pub struct SourceInfo {
+    pub binlog: String,
+    pub pos: u32,
+    pub server_id: u32,
+    pub gtid: Option<String>,
+    pub ts_ms: u64,
+}
+
+/// 每一行生成一条Debezium变更事件，server_name 对应Debezium的 topic.prefix。
+/// with_schema 为true时和Kafka Connect的 schemas.enable=true 一样输出 {"schema":..,"payload":..}
+pub fn envelopes(message: &DmlMessage, fields: &[FieldMeta], source: &SourceInfo, server_name: &str, with_schema: bool, encoding: &BinaryEncoding) -> Vec<String> {
+    let op = change_op(message);
+    let schema = if with_schema { Some(envelope_schema(message, fields, server_name, encoding)) } else { None };
+    let mut events = Vec::new();
+    for idx in 0..message.data.len() {
+        let (before, after) = row_images(message, idx);
+        let image = |row: Option<HashMap<String, Value>>| row.map(|row| row_value(&row, fields)).unwrap_or(Value::Null);
+        let payload = json!({
+            "before": image(before),
+            "after": image(after),
+            "source": {
+                "version": env!("CARGO_PKG_VERSION"),
+                "connector": "mysql",
+                "name": server_name,
+                "ts_ms": source.ts_ms,
+                "snapshot": "false",
+                "db": message.database,
+                "sequence": null,
+                "table": message.table,
+                "server_id": source.server_id,
+                "gtid": source.gtid,
+                "file": source.binlog,
+                "pos": source.pos,
+                "row": idx,
+                "thread": null,
+                "query": message.sql
+            },
+            "op": op,
+            "ts_ms": message.ts as u64,
+            "transaction": null
+        });
+        let event = match schema {
+            Some(ref schema) => json!({ "schema": schema, "payload": payload }),
+            None => payload
+        };
+        events.push(event.to_string());
+    }
+    events
+}
+
+/// 插入为c，更新为u，删除为d
+pub fn change_op(message: &DmlMessage) -> &'static str {
+    match message.r#type.as_str() {
+        "INSERT" => "c",
+        "UPDATE" => "u",
+        _ => "d"
+    }
+}
+
+/// 第 idx 行的前镜像和后镜像，插入没有前镜像，删除没有后镜像
+pub fn row_images(message: &DmlMessage, idx: usize) -> (Option<HashMap<String, Value>>, Option<HashMap<String, Value>>) {
+    let row = match message.data.get(idx) {
+        Some(row) => row.clone(),
+        None => return (None, None)
+    };
+    match change_op(message) {
+        "c" => (None, Some(row)),
+        // old 中只有修改过的列，和后镜像合并得到完整的前镜像
+        "u" => {
+            let mut before = row.clone();
+            if let Some(old) = message.old.as_ref().and_then(|old| old.get(idx)) {
+                before.extend(old.iter().map(|(k, v)| (k.clone(), v.clone())));
+            }
+            (Some(before), Some(row))
+        },
+        _ => (Some(row), None)
+    }
+}
+
+/// Debezium中JSON列是字符串，数字保持为JSON数字
+fn row_value(row: &HashMap<String, Value>, fields: &[FieldMeta]) -> Value {
+    let mut obj = Map::new();
+    for meta in fields.iter() {
+        if let Some(val) = row.get(&meta.name) {
+            let val = match val {
+                Value::Object(_) | Value::Array(_) => Value::from(val.to_string()),
+                _ => val.clone()
+            };
+            obj.insert(meta.name.clone(), val);
+        }
+    }
+    Value::Object(obj)
+}
+
+/// Kafka Connect的类型和语义名称，日期时间按字符串输出，DECIMAL按 decimal.handling.mode=string 输出
+fn field_type(meta: &FieldMeta, encoding: &BinaryEncoding) -> (&'static str, Option<&'static str>) {
+    let tp = meta.field_type.to_lowercase();
+    let unsigned = meta.is_unsigned();
+    if tp.starts_with("tinyint") {
+        return ("int16", None);
+    }
+    if tp.starts_with("smallint") {
+        return (if unsigned { "int32" } else { "int16" }, None);
+    }
+    if tp.starts_with("mediumint") {
+        return ("int32", None);
+    }
+    if tp.starts_with("int") {
+        return (if unsigned { "int64" } else { "int32" }, None);
+    }
+    if tp.starts_with("bigint") || tp.starts_with("bit") {
+        return ("int64", None);
+    }
+    if tp.starts_with("float") || tp.starts_with("double") || tp.starts_with("real") {
+        return ("float64", None);
+    }
+    if tp.starts_with("year") {
+        return ("int32", Some("io.debezium.time.Year"));
+    }
+    if tp.starts_with("json") {
+        return ("string", Some("io.debezium.data.Json"));
+    }
+    if tp.starts_with("enum(") {
+        return ("string", Some("io.debezium.data.Enum"));
+    }
+    if tp.starts_with("set(") {
+        return ("string", Some("io.debezium.data.EnumSet"));
+    }
+    // Kafka Connect的JsonConverter中bytes就是base64字符串
+    if meta.is_binary() && *encoding == BinaryEncoding::BASE64 {
+        return ("bytes", None);
+    }
+    ("string", None)
+}
+
+fn envelope_schema(message: &DmlMessage, fields: &[FieldMeta], server_name: &str, encoding: &BinaryEncoding) -> Value {
+    let prefix = format!("{server_name}.{}.{}", message.database, message.table);
+    let columns: Vec<Value> = fields.iter().map(|meta| {
+        let (tp, name) = field_type(meta, encoding);
+        let mut field = json!({ "type": tp, "optional": !meta.is_pk, "field": meta.name });
+        if let Some(name) = name {
+            field["name"] = Value::from(name);
+        }
+        field
+    }).collect();
+    let row_schema = |field: &str| json!({
+        "type": "struct",
+        "fields": columns,
+        "optional": true,
+        "name": format!("{prefix}.Value"),
+        "field": field
+    });
+    let source_field = |tp: &str, optional: bool, field: &str| json!({ "type": tp, "optional": optional, "field": field });
+    json!({
+        "type": "struct",
+        "fields": [
+            row_schema("before"),
+            row_schema("after"),
+            {
+                "type": "struct",
+                "fields": [
+                    source_field("string", false, "version"),
+                    source_field("string", false, "connector"),
+                    source_field("string", false, "name"),
+                    source_field("int64", false, "ts_ms"),
+                    source_field("string", true, "snapshot"),
+                    source_field("string", false, "db"),
+                    source_field("string", true, "sequence"),
+                    source_field("string", true, "table"),
+                    source_field("int64", false, "server_id"),
+                    source_field("string", true, "gtid"),
+                    source_field("string", false, "file"),
+                    source_field("int64", false, "pos"),
+                    source_field("int32", false, "row"),
+                    source_field("int64", true, "thread"),
+                    source_field("string", true, "query")
+                ],
+                "optional": false,
+                "name": "io.debezium.connector.mysql.Source",
+                "field": "source"
+            },
+            source_field("string", false, "op"),
+            source_field("int64", true, "ts_ms"),
+            {
+                "type": "struct",
+                "fields": [
+                    source_field("string", false, "id"),
+                    source_field("int64", false, "total_order"),
+                    source_field("int64", false, "data_collection_order")
+                ],
+                "optional": true,
+                "name": "event.block",
+                "field": "transaction"
+            }
+        ],
+        "optional": false,
+        "name": format!("{prefix}.Envelope")
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_uses_binlog_time_and_server_name() {
+        let fields = vec![
+            FieldMeta{ name: "id".to_string(), field_type: "int".to_string(), is_pk: true, charset: None },
+            FieldMeta{ name: "doc".to_string(), field_type: "json".to_string(), is_pk: false, charset: None },
+        ];
+        let row: HashMap<String, Value> = [("id".to_string(), Value::from(1)), ("doc".to_string(), json!({"a": 2}))].into_iter().collect();
+        let message = DmlMessage{
+            id: 1, database: "test".to_string(), table: "t".to_string(), pkNames: Some(vec!["id".to_string()]), isDdl: false,
+            r#type: "UPDATE".to_string(), es: 1704164645000, ts: 1704164646123, sql: None, sqlType: HashMap::new(), mysqlType: HashMap::new(),
+            data: vec![row], old: Some(vec![[("doc".to_string(), json!({"a": 1}))].into_iter().collect()]), xid: None, commitTs: None
+        };
+        let source = SourceInfo{ binlog: "binlog.000003".to_string(), pos: 1234, server_id: 1, gtid: None, ts_ms: 1704164645000 };
+        let events = envelopes(&message, &fields, &source, "dbserver1", true, &BinaryEncoding::BASE64);
+        let event: Value = serde_json::from_str(&events[0]).unwrap();
+        let payload = &event["payload"];
+        assert_eq!(payload["source"]["ts_ms"], 1704164645000u64);
+        assert_eq!(payload["source"]["name"], "dbserver1");
+        assert_eq!(payload["ts_ms"], 1704164646123u64);
+        assert_eq!(payload["op"], "u");
+        assert_eq!(payload["before"], json!({"id": 1, "doc": "{\"a\":1}"}));
+        assert_eq!(payload["after"], json!({"id": 1, "doc": "{\"a\":2}"}));
+        assert_eq!(event["schema"]["name"], "dbserver1.test.t.Envelope");
+    }
+}
@@ -1,22 +1,29 @@
-use std::collections::{HashMap, VecDeque};
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
-use hex;
 use serde_json::Value;
-use serde::Serialize;
+use serde_json::value::RawValue;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Serialize, Serializer};
+use serde::ser::{SerializeMap, SerializeSeq};
 use std::time::{SystemTime, UNIX_EPOCH};
 use rand::Rng;
-use crate::config::{Config, Instance};
+use thiserror::Error;
+use crate::config::{BinaryEncoding, Config, DispatchMode, Instance, MessageFormat, PartitionStrategy, Port, TransactionMode};
 use crate::message_queue::{MessageQueues, QueueMessage};
 use crate::mysql::{Decoder, MySQLConnection};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use nom::AsBytes;
-use crate::binlog::{ColMeta, DeleteRowEvent, EventRaw, TableMap, TableMapEvent, UpdateRowEvent, WriteRowEvent};
+use crate::binlog::{ColMeta, ColumnType, ColumnValue, DdlInfo, DeleteRowEvent, EventRaw, QueryEvent, TableMap, TableMapEvent, TemporalFormat, UpdateRowEvent, WriteRowEvent};
+use crate::position_manager::{abort_pipeline, CommitTracker, expect_acks, PanicGuard, track_event};
+use crate::charset;
+use crate::debezium::{self, SourceInfo};
+use crate::avro::{self, AvroError, RegistryError, SchemaRegistry};
 
 fn current_ms_ts() -> u128 {
     let now = SystemTime::now();
-    let timestamp = now.duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
-    timestamp
+    now.duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis()
 }
 
 pub fn current_ts() -> u64 {
@@ -34,12 +41,19 @@ pub fn generate_random_number() -> u32 {
 pub struct RowEvents {
     pub table_map: EventRaw,
     pub row_event: Option<EventRaw>,
-    pub seq_idx: u64
+    pub seq_idx: u64,
+    /// 按主键分发时该worker负责的行下标，None表示全部行
+    pub rows: Option<Vec<usize>>,
+    /// 事件所在的binlog文件和事务的GTID，用于Debezium的source块
+    pub binlog: String,
+    pub gtid: Option<String>,
+    /// 产生这些行的SQL，只有开启 binlog_rows_query_log_events 时才有
+    pub sql: Option<String>
 }
 
 impl RowEvents {
-    pub fn new(table_map: EventRaw) -> Self {
-        Self{ table_map, row_event: None, seq_idx:0 }
+    pub fn new(table_map: EventRaw, binlog: String, gtid: Option<String>, sql: Option<String>) -> Self {
+        Self{ table_map, row_event: None, seq_idx:0, rows: None, binlog, gtid, sql }
     }
     pub fn append(&mut self, ev: EventRaw, idx: u64) {
         self.row_event = Some(ev);
@@ -47,6 +61,57 @@ impl RowEvents {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct DdlEvent {
+    pub query: EventRaw,
+    pub seq_idx: u64
+}
+
+/// BEGIN到XID之间的行事件
+#[derive(Debug, Clone)]
+pub struct TransactionEvents {
+    pub rows: Vec<RowEvents>,
+    pub xid: u64,
+    pub commit_ts: u64,
+    pub seq_idx: u64
+}
+
+impl TransactionEvents {
+    pub fn new() -> Self {
+        Self{ rows: Vec::new(), xid: 0, commit_ts: 0, seq_idx: 0 }
+    }
+
+    pub fn commit(&mut self, xid: u64, commit_ts: u64, idx: u64) {
+        self.xid = xid;
+        self.commit_ts = commit_ts;
+        self.seq_idx = idx;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum WorkerEvent {
+    Rows(RowEvents),
+    Ddl(DdlEvent),
+    Transaction(TransactionEvents),
+    /// 独占事件，所有worker处理完之前的事件后才处理，处理完之后其它worker才继续
+    Exclusive(Box<WorkerEvent>, Arc<Barrier>),
+    /// 其它worker在这里等待独占事件处理完
+    Barrier(Arc<Barrier>)
+}
+
+impl WorkerEvent {
+    /// 屏障不对应binlog事件，没有序号
+    pub fn seq_idx(&self) -> u64 {
+        match self {
+            Self::Rows(rows) => rows.seq_idx,
+            Self::Ddl(ddl) => ddl.seq_idx,
+            Self::Transaction(txn) => txn.seq_idx,
+            Self::Exclusive(task, _) => task.seq_idx(),
+            Self::Barrier(_) => 0
+        }
+    }
+}
+
 
 
 #[derive(Debug, Clone)]
@@ -57,8 +122,8 @@ pub struct DmlData {
     pub table: String,
     pub dml_type: String,
     pub es: u64,
-    pub data: Vec<Vec<Value>>,
-    pub old_data: Vec<Vec<Value>>,
+    pub data: Vec<Vec<Option<ColumnValue>>>,
+    pub old_data: Vec<Vec<Option<ColumnValue>>>,
     pub pos: u32
 }
 
@@ -76,10 +141,9 @@ impl DmlData {
             pos:0
         }
     }
-    pub fn append_data(&mut self, idx: u64, dml_type: String, data: Vec<Vec<Value>>, old_data: Vec<Vec<Value>>, pos: u32) {
+    pub fn append_data(&mut self, idx: u64, dml_type: String, data: Vec<Vec<Option<ColumnValue>>>, old_data: Vec<Vec<Option<ColumnValue>>>, pos: u32) {
         self.id = idx;
         self.dml_type = dml_type;
-        self.es = current_ts();
         self.data.extend_from_slice(data.as_slice());
         self.old_data.extend_from_slice(old_data.as_slice());
         self.pos = pos
@@ -87,6 +151,78 @@ impl DmlData {
 }
 
 
+/// Canal FlatMessage 的字段按字母排序；data、old、mysqlType、sqlType 中的列按表的列顺序输出
+#[derive(Serialize)]
+struct FlatMessage<'a> {
+    data: ColumnRows<'a>,
+    database: &'a str,
+    es: u64,
+    id: u64,
+    isDdl: bool,
+    mysqlType: ColumnMap<'a, String>,
+    old: Option<ColumnRows<'a>>,
+    pkNames: &'a Option<Vec<String>>,
+    sql: &'a str,
+    sqlType: ColumnMap<'a, i16>,
+    table: &'a str,
+    ts: u128,
+    r#type: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    xid: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commitTs: Option<u64>
+}
+
+struct ColumnRows<'a> {
+    rows: &'a Vec<HashMap<String, Value>>,
+    fields: &'a [FieldMeta],
+    number_as_string: bool
+}
+
+impl Serialize for ColumnRows<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.rows.len()))?;
+        for row in self.rows.iter() {
+            let values = self.fields.iter()
+                .filter_map(|meta| row.get(&meta.name).map(|val| (meta.name.as_str(), val)))
+                .map(|(name, val)| match val {
+                    // Canal中所有的值都是字符串，JSON列也是JSON文本
+                Value::Number(_) | Value::Bool(_) | Value::Object(_) | Value::Array(_) if self.number_as_string => (name, Value::from(val.to_string())),
+                    _ => (name, val.clone())
+                });
+            seq.serialize_element(&OrderedMap(values.collect()))?;
+        }
+        seq.end()
+    }
+}
+
+/// 缺少的列直接跳过
+struct ColumnMap<'a, T> {
+    map: &'a HashMap<String, T>,
+    fields: &'a [FieldMeta]
+}
+
+impl<T: Serialize> Serialize for ColumnMap<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let entries: Vec<(&str, &T)> = self.fields.iter()
+            .filter_map(|meta| self.map.get(&meta.name).map(|val| (meta.name.as_str(), val)))
+            .collect();
+        OrderedMap(entries).serialize(serializer)
+    }
+}
+
+struct OrderedMap<K, V>(Vec<(K, V)>);
+
+impl<K: Serialize, V: Serialize> Serialize for OrderedMap<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, val) in self.0.iter() {
+            map.serialize_entry(key, val)?;
+        }
+        map.end()
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct DmlMessage {
     pub id: u64,
@@ -101,141 +237,37 @@ pub struct DmlMessage {
     pub sqlType: HashMap<String, i16>,
     pub mysqlType: HashMap<String, String>,
     pub data: Vec<HashMap<String, Value>>,
-    pub old: Option<Vec<HashMap<String, Value>>>
+    pub old: Option<Vec<HashMap<String, Value>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xid: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commitTs: Option<u64>
 }
 
 impl DmlMessage {
-    fn format_val(val: &Value) -> String {
-        if val.is_null() {
-            "null".to_string()
-        }else{
-            if val.is_string(){
-                format!("{}", val.to_string())
-            }else{
-                format!("\"{}\"", val.to_string())
-            }
-        }
-    }
-
-    fn format_json(&mut self,  fields: &mut Vec<FieldMeta>) -> String {
-        let mut buffer:Vec<String> = Vec::new();
-        buffer.push("{".to_string());
-        buffer.push("\"data\":[".to_string());
-        let data_count = &self.data.len();
-        if *data_count > 0usize {
-            for (i, record) in self.data.iter_mut().enumerate(){
-                buffer.push("{".to_string());
-                let mut key_buffer:Vec<String> = Vec::new();
-                for (idx, meta) in fields.iter().enumerate(){
-                    if let Some(val) = record.get(&meta.name){
-                        let data_value = format!("\"{}\":{}", &meta.name, Self::format_val(val));
-                        key_buffer.push(data_value);
-                    }
-                }
-                let dict_inner = key_buffer.join(",");
-                buffer.push(dict_inner);
-                if i != (*data_count - 1usize){
-                    buffer.push("},".to_string());
-                }else{
-                    buffer.push("}".to_string());
-                }
-            }
-        }
-        buffer.push("],".to_string());
-        buffer.push(format!("\"database\":\"{}\",", &self.database));
-        buffer.push(format!("\"es\":{},", self.es));
-        buffer.push(format!("\"id\":{},", self.id));
-        buffer.push("\"isDdl\": false,".to_string());
-        if self.mysqlType.len()>0usize {
-            buffer.push("\"mysqlType\":{".to_string());
-            for (idx, meta) in fields.iter().enumerate() {
-                if let Some(tp) = self.mysqlType.get(&meta.name) {
-                    if idx != (fields.len() - 1usize) {
-                        buffer.push(format!("\"{}\":\"{}\",", meta.name, tp));
-                    } else {
-                        buffer.push(format!("\"{}\":\"{}\"", meta.name, tp));
-                    }
-                } else {
-                    println!("table:{} meta:{} not found in {:?}", &self.table, &meta.name, &self.mysqlType);
-                }
-            }
-            buffer.push("},".to_string());
-        }else{
-            buffer.push("\"mysqlType\":{},".to_string());
-        }
-        if self.old.is_some(){
-            buffer.push("\"old\":[".to_string());
-            let old_count = self.old.clone().unwrap().len();
-            for (i, record) in self.old.clone().unwrap().iter().enumerate(){
-                buffer.push("{".to_string());
-                let mut key_buffer:Vec<String> = Vec::new();
-                for (idx, meta) in fields.iter().enumerate(){
-                    let val = &record.get::<String>(&meta.name);
-                    if let Some(val_ok) = val {
-                        let val_str = Self::format_val(val_ok);
-                        key_buffer.push(format!("\"{}\":{}", meta.name, val_str));
-                    }
-                }
-                let inner_str = key_buffer.join(",");
-                buffer.push(inner_str);
-                if i != (old_count - 1usize){
-                    buffer.push("},".to_string());
-                }else{
-                    buffer.push("}".to_string());
-                }
-            }
-            buffer.push("],".to_string());
-        }
-        if self.pkNames.is_none() {
-            buffer.push("\"pkNames\":null,".to_string());
-        }else{
-            buffer.push("\"pkNames\":[".to_string());
-            for (idx, name) in self.pkNames.clone().unwrap().iter().enumerate() {
-                if idx != (self.pkNames.clone().unwrap().len() - 1usize){
-                    buffer.push(format!("\"{name}\","));
-                }else{
-                    buffer.push(format!("\"{name}\""));
-                }
-            }
-            buffer.push("],".to_string())
-        }
-        buffer.push("\"sql\":\"\",".to_string());
-        buffer.push("\"sqlType\":{".to_string());
-        for (idx, meta) in fields.iter().enumerate(){
-            if let Some(sqlType) = self.sqlType.get::<String>(&meta.name){
-                if idx!= (fields.len() - 1usize) {
-                    buffer.push(format!("\"{}\":{},", meta.name, sqlType));
-                }else{
-                    buffer.push(format!("\"{}\":{}", meta.name, sqlType));
-                }
-            }else{
-                error!("没有找到列映射！table[{}]:{} not in {:?}", &self.table, &meta.name, self.sqlType);
-                panic!()
-            }
-        }
-        buffer.push("},".to_string());
-        buffer.push(format!("\"table\":\"{}\",", self.table));
-        buffer.push(format!("\"ts\":{},", self.ts));
-        buffer.push(format!("\"type\":\"{}\"", &self.r#type));
-        buffer.push("}".to_string());
-        buffer.join("")
-    }
-
-    fn text_field_data(val: &Value) -> String{
-        match val.as_array(){
-            Some(s)=>{String::from_utf8_lossy(s.iter().map(|n| n.as_u64().unwrap() as u8).collect::<Vec<u8>>().as_slice()).to_string()},
-            None=>String::from("")
-        }
-    }
-
-    fn blob_field_data(val: &Value) -> String {
-        match val.as_array(){
-            Some(s)=>{ String::from_utf16(s.iter().map(|n| n.as_u64().unwrap() as u16).collect::<Vec<u16>>().as_slice()).unwrap()},
-            None=>String::from("")
-        }
+    /// 序列化为Canal的FlatMessage，number_as_string 为true时和Canal一样把数字也输出为字符串
+    fn to_json(&self, fields: &[FieldMeta], number_as_string: bool) -> String {
+        let message = FlatMessage {
+            data: ColumnRows { rows: &self.data, fields, number_as_string },
+            database: &self.database,
+            es: self.es,
+            id: self.id,
+            isDdl: self.isDdl,
+            mysqlType: ColumnMap { map: &self.mysqlType, fields },
+            old: self.old.as_ref().map(|old| ColumnRows { rows: old, fields, number_as_string }),
+            pkNames: &self.pkNames,
+            sql: self.sql.as_deref().unwrap_or(""),
+            sqlType: ColumnMap { map: &self.sqlType, fields },
+            table: &self.table,
+            ts: self.ts,
+            r#type: &self.r#type,
+            xid: self.xid,
+            commitTs: self.commitTs,
+        };
+        serde_json::to_string(&message).expect("DML消息序列化失败")
     }
 
-    fn from_dml(mut dml: DmlData, fields: &mut Vec<FieldMeta>) -> Self {
+    fn from_dml(dml: DmlData, fields: &mut [FieldMeta], encoding: &BinaryEncoding) -> Self {
         let mut ins = Self::new(dml.id, dml.database, dml.table, dml.dml_type, dml.es);
         let mut pks: Vec<String> = Vec::new();
         let record_count = dml.data.len();
@@ -243,12 +275,11 @@ impl DmlMessage {
         let rc = if record_count > 0 { record_count } else { old_record_count };
 
         let mut old_record_vec: Vec<HashMap<String, Value>> = Vec::new();
+        let is_delete = ins.r#type == "DELETE";
 
         for record_id in 0..rc{
             let old_vals = dml.old_data.get(record_id);
             let new_vals = dml.data.get(record_id);
-            let no_new_val = new_vals.is_none();
-
             let mut record_data:HashMap<String, Value> = HashMap::new();
             let mut record_old:HashMap<String, Value> = HashMap::new();
 
@@ -257,67 +288,86 @@ impl DmlMessage {
                 if record_id == 0 {
                     ins.mysqlType.insert(file_meta.name.clone(), file_meta.field_type.clone());
                     ins.sqlType.insert(file_meta.name.clone(), sql_tp);
-                    if file_meta.is_pk {
-                        if !pks.contains(&file_meta.name){
-                            pks.insert(0, file_meta.name.clone());
+                    if file_meta.is_pk
+                        && !pks.contains(&file_meta.name){
+                            pks.push(file_meta.name.clone());
                         }
-                    }
                 }
-                let data_val =  if let Some(nv) = new_vals { nv.get(idx) } else { None };
-                let is_same = match old_vals {
-                    Some(old_values)=> {
-                        if let Some(ov) = old_values.get(idx) {
-                            if let Some(new_v) = data_val {
-                                ov.eq(new_v)
-                            } else {
-                                true
-                            }
-                        }else{
-                            true
-                        }
-                    },
-                    None=>true
+                // 不在行镜像中的列(binlog_row_image=MINIMAL/NOBLOB)直接跳过
+                let old_val = if let Some(ov) = old_vals { ov.get(idx).and_then(|v| v.as_ref()) } else { None };
+                // 和Canal一样，DELETE的data中是被删除的行
+                let data_val = if is_delete { old_val } else if let Some(nv) = new_vals { nv.get(idx).and_then(|v| v.as_ref()) } else { None };
+                let is_same = match (old_val, data_val) {
+                    (Some(ov), Some(new_v)) => ov.eq(new_v),
+                    _ => true
                 };
 
-                if sql_tp == 2005 {
-                    if let Some(dv) = data_val {
-                        let val_s = Self::text_field_data(dv);
-                        record_data.insert(file_meta.name.clone(), Value::from(val_s));
-                    }
-                    if !is_same{
-                        let old_val = old_vals.unwrap().get(idx).unwrap();
-                        let old_val_s = Self::text_field_data(old_val);
-                        record_old.insert(file_meta.name.clone(), Value::from(old_val_s));
-                    }
-                }else{
-                    if sql_tp == 2004 {
-                        if let Some(dv) = data_val {
-                            let val_s = Self::blob_field_data(dv);
-                            record_data.insert(file_meta.name.clone(), Value::from(val_s));
-                        }
-                        if !is_same {
-                            let old_val = old_vals.unwrap().get(idx).unwrap();
-                            let old_val_s = Self::blob_field_data(old_val);
-                            record_data.insert(file_meta.name.clone(), Value::from(old_val_s));
-                        }
-                    }else{
-                        if let Some(dv) = data_val {
-                            record_data.insert(file_meta.name.clone(), dv.clone());
-                        }
-                        if !is_same {
-                            let old_val = old_vals.unwrap().get(idx).unwrap();
-                            record_old.insert(file_meta.name.clone(), old_val.clone());
-                        }
-                    }
+                if let Some(dv) = data_val {
+                    record_data.insert(file_meta.name.clone(), file_meta.to_json_value(dv, encoding));
+                }
+                if !is_same {
+                    let old_val = old_val.unwrap();
+                    record_old.insert(file_meta.name.clone(), file_meta.to_json_value(old_val, encoding));
                 }
             }
             ins.data.push(record_data);
             old_record_vec.push(record_old);
         }
-        if pks.len() > 0{
+        if !pks.is_empty(){
             ins.pkNames = Some(pks);
         }
-        ins.old = Some(old_record_vec);
+        // 只有UPDATE有old，INSERT和DELETE为null
+        if ins.r#type == "UPDATE" {
+            ins.old = Some(old_record_vec);
+        }
+        ins
+    }
+
+    /// 按端口配置计算每行的消息key和分区，不同key的行拆成多条消息，同一个key的行保持原有顺序
+    fn split_by_key(&self, port: &Port) -> Vec<(Option<String>, Option<u64>, DmlMessage)> {
+        let table_hash = hash_key(&format!("{}.{}", self.database, self.table));
+        let route = |row: &HashMap<String, Value>| {
+            let key = port.message_key(&self.database, &self.table, &self.pkNames, row);
+            let partition = match port.partition {
+                PartitionStrategy::DEFAULT => None,
+                PartitionStrategy::TABLE => Some(table_hash),
+                PartitionStrategy::PRIMARY_KEY => match Port::pk_value(&self.pkNames, row) {
+                    Some(pk) => Some(hash_key(&format!("{}.{}|{pk}", self.database, self.table))),
+                    None => Some(table_hash)
+                }
+            };
+            (key, partition)
+        };
+        if self.data.len() < 2 {
+            let (key, partition) = route(self.data.first().unwrap_or(&HashMap::new()));
+            return vec![(key, partition, self.clone())];
+        }
+        let mut groups: Vec<(Option<String>, Option<u64>, DmlMessage)> = Vec::new();
+        for (idx, row) in self.data.iter().enumerate() {
+            let (key, partition) = route(row);
+            let old_row = self.old.as_ref().and_then(|old| old.get(idx).cloned());
+            let group = match groups.iter().position(|(k, p, _)| k.eq(&key) && p.eq(&partition)) {
+                Some(pos) => &mut groups[pos].2,
+                None => {
+                    let mut msg = self.clone();
+                    msg.data = Vec::new();
+                    msg.old = self.old.as_ref().map(|_| Vec::new());
+                    groups.push((key, partition, msg));
+                    &mut groups.last_mut().unwrap().2
+                }
+            };
+            group.data.push(row.clone());
+            if let (Some(old), Some(old_row)) = (group.old.as_mut(), old_row) {
+                old.push(old_row);
+            }
+        }
+        groups
+    }
+
+    fn from_ddl(mid: u64, database: String, table: String, ddl_type: String, es: u64, sql: String) -> Self {
+        let mut ins = Self::new(mid, database, table, ddl_type, es);
+        ins.isDdl = true;
+        ins.sql = Some(sql);
         ins
     }
 
@@ -337,6 +387,8 @@ impl DmlMessage {
             mysqlType: HashMap::new(),
             data: Vec::new(),
             old: None,
+            xid: None,
+            commitTs: None,
         }
     }
 }
@@ -345,10 +397,125 @@ impl DmlMessage {
 pub struct FieldMeta {
     pub name: String,
     pub field_type: String,
-    pub is_pk: bool
+    pub is_pk: bool,
+    /// 字符列的字符集，由collation得到，非字符列为None
+    pub charset: Option<String>
 }
 
 impl FieldMeta{
+    /// 用TABLE_MAP中的可选元数据生成列信息，类型写法和 information_schema 的 COLUMN_TYPE 一致。
+    /// 没有列名(binlog_row_metadata不是FULL)时返回None
+    pub fn from_table_map(tm: &TableMapEvent, col_types: &[ColumnType], col_metas: &[ColMeta]) -> Option<Vec<Self>> {
+        let md = &tm.metadata;
+        if md.column_names.len() != col_types.len() {
+            return None;
+        }
+        let quote_labels = |labels: Option<&Vec<Vec<u8>>>, charset: Option<&str>| {
+            labels.map(|labels| labels.iter()
+                .map(|l| format!("'{}'", charset::decode(charset, l).replace('\'', "''")))
+                .collect::<Vec<String>>().join(","))
+                .unwrap_or_default()
+        };
+        let (mut numeric_idx, mut char_idx, mut enum_idx, mut set_idx, mut geometry_idx) = (0usize, 0usize, 0usize, 0usize, 0usize);
+        let mut fields = Vec::new();
+        for (idx, (col_type, meta)) in col_types.iter().zip(col_metas.iter()).enumerate() {
+            let mut charset = None;
+            let field_type = match col_type {
+                ColumnType::TINYINT | ColumnType::SMALLINT | ColumnType::MEDIUMINT | ColumnType::INT | ColumnType::BIGINT
+                | ColumnType::FLOAT | ColumnType::DOUBLE | ColumnType::DECIMAL => {
+                    let unsigned = md.unsigned.get(numeric_idx).copied().unwrap_or(false);
+                    numeric_idx += 1;
+                    let name = match col_type {
+                        ColumnType::TINYINT => "tinyint".to_string(),
+                        ColumnType::SMALLINT => "smallint".to_string(),
+                        ColumnType::MEDIUMINT => "mediumint".to_string(),
+                        ColumnType::INT => "int".to_string(),
+                        ColumnType::BIGINT => "bigint".to_string(),
+                        ColumnType::FLOAT => "float".to_string(),
+                        ColumnType::DOUBLE => "double".to_string(),
+                        _ => format!("decimal({},{})", meta.precision.unwrap_or(10), meta.decimals.unwrap_or(0)),
+                    };
+                    if unsigned { format!("{name} unsigned") } else { name }
+                },
+                ColumnType::CHAR | ColumnType::VARCHAR | ColumnType::TEXT => {
+                    charset = md.charsets.get(char_idx).and_then(charset::collation_charset);
+                    let binary = charset == Some("binary");
+                    char_idx += 1;
+                    // 长度是字节数，按字符集的最大字节数换算为字符数
+                    let length = match (meta.max_length, charset.and_then(charset::max_bytes)) {
+                        (Some(bytes), Some(maxlen)) => format!("({})", bytes / maxlen),
+                        _ => String::new()
+                    };
+                    match (col_type, binary) {
+                        (ColumnType::CHAR, false) => format!("char{length}"),
+                        (ColumnType::CHAR, true) => format!("binary{length}"),
+                        (ColumnType::VARCHAR, false) => format!("varchar{length}"),
+                        (ColumnType::VARCHAR, true) => format!("varbinary{length}"),
+                        _ => {
+                            let prefix = match meta.length_size {
+                                Some(1) => "tiny",
+                                Some(3) => "medium",
+                                Some(4) => "long",
+                                _ => ""
+                            };
+                            format!("{prefix}{}", if binary { "blob" } else { "text" })
+                        }
+                    }
+                },
+                // ENUM和SET共用一个字符集序号
+                ColumnType::ENUM => {
+                    charset = md.enum_set_charsets.get(enum_idx + set_idx).and_then(charset::collation_charset);
+                    enum_idx += 1;
+                    format!("enum({})", quote_labels(md.enum_values.get(enum_idx - 1), charset))
+                },
+                ColumnType::SET => {
+                    charset = md.enum_set_charsets.get(enum_idx + set_idx).and_then(charset::collation_charset);
+                    set_idx += 1;
+                    format!("set({})", quote_labels(md.set_values.get(set_idx - 1), charset))
+                },
+                ColumnType::GEOMETRY => {
+                    geometry_idx += 1;
+                    let names = ["geometry", "point", "linestring", "polygon", "multipoint", "multilinestring", "multipolygon", "geomcollection"];
+                    md.geometry_types.get(geometry_idx - 1).and_then(|tp| names.get(*tp as usize)).unwrap_or(&"geometry").to_string()
+                },
+                ColumnType::TIME | ColumnType::DATETIME | ColumnType::TIMESTAMP => {
+                    let name = match col_type {
+                        ColumnType::TIME => "time",
+                        ColumnType::DATETIME => "datetime",
+                        _ => "timestamp"
+                    };
+                    match meta.fsp {
+                        Some(fsp) if fsp > 0 => format!("{name}({fsp})"),
+                        _ => name.to_string()
+                    }
+                },
+                ColumnType::DATE | ColumnType::NEWDATE => "date".to_string(),
+                ColumnType::OLD_TIME => "time".to_string(),
+                ColumnType::OLD_DATETIME => "datetime".to_string(),
+                ColumnType::OLD_TIMESTAMP => "timestamp".to_string(),
+                ColumnType::YEAR => "year".to_string(),
+                ColumnType::JSON => "json".to_string(),
+                ColumnType::BIT => match meta.bits {
+                    Some(bits) => format!("bit({bits})"),
+                    None => "bit".to_string()
+                },
+                ColumnType::NOT_MATCH => "unknown".to_string(),
+            };
+            fields.push(FieldMeta{
+                name: md.column_names[idx].clone(),
+                field_type,
+                is_pk: md.primary_key.contains(&idx),
+                charset: charset.map(|c| c.to_string()),
+            });
+        }
+        Some(fields)
+    }
+
+    /// 元数据和 information_schema 生成的类型都以 unsigned 结尾
+    pub fn is_unsigned(&self) -> bool {
+        self.field_type.ends_with("unsigned") || self.field_type.contains("unsigned ")
+    }
+
     pub fn get_sql_type(&mut self) -> i16 {
         if self.field_type.starts_with("tinyint") {
             return -6;
@@ -371,65 +538,190 @@ impl FieldMeta{
         if self.field_type.starts_with("decimal") {
             return 3;
         }
+        // timestamp也以time开头，需要先判断
+        if self.field_type.starts_with("datetime") || self.field_type.starts_with("timestamp") {
+            return 93
+        }
         if self.field_type.eq("date") {
             return 91;
         }
-        if self.field_type.eq("time") {
+        if self.field_type.starts_with("time") {
             return 92;
         }
         if self.field_type.starts_with("year") {
             return 12;
         }
-        if self.field_type.starts_with("datetime") || self.field_type.starts_with("timestamp") {
-            return 93
-        }
         if self.field_type.starts_with("char") {
             return 1;
         }
         if self.field_type.starts_with("varchar") {
             return 12;
         }
+        if self.field_type.starts_with("binary") {
+            return -2;
+        }
+        if self.field_type.starts_with("varbinary") {
+            return -3;
+        }
         if self.field_type.ends_with("blob") {
             return 2004;
         }
         if self.field_type.ends_with("text") {
             return 2005;
         }
+        if self.field_type.eq("json") {
+            return 12;
+        }
+        if self.field_type.starts_with("enum") {
+            return 4;
+        }
+        if self.field_type.starts_with("set") || self.field_type.starts_with("bit") {
+            return -7;
+        }
+        if ["geometry", "point", "linestring", "polygon", "multipoint", "multilinestring", "multipolygon", "geometrycollection", "geomcollection"].contains(&self.field_type.as_str()) {
+            return -2;
+        }
         error!("invalid:{:?}", self);
         -999
     }
+
+    /// 解析 enum('a','b') / set('a','b') 中的标签
+    fn labels(&self) -> Vec<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let inner = match (self.field_type.find('('), self.field_type.rfind(')')) {
+            (Some(start), Some(end)) if start < end => &self.field_type[start + 1..end],
+            _ => return labels
+        };
+        let mut current = String::new();
+        let mut in_quote = false;
+        let mut chars = inner.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\'' {
+                if in_quote && chars.peek() == Some(&'\'') {
+                    // 两个单引号表示转义的单引号
+                    current.push('\'');
+                    chars.next();
+                }else if in_quote {
+                    labels.push(current.clone());
+                    current.clear();
+                    in_quote = false;
+                }else{
+                    in_quote = true;
+                }
+            }else if in_quote {
+                current.push(c);
+            }
+        }
+        labels
+    }
+
+    /// BLOB、BINARY和VARBINARY，值按 binary_encoding 输出
+    pub fn is_binary(&self) -> bool {
+        self.field_type.starts_with("binary") || self.field_type.starts_with("varbinary") || self.field_type.ends_with("blob")
+    }
+
+    /// 按列的字符集转换为UTF-8，CHAR去掉末尾的填充空格。
+    /// ucs2/utf16/utf32的空格不是单个0x20，所以解码之后再去掉
+    fn decode_text(&self, bs: &[u8]) -> String {
+        let text = charset::decode(self.charset.as_deref(), bs);
+        if self.field_type.starts_with("char") {
+            text.trim_end_matches(' ').to_string()
+        } else {
+            text
+        }
+    }
+
+    /// 转换为消息中的值，字节按列类型解码成字符串或者按配置编码
+    pub fn to_json_value(&self, val: &ColumnValue, encoding: &BinaryEncoding) -> Value {
+        match val {
+            ColumnValue::Value(val) => self.resolve_value(val),
+            ColumnValue::Bytes(bs) if self.is_binary() => Value::from(match encoding {
+                BinaryEncoding::BASE64 => STANDARD.encode(bs),
+                BinaryEncoding::HEX => hex::encode(bs),
+                BinaryEncoding::UTF8_LOSSY => String::from_utf8_lossy(bs).to_string(),
+            }),
+            ColumnValue::Bytes(bs) => Value::from(self.decode_text(bs)),
+        }
+    }
+
+    /// ENUM 序号和 SET 位掩码转换为对应的标签
+    pub fn resolve_value(&self, val: &Value) -> Value {
+        let is_enum = self.field_type.starts_with("enum(");
+        let is_set = self.field_type.starts_with("set(");
+        if !(is_enum || is_set) {
+            return val.clone();
+        }
+        let num = match val.as_u64() {
+            Some(num) => num,
+            None => return val.clone()
+        };
+        let labels = self.labels();
+        if is_enum {
+            // 序号0表示插入了非法值，MySQL中为空字符串
+            let label = if num == 0 { "".to_string() } else { labels.get(num as usize - 1).cloned().unwrap_or_default() };
+            Value::from(label)
+        }else{
+            let selected: Vec<String> = labels.iter().enumerate()
+                .filter(|(idx, _)| *idx < 64 && num & (1u64 << idx) > 0)
+                .map(|(_, label)| label.clone())
+                .collect();
+            Value::from(selected.join(","))
+        }
+    }
 }
 
 
 #[derive(Clone)]
 struct TableMetaMapping {
-    mapping: Arc<Mutex<HashMap<u32, Vec<FieldMeta>>>>,
-    not_exists: Vec<String>
+    mapping: Arc<Mutex<HashMap<u32, (String, Vec<FieldMeta>)>>>,
+    not_exists: Arc<Mutex<Vec<String>>>
 }
 
 impl TableMetaMapping {
     fn new()->Self{
-        Self{ mapping: Arc::new(Mutex::new(HashMap::new())), not_exists: vec![] }
+        Self{ mapping: Arc::new(Mutex::new(HashMap::new())), not_exists: Arc::new(Mutex::new(vec![])) }
     }
 
-    fn update_mapping(&mut self, conn: &mut MySQLConnection, tid: u32, db: String, table: String, table_map: &Vec<ColMeta>) -> Result<Vec<FieldMeta>, ()> {
-        if self.not_exists.contains(&table) {
+    /// 按table_id缓存列信息。优先使用TABLE_MAP中的元数据，没有列名时(binlog_row_metadata不是FULL)才查询表结构
+    fn update_mapping(&mut self, conn: &mut MySQLConnection, tm: &TableMapEvent, col_types: &[ColumnType], col_metas: &[ColMeta]) -> Result<Vec<FieldMeta>, ()> {
+        let tid = tm.header.table_id as u32;
+        let db = tm.schema_name.clone();
+        let table = tm.table_name.clone();
+        let full_name = format!("{db}.{table}");
+        if let Some(cols) = self.cached(tid, &full_name) {
+            return Ok(cols);
+        }
+        if let Some(cols) = FieldMeta::from_table_map(tm, col_types, col_metas) {
+            loop {
+                if let Ok(mut mp) = self.mapping.lock() {
+                    mp.insert(tid, (full_name, cols.clone()));
+                    return Ok(cols);
+                }else{
+                    thread::sleep(std::time::Duration::from_micros(generate_random_number() as u64));
+                }
+            }
+        }
+        if self.not_exists.lock().is_ok_and(|ne| ne.contains(&full_name)) {
             return Err(())
         }
         loop {
             if let Ok(mut mp) = self.mapping.lock() {
-                if !mp.contains_key(&tid) {
+                if mp.get(&tid).is_none_or(|(name, _)| !name.eq(&full_name)) {
                     let mut cols = Vec::new();
-                    //println!("Check Mapping {tid} {db} {table} in {:?}", mp.contains_key(&tid));
-                    if conn.desc_table(db.clone(), table.clone(), &mut cols, table_map){
-                        mp.insert(tid, cols.clone());
+                    if conn.desc_table(db.clone(), table.clone(), &mut cols, col_metas){
+                        mp.insert(tid, (full_name, cols.clone()));
                         return Ok(cols.clone());
                     }else {
-                        self.not_exists.push(table.clone());
+                        // 连接断开导致的失败不能认为表不存在
+                        if !conn.is_broken() {
+                            if let Ok(mut ne) = self.not_exists.lock() {
+                                ne.push(full_name);
+                            }
+                        }
                         return Err(());
                     }
                 }else{
-                    if let Some(fm) = mp.get(&tid) {
+                    if let Some((_, fm)) = mp.get(&tid) {
                         return Ok(fm.clone());
                     }else{
                         return Err(());
@@ -440,177 +732,1045 @@ impl TableMetaMapping {
             }
         }
     }
+
+    /// 缓存中table_id对应的还是同一张表时返回列信息
+    fn cached(&self, tid: u32, full_name: &String) -> Option<Vec<FieldMeta>> {
+        loop {
+            if let Ok(mp) = self.mapping.lock() {
+                return mp.get(&tid).filter(|(name, _)| name.eq(full_name)).map(|(_, cols)| cols.clone());
+            }else{
+                thread::sleep(std::time::Duration::from_micros(generate_random_number() as u64));
+            }
+        }
+    }
+
+    /// 重连后table_id可能对应到别的表，清空全部缓存
+    fn clear(&mut self) {
+        loop {
+            if let Ok(mut mp) = self.mapping.lock() {
+                mp.clear();
+                break;
+            }else{
+                thread::sleep(std::time::Duration::from_micros(generate_random_number() as u64));
+            }
+        }
+        if let Ok(mut ne) = self.not_exists.lock() {
+            ne.clear();
+        }
+    }
+
+    /// 表结构变更后清除缓存，下一个行事件会重新获取列信息，table为空表示整个库
+    fn invalidate(&mut self, db: &String, table: &String) {
+        let matches = |full_name: &String| {
+            if table.is_empty() {
+                full_name.starts_with(format!("{db}.").as_str())
+            }else{
+                full_name.eq(&format!("{db}.{table}"))
+            }
+        };
+        loop {
+            if let Ok(mut mp) = self.mapping.lock() {
+                mp.retain(|_, (full_name, _)| !matches(full_name));
+                break;
+            }else{
+                thread::sleep(std::time::Duration::from_micros(generate_random_number() as u64));
+            }
+        }
+        if let Ok(mut ne) = self.not_exists.lock() {
+            ne.retain(|full_name| !matches(full_name));
+        }
+    }
 }
 
 #[derive(Debug)]
 struct Pool {
-    pub tx_channel : HashMap<u32, Arc<Mutex<Sender<RowEvents>>>>
+    pub tx_channel : HashMap<u32, Arc<Mutex<Sender<WorkerEvent>>>>
 }
 
 impl Pool {
-    pub fn regist_tx(&mut self, key: u32, tx: Arc<Mutex<Sender<RowEvents>>>) {
+    pub fn regist_tx(&mut self, key: u32, tx: Arc<Mutex<Sender<WorkerEvent>>>) {
         self.tx_channel.insert(key, tx);
     }
 
-    pub fn push(&mut self, data: &RowEvents) {
-        let i = ((data.seq_idx + 1) % self.tx_channel.len() as u64) as u32;
+    fn round_robin(&self, seq_idx: u64) -> u32 {
+        ((seq_idx + 1) % self.tx_channel.len() as u64) as u32
+    }
+
+    fn by_hash(&self, hash: u64) -> u32 {
+        (hash % self.tx_channel.len() as u64) as u32
+    }
+
+    pub fn push(&mut self, i: u32, data: &WorkerEvent) {
         if let Some(tx_ref) = self.tx_channel.get_mut(&i) {
             if let Ok(tx) = tx_ref.lock(){
-                tx.send(data.clone()).expect("send error");
+                // worker已经退出时流程会被停止，这里不再处理
+                if tx.send(data.clone()).is_err() {
+                    error!("worker[{i}]已经退出");
+                }
             }else{
                 error!("==========>(夭寿啦，获取锁失败了)");
             }
         }
     }
+
+    /// 交给owner独占处理，其它worker收到屏障后等待，保证和前后的事件都不会并发
+    pub fn push_exclusive(&mut self, owner: u32, data: WorkerEvent) {
+        let barrier = Arc::new(Barrier::new(self.tx_channel.len()));
+        for i in 0..self.tx_channel.len() as u32 {
+            if i == owner {
+                self.push(i, &WorkerEvent::Exclusive(Box::new(data.clone()), barrier.clone()));
+            }else{
+                self.push(i, &WorkerEvent::Barrier(barrier.clone()));
+            }
+        }
+    }
+}
+
+
+/// 查询表结构用的连接，连接失败时等待后重试。认证失败重试也没用，停止整个流程并返回None
+fn connect(config: &Config, tracker: &Arc<Mutex<CommitTracker>>) -> Option<MySQLConnection> {
+    loop {
+        match MySQLConnection::get_connection(config.db_ip.as_str(), config.db_port as u32, config.max_packages as u32, config.user_name.clone(), config.passwd.clone(), &config.ssl) {
+            Ok(conn) => return Some(conn),
+            Err(err) if err.is_fatal() => {
+                abort_pipeline(tracker.clone(), format!("worker连接数据库失败: {err}"));
+                return None;
+            },
+            Err(err) => {
+                error!("连接数据库失败: {err}，5秒后重试");
+                thread::sleep(std::time::Duration::from_secs(5));
+            }
+        }
+    }
 }
 
+/// 和Kafka默认分区器一样的murmur2，结果不随Rust版本变化，分区和Java客户端用同一个key时一致
+fn murmur2(data: &[u8]) -> i32 {
+    const SEED: u32 = 0x9747b28c;
+    const M: u32 = 0x5bd1e995;
+    const R: u32 = 24;
+    let mut h = SEED ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in chunks.by_ref() {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (idx, b) in tail.iter().enumerate().rev() {
+            h ^= (*b as u32) << (8 * idx);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+/// 取正数后的murmur2，和Kafka的 toPositive(murmur2(key)) 相同
+fn hash_key(key: &String) -> u64 {
+    (murmur2(key.as_bytes()) & 0x7fffffff) as u64
+}
+
+/// 在主线程中决定事件交给哪个worker。PRIMARY_KEY模式需要解码行数据拿到主键值，
+/// 主键只取自TABLE_MAP的元数据(binlog_row_metadata=FULL)，不查询表结构，拿不到时按表分发
+struct Dispatcher {
+    mode: DispatchMode,
+    table_map: TableMap
+}
+
+impl Dispatcher {
+    fn new(config: &Config) -> Self {
+        let mode = config.dispatch.clone().unwrap_or(DispatchMode::ROUND_ROBIN);
+        Self{ mode, table_map: TableMap::with_temporal(TemporalFormat::from_config(config)) }
+    }
+
+    /// 返回 (表名, 每一行的主键哈希)，表没有主键或者解码失败时行哈希为None。
+    /// 更新了主键的行有前后两个哈希
+    fn row_hashes(&mut self, data: &RowEvents) -> Option<(String, Option<Vec<Vec<u64>>>)> {
+        let (_, tm) = TableMapEvent::decode(data.table_map.payload.as_slice()).ok()?;
+        let full_name = format!("{}.{}", tm.schema_name, tm.table_name);
+        if self.mode != DispatchMode::PRIMARY_KEY {
+            return Some((full_name, None));
+        }
+        let ev = match &data.row_event {
+            Some(ev) => ev,
+            None => return Some((full_name, None))
+        };
+        let table_id = tm.header.table_id;
+        self.table_map.decode_columns(table_id, tm.column_types.clone(), tm.column_metas.as_bytes());
+        let col_types = self.table_map.mapping[&table_id].clone();
+        let col_metas = self.table_map.metas[&table_id].clone();
+        let meta = match FieldMeta::from_table_map(&tm, &col_types, &col_metas) {
+            Some(meta) => meta,
+            None => return Some((full_name, None))
+        };
+        self.table_map.set_unsigned(table_id, &meta.iter().map(|f| f.is_unsigned()).collect::<Vec<bool>>());
+        self.table_map.set_binary(table_id, &meta.iter().map(|f| f.is_binary()).collect::<Vec<bool>>());
+        let pk_idx: Vec<usize> = meta.iter().enumerate().filter(|(_, m)| m.is_pk).map(|(idx, _)| idx).collect();
+        if pk_idx.is_empty() {
+            return Some((full_name, None));
+        }
+        let rows: Option<Vec<(Vec<Option<ColumnValue>>, Option<Vec<Option<ColumnValue>>>)>> = match ev.header.event_type {
+            30 => WriteRowEvent::decode(ev.payload.as_bytes()).ok()
+                .and_then(|(i, event)| WriteRowEvent::decode_column_multirow_vals(&self.table_map, i, event.header.table_id, &event.present).ok())
+                .map(|(_, rows)| rows.into_iter().map(|row| (row, None)).collect()),
+            31 | UpdateRowEvent::PARTIAL_EVENT_TYPE => UpdateRowEvent::decode(ev.payload.as_bytes()).ok()
+                .and_then(|(i, event)| UpdateRowEvent::fetch_rows(i, &self.table_map, event.header.table_id, &event.present_before, &event.present_after, ev.header.event_type == UpdateRowEvent::PARTIAL_EVENT_TYPE).ok())
+                .map(|(_, (old_rows, new_rows))| old_rows.into_iter().zip(new_rows.into_iter().map(Some)).collect()),
+            32 => DeleteRowEvent::decode(ev.payload.as_bytes()).ok()
+                .and_then(|(i, event)| DeleteRowEvent::fetch_rows(i, &self.table_map, event.header.table_id, &event.present).ok())
+                .map(|(_, rows)| rows.into_iter().map(|row| (row, None)).collect()),
+            _ => None
+        };
+        let rows = match rows {
+            Some(rows) => rows,
+            None => return Some((full_name, None))
+        };
+        let pk_hash = |row: &Vec<Option<ColumnValue>>| {
+            let mut key = full_name.clone();
+            for idx in pk_idx.iter() {
+                if let Some(Some(val)) = row.get(*idx) {
+                    key.push_str(format!("|{val}").as_str());
+                }
+            }
+            hash_key(&key)
+        };
+        let hashes = rows.iter().map(|(row, new_row)| {
+            let mut hashes = vec![pk_hash(row)];
+            // MINIMAL模式下后镜像可能不包含主键，这时主键没有变
+            if let Some(new_row) = new_row.as_ref().filter(|r| pk_idx.iter().all(|idx| matches!(r.get(*idx), Some(Some(_))))) {
+                let new_hash = pk_hash(new_row);
+                if new_hash != hashes[0] {
+                    hashes.push(new_hash);
+                }
+            }
+            hashes
+        }).collect();
+        Some((full_name, Some(hashes)))
+    }
+}
+
+/// 按主键哈希把行分给worker，返回每个worker负责的行下标。
+/// 更新主键后前后两个主键属于不同的worker时返回None，需要独占处理才能和两边的事件都保持顺序
+fn group_rows(pool: &Pool, row_hashes: &[Vec<u64>]) -> Option<Vec<(u32, Option<Vec<usize>>)>> {
+    let mut routes: Vec<(u32, Option<Vec<usize>>)> = Vec::new();
+    for (row_idx, hashes) in row_hashes.iter().enumerate() {
+        let workers: Vec<u32> = hashes.iter().map(|hash| pool.by_hash(*hash)).collect();
+        let i = *workers.first()?;
+        if workers.iter().any(|w| *w != i) {
+            return None;
+        }
+        if let Some((_, Some(rows))) = routes.iter_mut().find(|(w, _)| *w == i) {
+            rows.push(row_idx);
+        }else{
+            routes.push((i, Some(vec![row_idx])));
+        }
+    }
+    if routes.len() == 1 {
+        routes[0].1 = None;
+    }
+    Some(routes)
+}
 
 pub struct Workers {
-    pool:Pool
+    pool:Pool,
+    mapping: TableMetaMapping,
+    dispatcher: Option<Dispatcher>,
+    tracker: Option<Arc<Mutex<CommitTracker>>>
 }
 
 impl Workers {
     pub fn new()->Self{
-        Self{ pool: Pool{ tx_channel: HashMap::new() } }
+        Self{ pool: Pool{ tx_channel: HashMap::new() }, mapping: TableMetaMapping::new(), dispatcher: None, tracker: None }
     }
 
+    fn track(&mut self, seq_idx: u64, parts: usize) {
+        if let Some(tracker) = &self.tracker {
+            track_event(tracker.clone(), seq_idx, parts);
+        }
+    }
 
-    pub fn start(&mut self, size: usize, queue: MessageQueues, instances: Vec<Instance>, config: Config){
+    /// 断线重连后清空表结构缓存
+    pub fn reset_table_meta(&mut self) {
+        self.mapping.clear();
+        if let Some(dispatcher) = self.dispatcher.as_mut() {
+            dispatcher.table_map = TableMap::with_temporal(dispatcher.table_map.temporal.clone());
+        }
+    }
 
-        let mut mapping = TableMetaMapping::new();
+    fn mode(&self) -> DispatchMode {
+        self.dispatcher.as_ref().map(|d| d.mode.clone()).unwrap_or(DispatchMode::ROUND_ROBIN)
+    }
+
+
+    pub fn start(&mut self, size: usize, queue: MessageQueues, instances: Vec<Instance>, config: Config, tracker: Arc<Mutex<CommitTracker>>){
+
+        let mapping = self.mapping.clone();
+        self.dispatcher = Some(Dispatcher::new(&config));
+        self.tracker = Some(tracker.clone());
         for thread_id in 0..size {
-            let (tx, rx) = channel::<RowEvents>();
+            let (tx, rx) = channel::<WorkerEvent>();
             let tx = Arc::new(Mutex::new(tx));
             self.pool.regist_tx(thread_id as u32, tx);
             let mut the_mapping = mapping.clone();
             let the_queue = queue.clone();
             let the_ins = instances.clone();
             let the_config = config.clone();
+            let the_tracker = tracker.clone();
             thread::spawn(move || {
-                worker_body(thread_id, rx, &mut the_mapping, the_queue, the_ins, the_config);
+                let _guard = PanicGuard{ name: format!("worker[{thread_id}]"), tracker: the_tracker.clone() };
+                worker_body(thread_id, rx, &mut the_mapping, the_queue, the_ins, the_config, the_tracker);
             });
 
         }
     }
 
+    /// 行事件交给哪些worker，None表示需要独占处理
+    fn routes(&mut self, data: &RowEvents) -> Option<Vec<(u32, Option<Vec<usize>>)>> {
+        let hashes = if self.mode() == DispatchMode::ROUND_ROBIN {
+            None
+        }else{
+            self.dispatcher.as_mut().and_then(|d| d.row_hashes(data))
+        };
+        match hashes {
+            None => Some(vec![(self.pool.round_robin(data.seq_idx), None)]),
+            Some((full_name, None)) => Some(vec![(self.pool.by_hash(hash_key(&full_name)), None)]),
+            Some((full_name, Some(row_hashes))) => {
+                // 按行拆分，同一个主键的行始终交给同一个worker
+                let routes = group_rows(&self.pool, &row_hashes)?;
+                if routes.is_empty() {
+                    return Some(vec![(self.pool.by_hash(hash_key(&full_name)), None)]);
+                }
+                Some(routes)
+            }
+        }
+    }
+
     pub fn push(&mut self, data: &RowEvents) {
-        self.pool.push(data);
+        match self.routes(data) {
+            Some(routes) => {
+                self.track(data.seq_idx, routes.len());
+                for (i, rows) in routes {
+                    let mut part = data.clone();
+                    part.rows = rows;
+                    self.pool.push(i, &WorkerEvent::Rows(part));
+                }
+            },
+            None => {
+                self.track(data.seq_idx, 1);
+                let i = self.pool.round_robin(data.seq_idx);
+                self.pool.push_exclusive(i, WorkerEvent::Rows(data.clone()));
+            }
+        }
+    }
+
+    /// DDL是所有worker的屏障：之前的行事件都用旧的表结构处理完之后才清除缓存，之后的行事件才开始处理，
+    /// 所以不管用哪种分发方式，同一张表的DDL和行事件都不会乱序
+    pub fn push_ddl(&mut self, data: DdlEvent) {
+        let i = self.pool.round_robin(data.seq_idx);
+        self.track(data.seq_idx, 1);
+        self.pool.push_exclusive(i, WorkerEvent::Ddl(data));
+    }
+
+    /// 事务整个交给一个worker。非轮询模式下事务中的行都属于同一个worker时交给它，
+    /// 涉及多个worker时独占处理，保证和这些表(行)的其它事件保持顺序
+    pub fn push_transaction(&mut self, data: TransactionEvents) {
+        self.track(data.seq_idx, 1);
+        if self.mode() == DispatchMode::ROUND_ROBIN {
+            let i = self.pool.round_robin(data.seq_idx);
+            self.pool.push(i, &WorkerEvent::Transaction(data));
+            return;
+        }
+        let mut owners: Vec<u32> = Vec::new();
+        for rows in data.rows.iter() {
+            match self.routes(rows) {
+                Some(routes) => {
+                    for (i, _) in routes {
+                        if !owners.contains(&i) {
+                            owners.push(i);
+                        }
+                    }
+                },
+                None => {
+                    owners.clear();
+                    break;
+                }
+            }
+        }
+        if owners.len() == 1 {
+            self.pool.push(owners[0], &WorkerEvent::Transaction(data));
+        }else{
+            let i = self.pool.round_robin(data.seq_idx);
+            self.pool.push_exclusive(i, WorkerEvent::Transaction(data));
+        }
+    }
+}
+
+fn handle_ddl(ddl: DdlEvent, mapping: &mut TableMetaMapping, instances: &mut [Instance]) -> Vec<(String, QueueMessage)> {
+    let mut messages: Vec<(String, QueueMessage)> = Vec::new();
+    let query = match QueryEvent::decode(ddl.query.payload.as_slice()) {
+        Ok((_, query)) => query,
+        Err(err) => {
+            error!("解码Query包失败：{:?} \n data: {:?}", err, ddl.query.payload);
+            return messages;
+        }
+    };
+    let info: DdlInfo = match query.parse_ddl() {
+        Some(info) => info,
+        None => return messages
+    };
+    info!("DDL: {}", &query.statement);
+    for (db, table) in info.tables.iter() {
+        mapping.invalidate(db, table);
+    }
+    let (db, table) = match info.tables.first() {
+        Some(name) => name.clone(),
+        None => (query.database.clone(), "".to_string())
+    };
+    let mut ports: Vec<Port> = Vec::new();
+    for instance in instances.iter_mut() {
+        // Debezium和Avro的表结构变更不发送到数据主题，Avro通过注册新版本的schema体现
+        if let Some(port) = instance.check_if_need_a_mq(db.clone(), table.clone()).filter(|p| p.format == MessageFormat::CANAL) {
+            ports.push(port);
+        }
+    }
+    if ports.is_empty() {
+        return messages;
+    }
+    let es = ddl.query.header.timestamp as u64 * 1000;
+    let message = DmlMessage::from_ddl(ddl.seq_idx, db, table, info.ddl_type, es, query.statement.clone());
+    let json_str = serde_json::to_string(&message).expect("DDL消息序列化失败");
+    for port in ports {
+        let (key, partition_hash, _) = message.split_by_key(&port).remove(0);
+        messages.push((port.mq, QueueMessage { topic: port.topic, payload: json_str.clone().into_bytes(), seq: ddl.seq_idx, key, partition_hash }));
+    }
+    messages
+}
+
+/// 只保留分发给当前worker的行
+fn select_rows<T>(rows: Vec<T>, picked: &Option<Vec<usize>>) -> Vec<T> {
+    match picked {
+        Some(picked) => rows.into_iter().enumerate().filter(|(idx, _)| picked.contains(idx)).map(|(_, row)| row).collect(),
+        None => rows
+    }
+}
+
+/// 各种消息格式需要的配置，AVRO格式需要schema注册中心
+struct Serializers {
+    number_as_string: bool,
+    encoding: BinaryEncoding,
+    registry: Option<SchemaRegistry>
+}
+
+impl Serializers {
+    /// AVRO格式是否配置了schema_registry在启动前检查中确认
+    fn from_config(config: &Config) -> Self {
+        Self {
+            number_as_string: config.number_as_string.unwrap_or(true),
+            encoding: config.binary_encoding.clone().unwrap_or(BinaryEncoding::BASE64),
+            registry: config.schema_registry.as_ref().map(SchemaRegistry::new),
+        }
+    }
+}
+
+/// 解码后的行事件，ports为需要发送的目的地
+struct DecodedRows {
+    ports: Vec<Port>,
+    message: DmlMessage,
+    fields: Vec<FieldMeta>,
+    source: SourceInfo
+}
+
+/// 消息生成失败的原因，出现后worker停止整个流程，位置停在这个事件之前
+#[derive(Error, Debug)]
+enum WorkerError {
+    #[error("行事件解码失败 表:{table} 位置:{binlog}:{pos} :{reason}")]
+    Decode { table: String, binlog: String, pos: u32, reason: String },
+    #[error(transparent)]
+    Avro(#[from] AvroError),
+}
+
+/// 解码失败时返回错误，不能跳过这些行。不需要发送的表返回None
+fn decode_row_events(data: RowEvents, table_map: &mut TableMap, mapping: &mut TableMetaMapping, conn: &mut MySQLConnection, instances: &mut [Instance], encoding: &BinaryEncoding) -> Result<Option<DecodedRows>, WorkerError> {
+    let mut ports: Vec<Port> = Vec::new();
+    let decode_error = |table: String, pos: u32, reason: String| WorkerError::Decode { table, binlog: data.binlog.clone(), pos, reason };
+    let tablemap = match TableMapEvent::decode(data.table_map.payload.as_slice()){
+        Ok((_, table))=>{ table },
+        Err(err)=> {
+            error!("解码19包失败：{:?} \n data: {:?}", err, data.table_map.payload);
+            return Err(decode_error("TABLE_MAP".to_string(), data.table_map.header.log_pos, format!("{err:?}")));
+        }
+    };
+
+    let tm = tablemap.clone();
+    table_map.decode_columns(tm.header.table_id, tm.column_types, tm.column_metas.as_bytes());
+    let mut current_data = DmlData::new_data(tablemap.header.table_id as u32, tablemap.schema_name.clone(), tablemap.table_name.clone());
+    for instance in instances.iter_mut(){
+        if let Some(port) = instance.check_if_need_a_mq(current_data.database.clone(), current_data.table.clone()) {
+            ports.push(port);
+        }
+    }
+    if ports.is_empty() {
+        return Ok(None);
+    }
+    let ev = match data.row_event {
+        Some(ref ev) => ev.clone(),
+        None => return Ok(None)
+    };
+    if ![32u8, 31u8, 30u8, 39u8].contains(&ev.header.event_type) {
+        return Ok(None);
+    }
+    let full_name = format!("{}.{}", current_data.database, current_data.table);
+    let row_error = |reason: String| decode_error(full_name.clone(), ev.header.log_pos, reason);
+    // 和DDL一样，es为binlog中事件的时间
+    current_data.es = ev.header.timestamp as u64 * 1000;
+    let tm = tablemap.clone();
+    let meta = match mapping.update_mapping(conn,
+                                           &tm,
+                                           &table_map.mapping[&tm.header.table_id],
+                                           &table_map.metas[&tm.header.table_id]
+    ) {
+        Ok(meta) => meta,
+        Err(_) => {
+            return Ok(None);
+        }
+    };
+    if meta.is_empty() {
+        error!("表{}.{} 不存在", current_data.database, current_data.table);
+        return Ok(None);
     }
+    table_map.set_unsigned(tm.header.table_id, &meta.iter().map(|f| f.is_unsigned()).collect::<Vec<bool>>());
+    table_map.set_binary(tm.header.table_id, &meta.iter().map(|f| f.is_binary()).collect::<Vec<bool>>());
+    if ev.header.event_type == 30 {
+        let (i, event) = WriteRowEvent::decode(ev.payload.as_bytes()).map_err(|err| row_error(format!("插入包解码错误 {err:?}")))?;
+        let (_, rows) = WriteRowEvent::decode_column_multirow_vals(table_map, i, event.header.table_id, &event.present)
+            .map_err(|err| row_error(format!("数据列解码错误 {err:?}")))?;
+        current_data.append_data(data.seq_idx, "INSERT".to_string(), select_rows(rows, &data.rows), Vec::new(), ev.header.log_pos);
+    }
+    if ev.header.event_type == 31 || ev.header.event_type == UpdateRowEvent::PARTIAL_EVENT_TYPE {
+        let (i, event) = UpdateRowEvent::decode(ev.payload.as_bytes()).map_err(|err| row_error(format!("更新包解码错误 {err:?}")))?;
+        let partial = ev.header.event_type == UpdateRowEvent::PARTIAL_EVENT_TYPE;
+        let (_, (old_val, new_val)) = UpdateRowEvent::fetch_rows(i, table_map, event.header.table_id, &event.present_before, &event.present_after, partial)
+            .map_err(|err| row_error(format!("数据列解码错误 {err:?}")))?;
+        current_data.append_data(data.seq_idx, "UPDATE".to_string(), select_rows(new_val, &data.rows), select_rows(old_val, &data.rows), ev.header.log_pos);
+    }
+    if ev.header.event_type == 32{
+        let (i, event) = DeleteRowEvent::decode(ev.payload.as_bytes()).map_err(|err| row_error(format!("删除包解码错误 {err:?}")))?;
+        let (_, old_values) = DeleteRowEvent::fetch_rows(i, table_map, event.header.table_id, &event.present)
+            .map_err(|err| row_error(format!("数据列解码错误 {err:?}")))?;
+        current_data.append_data(data.seq_idx, "DELETE".to_string(), Vec::new(), select_rows(old_values, &data.rows), ev.header.log_pos);
+    }
+    let mut fields = meta;
+    let mut message = DmlMessage::from_dml(current_data, &mut fields, encoding);
+    message.sql = data.sql;
+    let source = SourceInfo { binlog: data.binlog.clone(), pos: ev.header.log_pos, server_id: ev.header.server_id, gtid: data.gtid.clone(), ts_ms: ev.header.timestamp as u64 * 1000 };
+    Ok(Some(DecodedRows{ ports, message, fields, source }))
 }
 
-fn worker_body(thread_id: usize, rx: Receiver<RowEvents>, mapping: &mut TableMetaMapping, mut queue: MessageQueues, mut instances: Vec<Instance>, config: Config) {
+fn handle_transaction(txn: TransactionEvents, mode: &TransactionMode, table_map: &mut TableMap, mapping: &mut TableMetaMapping, conn: &mut MySQLConnection, instances: &mut [Instance], serializers: &mut Serializers) -> Result<Vec<(String, QueueMessage)>, WorkerError> {
+    let mut messages: Vec<(String, QueueMessage)> = Vec::new();
+    let mut decoded: Vec<DecodedRows> = Vec::new();
+    for rows in txn.rows {
+        if let Some(d) = decode_row_events(rows, table_map, mapping, conn, instances, &serializers.encoding)? {
+            decoded.push(d);
+        }
+    }
+    if *mode == TransactionMode::BATCH {
+        // 每个事务在每个端口上只发送一条消息，key和分区取事务中第一条消息的
+        let mut batches: Vec<(QueueMessage, String, Vec<Vec<u8>>)> = Vec::new();
+        for d in decoded {
+            for port in d.ports.iter() {
+                // Avro二进制不能拼接成JSON数组，还是每行一条消息
+                if port.format == MessageFormat::AVRO {
+                    messages.extend(port_messages(&d, port, txn.seq_idx, serializers)?);
+                    continue;
+                }
+                let payload = d.payloads(port, &d.message, serializers)?;
+                if let Some((_, _, payloads)) = batches.iter_mut().find(|(msg, mq, _)| mq.eq(&port.mq) && msg.topic.eq(&port.topic)) {
+                    payloads.extend(payload);
+                }else{
+                    // 整个事务一条消息，key和分区取第一行，配置检查不允许按主键分区
+                    let (key, partition_hash, _) = d.message.split_by_key(port).remove(0);
+                    let msg = QueueMessage { topic: port.topic.clone(), payload: Vec::new(), seq: txn.seq_idx, key, partition_hash };
+                    batches.push((msg, port.mq.clone(), payload));
+                }
+            }
+        }
+        for (mut msg, mq_name, payloads) in batches {
+            msg.payload = TransactionBatch::to_json(txn.xid, txn.commit_ts, payloads).into_bytes();
+            messages.push((mq_name, msg));
+        }
+    }else{
+        for mut d in decoded {
+            d.message.xid = Some(txn.xid);
+            d.message.commitTs = Some(txn.commit_ts);
+            messages.extend(keyed_messages(&d, txn.seq_idx, serializers)?);
+        }
+    }
+    Ok(messages)
+}
+
+/// BATCH模式下一个事务的消息，messages 中是已经序列化的Canal或Debezium消息
+#[derive(Serialize)]
+struct TransactionBatch {
+    xid: u64,
+    commitTs: u64,
+    messages: Vec<Box<RawValue>>
+}
+
+impl TransactionBatch {
+    fn to_json(xid: u64, commit_ts: u64, payloads: Vec<Vec<u8>>) -> String {
+        let messages = payloads.into_iter()
+            .map(|payload| RawValue::from_string(String::from_utf8(payload).expect("JSON消息不是UTF-8")).expect("JSON消息无效"))
+            .collect();
+        serde_json::to_string(&TransactionBatch { xid, commitTs: commit_ts, messages }).expect("事务消息序列化失败")
+    }
+}
+
+/// 每个端口按消息key拆分后生成待发送的消息
+fn keyed_messages(d: &DecodedRows, seq: u64, serializers: &mut Serializers) -> Result<Vec<(String, QueueMessage)>, AvroError> {
+    let mut messages: Vec<(String, QueueMessage)> = Vec::new();
+    for port in d.ports.iter() {
+        messages.extend(port_messages(d, port, seq, serializers)?);
+    }
+    Ok(messages)
+}
+
+fn port_messages(d: &DecodedRows, port: &Port, seq: u64, serializers: &mut Serializers) -> Result<Vec<(String, QueueMessage)>, AvroError> {
+    let mut messages: Vec<(String, QueueMessage)> = Vec::new();
+    for (key, partition_hash, message) in d.message.split_by_key(port) {
+        for payload in d.payloads(port, &message, serializers)? {
+            messages.push((port.mq.clone(), QueueMessage { topic: port.topic.clone(), payload, seq, key: key.clone(), partition_hash }));
+        }
+    }
+    Ok(messages)
+}
+
+impl DecodedRows {
+    /// 按端口的消息格式序列化，Debezium和Avro每行一条消息。schema注册失败时返回错误，消息不能跳过
+    fn payloads(&self, port: &Port, message: &DmlMessage, serializers: &mut Serializers) -> Result<Vec<Vec<u8>>, AvroError> {
+        match port.format {
+            MessageFormat::CANAL => Ok(vec![message.to_json(&self.fields, serializers.number_as_string).into_bytes()]),
+            MessageFormat::DEBEZIUM => Ok(debezium::envelopes(message, &self.fields, &self.source, &port.server_name, port.schema, &serializers.encoding)
+                .into_iter().map(String::into_bytes).collect()),
+            MessageFormat::AVRO => match serializers.registry.as_mut() {
+                Some(registry) => avro::encode(message, &self.fields, &port.topic, registry),
+                None => Err(RegistryError::NotConfigured.into())
+            },
+        }
+    }
+}
+
+fn worker_body(thread_id: usize, rx: Receiver<WorkerEvent>, mapping: &mut TableMetaMapping, mut queue: MessageQueues, mut instances: Vec<Instance>, config: Config, tracker: Arc<Mutex<CommitTracker>>) {
     info!("[t:{thread_id}] Worker Started");
-    let mut table_map = TableMap::new();
+    let mut table_map = TableMap::with_temporal(TemporalFormat::from_config(&config));
+    let txn_mode = config.transaction_mode.clone().unwrap_or(TransactionMode::NONE);
+    let mut serializers = Serializers::from_config(&config);
 
-    let mut conn = MySQLConnection::get_connection(config.db_ip.as_str(), config.db_port as u32, config.max_packages as u32, config.user_name.clone(), config.passwd.clone());
+    let mut conn = match connect(&config, &tracker) {
+        Some(conn) => conn,
+        None => return
+    };
     let mut last_connect_ts = current_ts();
 
     loop {
-        if let Ok(data) = rx.recv() {
-            let mut ports: Vec<(String, String)> = Vec::new();
-            let tablemap = match TableMapEvent::decode(data.table_map.payload.as_slice()){
-                Ok((_, table))=>{ table },
-                Err(err)=> {
-                    error!("解码19包失败：{:?} \n data: {:?}", err, data.table_map.payload);
-                    panic!()
-                }
-            };
-
+        if let Ok(task) = rx.recv() {
             let c_ts = current_ts();
-            if c_ts - last_connect_ts > 3600 * 1000 {
+            if conn.is_broken() || c_ts - last_connect_ts > 3600 * 1000 {
                 conn.close();
-                conn = MySQLConnection::get_connection(config.db_ip.as_str(), config.db_port as u32, config.max_packages as u32, config.user_name.clone(), config.passwd.clone());
+                conn = match connect(&config, &tracker) {
+                    Some(conn) => conn,
+                    None => return
+                };
                 last_connect_ts = c_ts
             }
-
-            let tm = tablemap.clone();
-            table_map.decode_columns(tm.header.table_id, tm.column_types, tm.column_metas.as_bytes());
-            let mut current_data = DmlData::new_data(tablemap.header.table_id as u32, tablemap.schema_name.clone(), tablemap.table_name.clone());
-            for instance in instances.iter_mut(){
-                if let Some((mq_name, topic)) = instance.check_if_need_a_mq(current_data.database.clone(), current_data.table.clone()) {
-                    ports.push((mq_name, topic));
-                }
-            }
-            if ports.len() < 1 {
-                //println!("未匹配到实例：{}.{}", &current_data.database, &current_data.table);
-                continue;
-            }
-            if let Some(ev) = data.row_event {
-                let pos = ev.header.log_pos;
-                if vec![32u8, 31u8, 30u8].contains(&ev.header.event_type) {
-                    let tm = tablemap.clone();
-                    if let Ok(mut meta) = mapping.update_mapping(&mut conn,
-                                                                 current_data.table_id,
-                                                                 current_data.database.clone(),
-                                                                 current_data.table.clone(),
-                                                                 &table_map.metas[&tm.header.table_id]
-
-                    ) {
-                        if meta.len() == 0usize {
-                            error!("表{}.{} 不存在", current_data.database, current_data.table);
-                            continue
-                        }
-                        if ev.header.event_type == 30 {
-                            if let Ok((i, event)) = WriteRowEvent::decode(ev.payload.as_bytes()){
-                                match WriteRowEvent::decode_column_multirow_vals(&table_map, i, event.header.table_id, event.col_map_len){
-                                    Ok((_, rows))=>{
-                                        //warn!("insert rows:{}", rows.len());
-                                        current_data.append_data(data.seq_idx, "INSERT".to_string(), rows, Vec::new(), ev.header.log_pos);
-                                    },
-                                    Err(ex)=>{
-                                        error!("数据列解码错误:{ex:?}\n data{:?}", ev.payload.as_bytes());
-                                        panic!("{ex:?}")
-                                    }
-                                }
-
-                            }else{
-                                error!("插入包解码错误-data:{:?}", ev.payload.as_bytes());
-                                continue
-                            }
-                        }
-                        if ev.header.event_type == 31{
-                            if let Ok((i, event)) = UpdateRowEvent::decode(ev.payload.as_bytes()) {
-                                let (_, (old_val, new_val)) = match UpdateRowEvent::fetch_rows(i, table_map.clone(), event.header.table_id, event.col_map_len){
-                                    Ok((i, (old_values, new_values)))=> (i, (old_values, new_values)),
-                                    Err(err)=>{
-                                        error!("exec table:{} fail with: {err:?}", current_data.table);
-                                        panic!("{err:?}")
-                                    }
-                                };
-                                //warn!("update rows:{} {}", new_val.len(), old_val.len());
-                                current_data.append_data(data.seq_idx, "UPDATE".to_string(), new_val, old_val, ev.header.log_pos);
-                            }else{
-                                error!("更新包解码错误-data:{:?}", ev.payload.as_bytes());
-                                continue
-                            }
-                        }
-                        if ev.header.event_type == 32{
-                            if let Ok((i, event)) = DeleteRowEvent::decode(ev.payload.as_bytes()){
-                                let (_, old_values) = DeleteRowEvent::fetch_rows(i, table_map.clone(), event.header.table_id, event.col_map_len).expect("解码 Delete Val错误");
-                                //warn!("delete rows:{}", old_values.len());
-                                current_data.append_data(data.seq_idx, "DELETE".to_string(), Vec::new(), old_values, ev.header.log_pos);
-                            }else{
-                                error!("删除包解码错误-data:{:?}", ev.payload.as_bytes());
-                                continue
-                            }
-                        }
-                        let mut message = DmlMessage::from_dml(current_data, &mut meta);
-                        let json_str = message.format_json(&mut meta);
-                        //info!("will sent:\n{}", &json_str);
-                        if ports.len() > 0 {
-                            for (mq_name, topic) in ports {
-                                let msg_qu = QueueMessage { topic, payload: json_str.clone(), pos };
-                                queue.push(&mq_name, msg_qu);
-                            }
-                        }else{
-                            warn!("没有可用发送端口");
-                        }
-                    }else{
-                        //error!("解析meta失败，pass");
+            // 其它worker在处理独占事件，等它处理完再继续
+            let (task, barrier) = match task {
+                WorkerEvent::Barrier(barrier) => {
+                    barrier.wait();
+                    barrier.wait();
+                    continue;
+                },
+                WorkerEvent::Exclusive(task, barrier) => {
+                    barrier.wait();
+                    (*task, Some(barrier))
+                },
+                task => (task, None)
+            };
+            let seq = task.seq_idx();
+            let messages = match task {
+                WorkerEvent::Rows(data) => {
+                    match decode_row_events(data, &mut table_map, mapping, &mut conn, &mut instances, &serializers.encoding) {
+                        Ok(Some(d)) => keyed_messages(&d, seq, &mut serializers).map_err(WorkerError::from),
+                        Ok(None) => Ok(Vec::new()),
+                        Err(err) => Err(err)
                     }
+                },
+                WorkerEvent::Ddl(ddl) => {
+                    Ok(handle_ddl(ddl, mapping, &mut instances))
+                },
+                WorkerEvent::Transaction(txn) => {
+                    handle_transaction(txn, &txn_mode, &mut table_map, mapping, &mut conn, &mut instances, &mut serializers)
+                },
+                WorkerEvent::Exclusive(..) | WorkerEvent::Barrier(_) => Ok(Vec::new())
+            };
+            // 消息生成不了就不能继续，停止整个流程，位置停在这个事件之前
+            let messages = match messages {
+                Ok(messages) => messages,
+                Err(err) => {
+                    abort_pipeline(tracker.clone(), format!("[t:{thread_id}] {err}"));
+                    return;
                 }
+            };
+            // 先登记需要确认的消息数再发送，避免回调先于登记到达
+            expect_acks(tracker.clone(), seq, messages.len());
+            for (mq_name, msg_qu) in messages {
+                queue.push(&mq_name, msg_qu, &tracker);
+            }
+            if let Some(barrier) = barrier {
+                barrier.wait();
             }
         }
-        //println!("[t:{thread_id}]DML Data: {data:?}");
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binlog::{ColumnCharsets, EventHeader, EventHeaderFlag, RowEventHeader, TableMapMetadata};
+
+    fn ddl_event(seq_idx: u64) -> DdlEvent {
+        let (_, query) = EventRaw::decode(&[0u8; 20]).unwrap();
+        DdlEvent{ query, seq_idx }
+    }
+
+    #[test]
+    fn exclusive_event_is_a_barrier_for_other_workers() {
+        let mut pool = Pool{ tx_channel: HashMap::new() };
+        let mut receivers = Vec::new();
+        for i in 0..3u32 {
+            let (tx, rx) = channel::<WorkerEvent>();
+            pool.regist_tx(i, Arc::new(Mutex::new(tx)));
+            receivers.push(rx);
+        }
+        pool.push_exclusive(1, WorkerEvent::Ddl(ddl_event(7)));
+        let events: Vec<WorkerEvent> = receivers.iter().map(|rx| rx.try_recv().unwrap()).collect();
+        assert!(matches!(&events[0], WorkerEvent::Barrier(_)));
+        assert!(matches!(&events[2], WorkerEvent::Barrier(_)));
+        match &events[1] {
+            WorkerEvent::Exclusive(task, _) => assert_eq!(task.seq_idx(), 7),
+            ev => panic!("{ev:?}")
+        }
+        // 三个worker等的是同一个屏障
+        let barriers: Vec<Arc<Barrier>> = events.into_iter().map(|ev| match ev {
+            WorkerEvent::Exclusive(_, b) | WorkerEvent::Barrier(b) => b,
+            _ => unreachable!()
+        }).collect();
+        assert!(Arc::ptr_eq(&barriers[0], &barriers[1]) && Arc::ptr_eq(&barriers[1], &barriers[2]));
+    }
+
+    #[test]
+    fn transaction_batch_embeds_serialized_messages() {
+        let payloads = vec![br#"{"b":"1","a":[1,2]}"#.to_vec(), br#"{"x":null}"#.to_vec()];
+        assert_eq!(TransactionBatch::to_json(12, 1700000000000, payloads), r#"{"xid":12,"commitTs":1700000000000,"messages":[{"b":"1","a":[1,2]},{"x":null}]}"#);
+        assert_eq!(TransactionBatch::to_json(0, 0, Vec::new()), r#"{"xid":0,"commitTs":0,"messages":[]}"#);
+    }
+
+    #[test]
+    fn primary_key_change_across_workers_is_exclusive() {
+        let mut pool = Pool{ tx_channel: HashMap::new() };
+        let mut receivers = Vec::new();
+        for i in 0..2u32 {
+            let (tx, rx) = channel::<WorkerEvent>();
+            pool.regist_tx(i, Arc::new(Mutex::new(tx)));
+            receivers.push(rx);
+        }
+        // 哈希按worker数取模：偶数给worker 0，奇数给worker 1
+        let routes = group_rows(&pool, &[vec![2], vec![3], vec![4]]).unwrap();
+        assert_eq!(routes, vec![(0, Some(vec![0, 2])), (1, Some(vec![1]))]);
+        let routes = group_rows(&pool, &[vec![2], vec![4, 6]]).unwrap();
+        assert_eq!(routes, vec![(0, None)]);
+        // 主键从worker 0的值改成了worker 1的值
+        assert_eq!(group_rows(&pool, &[vec![2], vec![4, 5]]), None);
+    }
+
+    #[test]
+    fn murmur2_matches_kafka() {
+        // Kafka UtilsTest 中的向量
+        assert_eq!(murmur2("21".as_bytes()), -973932308);
+        assert_eq!(murmur2("foobar".as_bytes()), -790332482);
+        assert_eq!(murmur2("a-little-bit-long-string".as_bytes()), -985981536);
+        assert_eq!(murmur2("a-little-bit-longer-string".as_bytes()), -1486304829);
+        assert_eq!(murmur2("lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8".as_bytes()), -58897971);
+        assert_eq!(hash_key(&"foobar".to_string()), (-790332482i32 & 0x7fffffff) as u64);
+    }
+
+    /// testdata/canal 中all_types表的TABLE_MAP，binlog_row_metadata=FULL
+    fn golden_table_map() -> EventRaw {
+        let names = ["id", "c_tinyint", "c_bigint", "c_decimal", "c_double", "c_varchar", "c_char", "c_text", "c_blob", "c_date",
+            "c_datetime", "c_time", "c_time3", "c_timestamp", "c_year", "c_json", "c_enum", "c_set", "c_bit", "c_null"];
+        let types = [3u8, 1, 8, 246, 5, 15, 254, 252, 252, 10, 18, 19, 19, 17, 13, 245, 254, 254, 16, 15];
+        let metas = [10u8, 2, 8, 128, 0, 254, 16, 2, 2, 3, 0, 3, 0, 4, 247, 1, 248, 1, 0, 1, 32, 0];
+        let metadata = [
+            // 数字列中只有c_bigint是unsigned
+            metadata_item(1, vec![0b0010_0000]),
+            metadata_item(3, vec![45, 45, 45, 63, 45]),
+            metadata_item(5, vec![2, 1, b'x', 1, b'y']),
+            metadata_item(6, vec![2, 1, b'a', 1, b'b']),
+            metadata_item(8, vec![0]),
+            metadata_item(10, vec![45]),
+        ].concat();
+        table_map_event(9, "test", "all_types", &names, &types, &metas, metadata)
+    }
+
+    /// all_types表一行的binlog数据，c_null为NULL
+    fn golden_row(varchar: &str, json_a: u8) -> Vec<u8> {
+        let mut row = vec![0x00, 0x00, 0x08];
+        row.extend_from_slice(&[0x01, 0x00, 0x00, 0x00]);
+        row.push(0x80);
+        row.extend_from_slice(&[0xff; 8]);
+        // decimal(10,2) 12.50
+        row.extend_from_slice(&[0x80, 0x00, 0x00, 0x0c, 0x32]);
+        row.extend_from_slice(&1.5f64.to_le_bytes());
+        row.push(varchar.len() as u8);
+        row.extend_from_slice(varchar.as_bytes());
+        // CHAR写入binlog时去掉了末尾的空格
+        row.extend_from_slice(&[2, b'a', b'b']);
+        row.extend_from_slice(&[4, 0, b't', b'e', b'x', b't']);
+        row.extend_from_slice(&[3, 0, b'a', b'b', b'c']);
+        // 2024-01-02
+        row.extend_from_slice(&[0x22, 0xd0, 0x0f]);
+        // 2024-01-02 03:04:05.678
+        row.extend_from_slice(&[0x99, 0xb2, 0x44, 0x31, 0x05, 0x1a, 0x7c]);
+        // 12:34:56 和 12:34:56.789
+        row.extend_from_slice(&[0x80, 0xc8, 0xb8]);
+        row.extend_from_slice(&[0x80, 0xc8, 0xb8, 0x1e, 0xd2]);
+        // 1704164645 = 2024-01-02 03:04:05 UTC
+        row.extend_from_slice(&[0x65, 0x93, 0x7d, 0x25]);
+        row.push(124);
+        // {"a": json_a, "b": [true, null]}
+        let json = [
+            0x00, 0x02, 0x00, 0x1e, 0x00, 0x12, 0x00, 0x01, 0x00, 0x13, 0x00, 0x01, 0x00, 0x05, json_a, 0x00, 0x02, 0x14, 0x00, b'a', b'b',
+            0x02, 0x00, 0x0a, 0x00, 0x04, 0x01, 0x00, 0x04, 0x00, 0x00,
+        ];
+        row.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        row.extend_from_slice(&json);
+        // enum的序号、set的位掩码、bit(8)
+        row.extend_from_slice(&[2, 3, 5]);
+        row
+    }
+
+    /// 把TABLE_MAP和行事件解码后和Canal的FlatMessage对比，testdata/canal 中是同一张表在Canal中的输出
+    fn assert_golden(event_type: u8, seq: u64, images: usize, rows: Vec<u8>, sql: Option<&str>, golden: &str) {
+        let mut config = Config::gen_default();
+        config.instances[0].tables = "all_types".to_string();
+        config.binary_encoding = Some(BinaryEncoding::UTF8_LOSSY);
+        let mut data = RowEvents::new(golden_table_map(), "binlog.000003".to_string(), None, sql.map(|s| s.to_string()));
+        data.append(rows_event(event_type, 9, 1234, 20, images, rows), seq);
+        let mut table_map = TableMap::with_temporal(TemporalFormat::from_config(&config));
+        let decoded = decode_row_events(data, &mut table_map, &mut TableMetaMapping::new(), &mut idle_connection(),
+            &mut config.instances, &BinaryEncoding::UTF8_LOSSY).unwrap().unwrap();
+        let mut message = decoded.message;
+        // ts为Canal处理消息的时间，不在binlog中
+        message.ts = 1704164645123;
+        let actual: Value = serde_json::from_str(&message.to_json(&decoded.fields, true)).unwrap();
+        let expected: Value = serde_json::from_str(golden).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn canal_golden_insert() {
+        assert_golden(30, 3, 1, golden_row("hello", 1), None, include_str!("../testdata/canal/insert.json"));
+    }
+
+    #[test]
+    fn canal_golden_update() {
+        let rows = [golden_row("hello", 1), golden_row("world", 2)].concat();
+        let sql = "update all_types set c_varchar='world', c_json=json_set(c_json, '$.a', 2) where id=1";
+        assert_golden(31, 4, 2, rows, Some(sql), include_str!("../testdata/canal/update.json"));
+    }
+
+    #[test]
+    fn canal_golden_delete() {
+        assert_golden(32, 5, 1, golden_row("hello", 1), None, include_str!("../testdata/canal/delete.json"));
+    }
+
+    #[test]
+    fn table_map_types_keep_length_and_precision() {
+        // id int, name varchar(32) utf8mb4, code char(4) gbk, flags bit(10), amount decimal(10,2) unsigned, t time(3), bin varbinary(16)
+        let column_types = vec![3u8, 15, 254, 16, 246, 19, 15];
+        let column_metas = vec![128u8, 0, 254, 8, 2, 1, 10, 2, 3, 16, 0];
+        let metadata = TableMapMetadata{
+            column_names: ["id", "name", "code", "flags", "amount", "t", "bin"].iter().map(|n| n.to_string()).collect(),
+            unsigned: vec![false, true],
+            charsets: ColumnCharsets{ columns: vec![255, 28, 63], ..Default::default() },
+            primary_key: vec![0],
+            ..Default::default()
+        };
+        let tm = TableMapEvent{ header: RowEventHeader{ table_id: 1, flag: 0 }, schema_name: "test".to_string(), table_name: "t".to_string(),
+            column_count: column_types.len(), column_types: column_types.clone(), column_metas: column_metas.clone(), metadata };
+        let mut table_map = TableMap::new();
+        table_map.decode_columns(1, column_types, &column_metas);
+        let fields = FieldMeta::from_table_map(&tm, &table_map.mapping[&1], &table_map.metas[&1]).unwrap();
+        let types: Vec<&str> = fields.iter().map(|f| f.field_type.as_str()).collect();
+        assert_eq!(types, vec!["int", "varchar(32)", "char(4)", "bit(10)", "decimal(10,2) unsigned", "time(3)", "varbinary(16)"]);
+        assert_eq!(fields[2].charset.as_deref(), Some("gbk"));
+    }
+
+    /// TABLE_MAP可选元数据中的一项：类型 + 长度 + 内容
+    fn metadata_item(tp: u8, body: Vec<u8>) -> Vec<u8> {
+        let mut item = vec![tp, body.len() as u8];
+        item.extend(body);
+        item
+    }
+
+    fn packed_strings(values: &[&[u8]]) -> Vec<u8> {
+        values.iter().flat_map(|v| [vec![v.len() as u8], v.to_vec()].concat()).collect()
+    }
+
+    fn raw_event(event_type: u8, log_pos: u32, payload: Vec<u8>) -> EventRaw {
+        let header = EventHeader{ timestamp: 1704164645, event_type, server_id: 1, event_size: 19 + payload.len() as u32, log_pos, flags: EventHeaderFlag::empty() };
+        EventRaw{ header, payload }
+    }
+
+    /// binlog_row_metadata=FULL 的TABLE_MAP，metadata为列名之外的其它元数据
+    fn table_map_event(table_id: u8, db: &str, table: &str, names: &[&str], types: &[u8], metas: &[u8], metadata: Vec<u8>) -> EventRaw {
+        let mut payload = vec![table_id, 0, 0, 0, 0, 0, 1, 0];
+        for name in [db, table] {
+            payload.push(name.len() as u8);
+            payload.extend_from_slice(name.as_bytes());
+            payload.push(0);
+        }
+        payload.push(types.len() as u8);
+        payload.extend_from_slice(types);
+        payload.push(metas.len() as u8);
+        payload.extend_from_slice(metas);
+        payload.extend(vec![0xffu8; types.len().div_ceil(8)]);
+        let names: Vec<&[u8]> = names.iter().map(|n| n.as_bytes()).collect();
+        payload.extend(metadata_item(4, packed_strings(&names)));
+        payload.extend(metadata);
+        raw_event(19, 100, payload)
+    }
+
+    /// v2行事件，rows为按列解码的行数据(包含每行的null位图)
+    fn rows_event(event_type: u8, table_id: u8, log_pos: u32, column_count: u8, images: usize, rows: Vec<u8>) -> EventRaw {
+        let mut payload = vec![table_id, 0, 0, 0, 0, 0, 1, 0, 2, 0, column_count];
+        let bitmap = vec![0xffu8 >> (8 - column_count.min(8)); (column_count as usize).div_ceil(8)];
+        for _ in 0..images {
+            payload.extend(bitmap.clone());
+        }
+        payload.extend(rows);
+        raw_event(event_type, log_pos, payload)
+    }
+
+    /// 列信息都来自TABLE_MAP，不会查询表结构，只需要一个连接对象
+    fn idle_connection() -> MySQLConnection {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        MySQLConnection::from_tcp(tcp)
+    }
+
+    fn decode_rows(table_map: EventRaw, row_event: EventRaw, config: &Config) -> Result<Option<DecodedRows>, WorkerError> {
+        let mut data = RowEvents::new(table_map, "binlog.000003".to_string(), None, None);
+        data.append(row_event, 3);
+        let mut instances = config.instances.clone();
+        let mut table_map = TableMap::with_temporal(TemporalFormat::from_config(config));
+        let encoding = config.binary_encoding.clone().unwrap_or(BinaryEncoding::BASE64);
+        decode_row_events(data, &mut table_map, &mut TableMetaMapping::new(), &mut idle_connection(), &mut instances, &encoding)
+    }
+
+    #[test]
+    fn truncated_row_is_a_decode_error() {
+        let config = Config::gen_default();
+        let table_map = table_map_event(9, "test", "s1", &["id"], &[3], &[], metadata_item(8, vec![0]));
+        // INT需要4字节，只有2字节
+        let row_event = rows_event(30, 9, 1234, 1, 1, vec![0x00, 0x01, 0x00]);
+        match decode_rows(table_map, row_event, &config) {
+            Err(err @ WorkerError::Decode { .. }) => {
+                let text = err.to_string();
+                assert!(text.contains("test.s1") && text.contains("binlog.000003:1234"), "{text}");
+            },
+            Err(err) => panic!("{err}"),
+            Ok(_) => panic!("截断的行不能解码成功")
+        }
+        // 不在实例中的表不需要解码
+        let table_map = table_map_event(9, "other", "s1", &["id"], &[3], &[], Vec::new());
+        let row_event = rows_event(30, 9, 1234, 1, 1, vec![0x00, 0x01, 0x00]);
+        assert!(matches!(decode_rows(table_map, row_event, &config), Ok(None)));
+    }
+
+    #[test]
+    fn composite_primary_key_keeps_column_order() {
+        let mut fields = vec![
+            FieldMeta{ name: "a".to_string(), field_type: "int".to_string(), is_pk: true, charset: None },
+            FieldMeta{ name: "v".to_string(), field_type: "varchar(8)".to_string(), is_pk: false, charset: None },
+            FieldMeta{ name: "b".to_string(), field_type: "int".to_string(), is_pk: true, charset: None },
+        ];
+        let mut dml = DmlData::new_data(1, "test".to_string(), "t".to_string());
+        let row = vec![Some(ColumnValue::Value(Value::from(1))), Some(ColumnValue::Bytes(b"x".to_vec())), Some(ColumnValue::Value(Value::from(2)))];
+        dml.append_data(3, "INSERT".to_string(), vec![row], Vec::new(), 4);
+        let message = DmlMessage::from_dml(dml, &mut fields, &BinaryEncoding::BASE64);
+        assert_eq!(message.pkNames, Some(vec!["a".to_string(), "b".to_string()]));
+        let mut port = Config::gen_default().instances[0].check_if_need_a_mq("test".to_string(), "s1".to_string()).unwrap();
+        let (db, table) = (message.database.clone(), message.table.clone());
+        assert_eq!(port.message_key(&db, &table, &message.pkNames, &message.data[0]), Some("1,2".to_string()));
+        port.key = Some("${db}.${table}:${pk}".to_string());
+        assert_eq!(port.message_key(&db, &table, &message.pkNames, &message.data[0]), Some("test.t:1,2".to_string()));
+    }
+
+    #[test]
+    fn char_columns_are_transcoded_with_table_map_charsets() {
+        let config = Config::gen_default();
+        // id int, c char(4) gbk, v varchar(10) latin1, u char(2) utf16
+        let charsets = metadata_item(3, vec![28, 8, 54]);
+        let pk = metadata_item(8, vec![0]);
+        let table_map = table_map_event(9, "test", "s2", &["id", "c", "v", "u"], &[3, 254, 15, 254], &[254, 8, 10, 0, 254, 8], [charsets, pk].concat());
+        let mut row = vec![0x00, 0x01, 0x00, 0x00, 0x00];
+        // CHAR末尾的填充空格，utf16的空格是0x0020
+        row.extend_from_slice(&[4, 0xd6, 0xd0, 0x20, 0x20]);
+        row.extend_from_slice(&[4, b'c', b'a', b'f', 0xe9]);
+        row.extend_from_slice(&[4, 0x4e, 0x2d, 0x00, 0x20]);
+        let decoded = decode_rows(table_map, rows_event(30, 9, 1234, 4, 1, row), &config).unwrap().unwrap();
+        let data = &decoded.message.data[0];
+        assert_eq!(data["c"], Value::from("中"));
+        assert_eq!(data["v"], Value::from("café"));
+        assert_eq!(data["u"], Value::from("中"));
+        assert_eq!(decoded.message.mysqlType["c"], "char(4)");
+        assert_eq!(decoded.message.mysqlType["v"], "varchar(10)");
+        assert_eq!(decoded.message.mysqlType["u"], "char(2)");
+    }
+
+    #[test]
+    fn table_meta_cache_is_checked_by_name() {
+        let mut mapping = TableMetaMapping::new();
+        let cols = vec![FieldMeta{ name: "id".to_string(), field_type: "int".to_string(), is_pk: true, charset: None }];
+        mapping.mapping.lock().unwrap().insert(7, ("test.t1".to_string(), cols));
+        assert_eq!(mapping.cached(7, &"test.t1".to_string()).map(|cols| cols.len()), Some(1));
+        // 重连之后同一个table_id可能是别的表
+        assert!(mapping.cached(7, &"test.t2".to_string()).is_none());
+        mapping.invalidate(&"test".to_string(), &"t1".to_string());
+        assert!(mapping.cached(7, &"test.t1".to_string()).is_none());
+    }
+
+    #[test]
+    fn binary_encodings_round_trip_non_utf8_bytes() {
+        let bytes = vec![0x00u8, 0xff, 0xfe, 0x80, b'a', 0xc3, 0x28, 0x00];
+        let blob = FieldMeta{ name: "b".to_string(), field_type: "blob".to_string(), is_pk: false, charset: None };
+        // 经过JSON序列化再解析，和消费者看到的一致
+        let through_json = |encoding: &BinaryEncoding| -> String {
+            let val = blob.to_json_value(&ColumnValue::Bytes(bytes.clone()), encoding);
+            let parsed: Value = serde_json::from_str(&serde_json::to_string(&val).unwrap()).unwrap();
+            parsed.as_str().unwrap().to_string()
+        };
+        assert_eq!(STANDARD.decode(through_json(&BinaryEncoding::BASE64)).unwrap(), bytes);
+        assert_eq!(hex::decode(through_json(&BinaryEncoding::HEX)).unwrap(), bytes);
+        // 非法的UTF-8字节替换为U+FFFD，0x00和合法的字符保留
+        assert_eq!(through_json(&BinaryEncoding::UTF8_LOSSY), "\u{0}\u{FFFD}\u{FFFD}\u{FFFD}a\u{FFFD}(\u{0}");
+    }
+}
@@ -0,0 +1,443 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use nom::error::{Error, ErrorKind};
+use nom::{IResult, Err as NomErr};
+use serde_json::{Map, Value};
+use crate::binlog::DecimalVal;
+use crate::mysql::{Decoder, take_bytes, take_i_int4, take_i_int8, take_int1, take_int2, take_int4, take_int8, take_int_n};
+use crate::protocal::VLenInt;
+
+/// MySQL JSON 二进制格式
+/// https://dev.mysql.com/doc/dev/mysql-server/latest/json__binary_8h.html
+const JSONB_TYPE_SMALL_OBJECT: u8 = 0x00;
+const JSONB_TYPE_LARGE_OBJECT: u8 = 0x01;
+const JSONB_TYPE_SMALL_ARRAY: u8 = 0x02;
+const JSONB_TYPE_LARGE_ARRAY: u8 = 0x03;
+const JSONB_TYPE_LITERAL: u8 = 0x04;
+const JSONB_TYPE_INT16: u8 = 0x05;
+const JSONB_TYPE_UINT16: u8 = 0x06;
+const JSONB_TYPE_INT32: u8 = 0x07;
+const JSONB_TYPE_UINT32: u8 = 0x08;
+const JSONB_TYPE_INT64: u8 = 0x09;
+const JSONB_TYPE_UINT64: u8 = 0x0a;
+const JSONB_TYPE_DOUBLE: u8 = 0x0b;
+const JSONB_TYPE_STRING: u8 = 0x0c;
+const JSONB_TYPE_OPAQUE: u8 = 0x0f;
+
+const JSONB_NULL_LITERAL: u8 = 0x00;
+const JSONB_TRUE_LITERAL: u8 = 0x01;
+const JSONB_FALSE_LITERAL: u8 = 0x02;
+
+/// PARTIAL_UPDATE_ROWS_EVENT 中 JSON 列的修改操作
+const JSON_DIFF_REPLACE: u8 = 0;
+const JSON_DIFF_INSERT: u8 = 1;
+const JSON_DIFF_REMOVE: u8 = 2;
+
+fn fail(input: &[u8]) -> NomErr<Error<&[u8]>> {
+    NomErr::Error(Error::new(input, ErrorKind::Verify))
+}
+
+/// 解码一个完整的 JSON 文档，空文档视为 null
+pub fn decode_json_binary(input: &[u8]) -> IResult<&[u8], Value> {
+    if input.is_empty() {
+        return Ok((input, Value::Null));
+    }
+    let (i, tp) = take_int1(input)?;
+    let (_, val) = decode_value(tp, i)?;
+    Ok((&[], val))
+}
+
+/// 解码部分更新的 JSON 列，输出为操作列表: [{"op":"REPLACE","path":"$.a","value":1}]
+pub fn decode_json_diff(input: &[u8]) -> IResult<&[u8], Value> {
+    let mut diffs: Vec<Value> = Vec::new();
+    let mut i = input;
+    while !i.is_empty() {
+        let (ni, operation) = take_int1(i)?;
+        let op = match operation {
+            JSON_DIFF_REPLACE => "REPLACE",
+            JSON_DIFF_INSERT => "INSERT",
+            JSON_DIFF_REMOVE => "REMOVE",
+            _ => return Err(fail(i))
+        };
+        let (ni, path_len) = VLenInt::decode(ni)?;
+        let (ni, path) = take_bytes(ni, path_len.int() as usize)?;
+        let mut diff = Map::new();
+        diff.insert("op".to_string(), Value::from(op));
+        diff.insert("path".to_string(), Value::from(String::from_utf8_lossy(path).to_string()));
+        i = ni;
+        if operation != JSON_DIFF_REMOVE {
+            let (ni, value_len) = VLenInt::decode(i)?;
+            let (ni, value) = take_bytes(ni, value_len.int() as usize)?;
+            let (_, value) = decode_json_binary(value)?;
+            diff.insert("value".to_string(), value);
+            i = ni;
+        }
+        diffs.push(Value::Object(diff));
+    }
+    Ok((i, Value::Array(diffs)))
+}
+
+/// JSON路径中的一段，$.a[1] 为 [Key("a"), Index(1)]
+enum PathLeg {
+    Key(String),
+    Index(usize),
+    Last,
+}
+
+/// 解析修改操作中的路径，只支持部分更新会产生的成员和下标
+fn parse_path(path: &str) -> Option<Vec<PathLeg>> {
+    let mut chars = path.strip_prefix('$')?.chars().peekable();
+    let mut legs = Vec::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '.' => {
+                let mut key = String::new();
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    loop {
+                        match chars.next()? {
+                            '\\' => key.push(chars.next()?),
+                            '"' => break,
+                            c => key.push(c)
+                        }
+                    }
+                } else {
+                    while let Some(c) = chars.peek() {
+                        if *c == '.' || *c == '[' {
+                            break;
+                        }
+                        key.push(*c);
+                        chars.next();
+                    }
+                }
+                legs.push(PathLeg::Key(key));
+            },
+            '[' => {
+                let mut idx = String::new();
+                loop {
+                    match chars.next()? {
+                        ']' => break,
+                        c => idx.push(c)
+                    }
+                }
+                let idx = idx.trim();
+                legs.push(if idx == "last" { PathLeg::Last } else { PathLeg::Index(idx.parse().ok()?) });
+            },
+            c if c.is_whitespace() => continue,
+            _ => return None
+        }
+    }
+    Some(legs)
+}
+
+/// 把部分更新的修改操作应用到前镜像上，得到完整的后镜像。路径不存在等无法应用的情况返回None
+pub fn apply_json_diff(mut doc: Value, diffs: &Value) -> Option<Value> {
+    for diff in diffs.as_array()? {
+        let op = diff["op"].as_str()?;
+        let mut legs = parse_path(diff["path"].as_str()?)?;
+        let value = diff.get("value").cloned().unwrap_or(Value::Null);
+        let last = match legs.pop() {
+            Some(last) => last,
+            // 路径为$时替换整个文档
+            None if op == "REPLACE" => {
+                doc = value;
+                continue;
+            },
+            None => return None
+        };
+        let mut parent = &mut doc;
+        for leg in legs.iter() {
+            parent = match (leg, parent) {
+                (PathLeg::Key(key), Value::Object(obj)) => obj.get_mut(key)?,
+                (PathLeg::Index(idx), Value::Array(arr)) => arr.get_mut(*idx)?,
+                (PathLeg::Last, Value::Array(arr)) => arr.last_mut()?,
+                _ => return None
+            };
+        }
+        match (op, last, parent) {
+            ("REPLACE", PathLeg::Key(key), Value::Object(obj)) => *obj.get_mut(&key)? = value,
+            ("REPLACE", PathLeg::Index(idx), Value::Array(arr)) => *arr.get_mut(idx)? = value,
+            ("REPLACE", PathLeg::Last, Value::Array(arr)) => *arr.last_mut()? = value,
+            ("INSERT", PathLeg::Key(key), Value::Object(obj)) => { obj.insert(key, value); },
+            // 超出数组长度的下标追加到末尾
+            ("INSERT", PathLeg::Index(idx), Value::Array(arr)) => arr.insert(idx.min(arr.len()), value),
+            ("INSERT", PathLeg::Last, Value::Array(arr)) => arr.push(value),
+            ("REMOVE", PathLeg::Key(key), Value::Object(obj)) => { obj.remove(&key)?; },
+            ("REMOVE", PathLeg::Index(idx), Value::Array(arr)) if idx < arr.len() => { arr.remove(idx); },
+            ("REMOVE", PathLeg::Last, Value::Array(arr)) => { arr.pop()?; },
+            _ => return None
+        }
+    }
+    Some(doc)
+}
+
+fn decode_value(tp: u8, data: &[u8]) -> IResult<&[u8], Value> {
+    match tp {
+        JSONB_TYPE_SMALL_OBJECT => decode_container(data, false, true),
+        JSONB_TYPE_LARGE_OBJECT => decode_container(data, true, true),
+        JSONB_TYPE_SMALL_ARRAY => decode_container(data, false, false),
+        JSONB_TYPE_LARGE_ARRAY => decode_container(data, true, false),
+        JSONB_TYPE_LITERAL => {
+            let (i, literal) = take_int1(data)?;
+            match literal {
+                JSONB_NULL_LITERAL => Ok((i, Value::Null)),
+                JSONB_TRUE_LITERAL => Ok((i, Value::Bool(true))),
+                JSONB_FALSE_LITERAL => Ok((i, Value::Bool(false))),
+                _ => Err(fail(data))
+            }
+        },
+        JSONB_TYPE_INT16 => {
+            let (i, val) = take_int2(data)?;
+            Ok((i, Value::from(val as i16)))
+        },
+        JSONB_TYPE_UINT16 => {
+            let (i, val) = take_int2(data)?;
+            Ok((i, Value::from(val)))
+        },
+        JSONB_TYPE_INT32 => {
+            let (i, val) = take_i_int4(data)?;
+            Ok((i, Value::from(val)))
+        },
+        JSONB_TYPE_UINT32 => {
+            let (i, val) = take_int4(data)?;
+            Ok((i, Value::from(val)))
+        },
+        JSONB_TYPE_INT64 => {
+            let (i, val) = take_i_int8(data)?;
+            Ok((i, Value::from(val)))
+        },
+        JSONB_TYPE_UINT64 => {
+            let (i, val) = take_int8(data)?;
+            Ok((i, Value::from(val)))
+        },
+        JSONB_TYPE_DOUBLE => {
+            let (i, val) = take_int8(data)?;
+            Ok((i, Value::from(f64::from_bits(val))))
+        },
+        JSONB_TYPE_STRING => {
+            let (i, len) = take_variable_length(data)?;
+            let (i, bs) = take_bytes(i, len)?;
+            Ok((i, Value::from(String::from_utf8_lossy(bs).to_string())))
+        },
+        JSONB_TYPE_OPAQUE => {
+            let (i, field_type) = take_int1(data)?;
+            let (i, len) = take_variable_length(i)?;
+            let (i, bs) = take_bytes(i, len)?;
+            Ok((i, decode_opaque(field_type, bs)))
+        },
+        _ => Err(fail(data))
+    }
+}
+
+/// 对象和数组，所有偏移量都是相对于容器开头(类型字节之后)计算的
+fn decode_container(data: &[u8], large: bool, is_object: bool) -> IResult<&[u8], Value> {
+    let offset_size = if large { 4usize } else { 2usize };
+    let (i, count) = take_int_n(data, offset_size)?;
+    let (mut i, _size) = take_int_n(i, offset_size)?;
+    let mut keys: Vec<String> = Vec::new();
+    if is_object {
+        for _ in 0..count {
+            let (ni, key_offset) = take_int_n(i, offset_size)?;
+            let (ni, key_len) = take_int2(ni)?;
+            i = ni;
+            let key_data = data.get(key_offset as usize..).ok_or_else(|| fail(data))?;
+            let (_, key) = take_bytes(key_data, key_len as usize)?;
+            keys.push(String::from_utf8_lossy(key).to_string());
+        }
+    }
+    let mut values: Vec<Value> = Vec::new();
+    for _ in 0..count {
+        let (ni, tp) = take_int1(i)?;
+        let (ni, entry) = take_bytes(ni, offset_size)?;
+        i = ni;
+        let (_, val) = if is_inlined(tp, large) {
+            decode_value(tp, entry)?
+        } else {
+            let (_, offset) = take_int_n(entry, offset_size)?;
+            let value_data = data.get(offset as usize..).ok_or_else(|| fail(data))?;
+            decode_value(tp, value_data)?
+        };
+        values.push(val);
+    }
+    if is_object {
+        let mut obj = Map::new();
+        for (key, val) in keys.into_iter().zip(values) {
+            obj.insert(key, val);
+        }
+        Ok((&[], Value::Object(obj)))
+    } else {
+        Ok((&[], Value::Array(values)))
+    }
+}
+
+fn is_inlined(tp: u8, large: bool) -> bool {
+    match tp {
+        JSONB_TYPE_LITERAL | JSONB_TYPE_INT16 | JSONB_TYPE_UINT16 => true,
+        JSONB_TYPE_INT32 | JSONB_TYPE_UINT32 => large,
+        _ => false
+    }
+}
+
+/// 变长整数，每个字节低7位为数据，最高位表示后面还有字节
+fn take_variable_length(input: &[u8]) -> IResult<&[u8], usize> {
+    let mut len = 0usize;
+    let mut i = input;
+    for idx in 0..5 {
+        let (ni, b) = take_int1(i)?;
+        i = ni;
+        len |= ((b & 0x7f) as usize) << (7 * idx);
+        if b & 0x80 == 0 {
+            return Ok((i, len));
+        }
+    }
+    Err(fail(input))
+}
+
+/// 非JSON原生类型(DECIMAL、日期时间等)，日期时间为8字节的packed整数。
+/// serde_json开启了arbitrary_precision，DECIMAL按原文保存为数字，不经过f64
+fn decode_opaque(field_type: u8, data: &[u8]) -> Value {
+    match field_type {
+        246 if data.len() > 2 => {
+            match DecimalVal::decode(&data[2..], data[0], data[1]) {
+                Ok((_, dec)) => serde_json::from_str::<Value>(dec.val.as_str()).unwrap_or(Value::from(dec.val)),
+                Err(_) => Value::Null
+            }
+        },
+        7 | 10 | 11 | 12 if data.len() >= 8 => {
+            let mut bs = [0u8; 8];
+            bs.copy_from_slice(&data[..8]);
+            let packed = i64::from_le_bytes(bs);
+            Value::from(format_packed_temporal(field_type, packed))
+        },
+        _ => Value::from(format!("base64:type{field_type}:{}", STANDARD.encode(data)))
+    }
+}
+
+fn format_packed_temporal(field_type: u8, packed: i64) -> String {
+    let sign = if packed < 0 { "-" } else { "" };
+    let packed = packed.unsigned_abs();
+    let int_part = packed >> 24;
+    let frac = packed % (1 << 24);
+    let frac_str = if frac > 0 { format!(".{frac:06}") } else { "".to_string() };
+    if field_type == 11 {
+        let hour = (int_part >> 12) % (1 << 10);
+        let minute = (int_part >> 6) % (1 << 6);
+        let second = int_part % (1 << 6);
+        return format!("{sign}{hour:02}:{minute:02}:{second:02}{frac_str}");
+    }
+    let ymd = int_part >> 17;
+    let ym = ymd >> 5;
+    let hms = int_part % (1 << 17);
+    let year = ym / 13;
+    let month = ym % 13;
+    let day = ymd % (1 << 5);
+    if field_type == 10 {
+        return format!("{year:04}-{month:02}-{day:02}");
+    }
+    let hour = hms >> 12;
+    let minute = (hms >> 6) % (1 << 6);
+    let second = hms % (1 << 6);
+    format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}{frac_str}")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn decode(bs: &[u8]) -> Value {
+        decode_json_binary(bs).unwrap().1
+    }
+
+    #[test]
+    fn small_object_with_inlined_int16() {
+        // JSON_OBJECT('a', 1, 'b', 'x')
+        let bs = [0x00, 0x02, 0x00, 0x16, 0x00, 0x12, 0x00, 0x01, 0x00, 0x13, 0x00, 0x01, 0x00,
+            0x05, 0x01, 0x00, 0x0c, 0x14, 0x00, b'a', b'b', 0x01, b'x'];
+        assert_eq!(decode(&bs), json!({"a": 1, "b": "x"}));
+    }
+
+    #[test]
+    fn small_array_literals_and_integers() {
+        // JSON_ARRAY(true, null, false, -2, 70000, 4294967295)，小数组中int32/uint32不内联
+        let bs = [0x02, 0x06, 0x00, 0x1e, 0x00,
+            0x04, 0x01, 0x00, 0x04, 0x00, 0x00, 0x04, 0x02, 0x00, 0x05, 0xfe, 0xff, 0x07, 0x16, 0x00, 0x08, 0x1a, 0x00,
+            0x70, 0x11, 0x01, 0x00, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(decode(&bs), json!([true, null, false, -2, 70000, 4294967295u64]));
+
+        // JSON_ARRAY(65535, -1(int64), 18446744073709551615, 1.5)
+        let mut bs = vec![0x02, 0x04, 0x00, 0x28, 0x00, 0x06, 0xff, 0xff, 0x09, 0x10, 0x00, 0x0a, 0x18, 0x00, 0x0b, 0x20, 0x00];
+        bs.extend_from_slice(&(-1i64).to_le_bytes());
+        bs.extend_from_slice(&u64::MAX.to_le_bytes());
+        bs.extend_from_slice(&1.5f64.to_le_bytes());
+        assert_eq!(decode(&bs), json!([65535, -1, u64::MAX, 1.5]));
+    }
+
+    #[test]
+    fn large_containers_inline_int32_and_nest() {
+        // 大对象 {"k": 70000}，int32内联在4字节的值里
+        let bs = [0x01, 0x01, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x13, 0x00, 0x00, 0x00, 0x01, 0x00,
+            0x07, 0x70, 0x11, 0x01, 0x00, b'k'];
+        assert_eq!(decode(&bs), json!({"k": 70000}));
+
+        // 大数组 [[1], "s"]，嵌套的小数组偏移相对于自己的开头
+        let bs = [0x03, 0x02, 0x00, 0x00, 0x00, 0x1b, 0x00, 0x00, 0x00,
+            0x02, 0x12, 0x00, 0x00, 0x00, 0x0c, 0x19, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x07, 0x00, 0x05, 0x01, 0x00,
+            0x01, b's'];
+        assert_eq!(decode(&bs), json!([[1], "s"]));
+    }
+
+    #[test]
+    fn opaque_decimal_keeps_exact_digits() {
+        // CAST(12.50 AS DECIMAL(4,2))：精度、小数位，然后是DECIMAL的二进制格式
+        let bs = [0x02, 0x01, 0x00, 0x0d, 0x00, 0x0f, 0x07, 0x00, 0xf6, 0x04, 0x04, 0x02, 0x8c, 0x32];
+        assert_eq!(serde_json::to_string(&decode(&bs)).unwrap(), "[12.50]");
+
+        // DECIMAL(21,2) 1234567890123456789.12 超出f64的精度
+        let dec = [21u8, 2, 0x81, 0x0d, 0xfb, 0x38, 0xd2, 0x07, 0x5b, 0xcd, 0x15, 0x0c];
+        let mut bs = vec![0x02, 0x01, 0x00, 7 + 2 + dec.len() as u8, 0x00, 0x0f, 0x07, 0x00, 0xf6, dec.len() as u8];
+        bs.extend_from_slice(&dec);
+        assert_eq!(serde_json::to_string(&decode(&bs)).unwrap(), "[1234567890123456789.12]");
+    }
+
+    #[test]
+    fn opaque_temporal_values() {
+        let ymd = (2024i64 * 13 + 1) << 5 | 2;
+        let hms = 3i64 << 12 | 4 << 6 | 5;
+        let values = [
+            (12u8, ((ymd << 17 | hms) << 24) | 678000, "2024-01-02 03:04:05.678000"),
+            (10, (ymd << 17) << 24, "2024-01-02"),
+            (11, (12i64 << 12 | 34 << 6 | 56) << 24, "12:34:56"),
+            (11, -((1i64 << 12) << 24), "-01:00:00"),
+        ];
+        for (field_type, packed, expected) in values {
+            let mut bs = vec![0x0f, field_type, 0x08];
+            bs.extend_from_slice(&packed.to_le_bytes());
+            assert_eq!(decode(&bs), Value::from(expected));
+        }
+    }
+
+    #[test]
+    fn apply_partial_update() {
+        let doc = json!({"a": 1, "b": [1, 2, 3], "c d": {"e": "x"}});
+        let diffs = json!([
+            {"op": "REPLACE", "path": "$.a", "value": 2},
+            {"op": "INSERT", "path": "$.f", "value": null},
+            {"op": "REMOVE", "path": "$.b[0]"},
+            {"op": "INSERT", "path": "$.b[10]", "value": 4},
+            {"op": "REPLACE", "path": "$.\"c d\".e", "value": "y"}
+        ]);
+        assert_eq!(apply_json_diff(doc, &diffs), Some(json!({"a": 2, "b": [2, 3, 4], "c d": {"e": "y"}, "f": null})));
+        assert_eq!(apply_json_diff(json!([1, 2]), &json!([{"op": "REPLACE", "path": "$", "value": {"k": 1}}])), Some(json!({"k": 1})));
+    }
+
+    #[test]
+    fn apply_partial_update_missing_path() {
+        let doc = json!({"a": 1});
+        assert_eq!(apply_json_diff(doc.clone(), &json!([{"op": "REPLACE", "path": "$.b", "value": 1}])), None);
+        assert_eq!(apply_json_diff(doc.clone(), &json!([{"op": "REMOVE", "path": "$.a[3]"}])), None);
+        assert_eq!(apply_json_diff(doc, &json!([{"op": "INSERT", "path": "a", "value": 1}])), None);
+    }
+}
@@ -1,3 +1,9 @@
+// 枚举值和配置、MySQL、Canal中的写法一致(CANAL、VARCHAR)，消息字段名和Canal的FlatMessage一致(pkNames、isDdl)
+#![allow(non_snake_case, non_camel_case_types, clippy::upper_case_acronyms)]
+// 协议包按文档完整解码，部分字段目前没有使用
+#![allow(dead_code)]
+#![allow(clippy::type_complexity, clippy::too_many_arguments)]
+
 mod mysql;
 mod protocal;
 mod binlog;
@@ -6,21 +12,22 @@ mod config;
 mod message_queue;
 mod statistics;
 mod position_manager;
+mod json_binary;
+mod preflight;
+mod charset;
+mod debezium;
+mod avro;
 
-use std::{
-    io::{Read, Write},
-    net::TcpStream,
-};
-use bytes::BytesMut;
+use std::sync::{Arc, Mutex};
 use nom::AsBytes;
-use crate::binlog::{DeleteRowEvent, EventHeader, EventRaw, QueryEvent, RotateEvent, TableMap, TableMapEvent, UpdateRowEvent, WriteRowEvent};
-use crate::executor::{DmlData, RowEvents, Workers};
-use crate::mysql::{Decoder, MySQLConnection, native_password_auth, Packet};
-use crate::protocal::{AuthSwitchReq, AuthSwitchResp, Capabilities, ComBinLogDump, ComQuery, HandshakeResponse41, HandshakeV10, OkPacket};
+use crate::binlog::{ChecksumAlg, EventRaw, FormatDescriptionEvent, GtidEvent, GtidSet, HeartbeatEvent, PreviousGtidsEvent, QueryEvent, RotateEvent, RowsQueryEvent, XidEvent};
+use crate::executor::{DdlEvent, RowEvents, TransactionEvents, Workers};
+use crate::mysql::{ConnectionError, Decoder, MySQLConnection};
+use crate::protocal::{ComBinLogDump, ComBinLogDumpGtid, ComQuery, ComRegisterSlave, OkPacket};
 use clap::{Arg, App};
-use crate::config::{Config, get_abs_path};
-use crate::message_queue::{MessageQueues, QueueMessage};
-use crate::position_manager::{check_valid_pos, load_from_file, PositionMng, update_name_pos, update_pos};
+use crate::config::{Config, get_abs_path, TransactionMode};
+use crate::message_queue::MessageQueues;
+use crate::position_manager::{check_valid_gtid, check_valid_pos, Checkpoint, CommitTracker, load_from_file, pipeline_failure, PositionMng, track_checkpoint, update_gtid, update_name_pos};
 use crate::statistics::Statistics;
 
 #[macro_use]
@@ -51,11 +58,17 @@ fn main() {
             .short('g')
             .long("gen")
             .help("启动服务"))
+        .arg(Arg::with_name("check")
+            .long("check")
+            .help("检查服务器配置和复制权限"))
         .get_matches();
     let config_path = matches.get_one::<String>("config").expect("配置文件地址");
     if matches.is_present("gen") {
         cli_gen_default(config_path);
     }
+    if matches.is_present("check") {
+        cli_check(config_path);
+    }
     if matches.is_present("serve") {
         serve(config_path);
     }
@@ -70,63 +83,298 @@ fn cli_gen_default(config_path: &String) {
     println!("Dump complete!")
 }
 
+fn cli_check(config_path: &String) {
+    let config = Config::load_from(config_path.to_string());
+    let report = MySQLConnection::get_connection(config.db_ip.as_str(), config.db_port as u32, config.max_packages as u32, config.user_name.clone(), config.passwd.clone(), &config.ssl)
+        .and_then(|mut conn| preflight::check(&mut conn, &config));
+    match report {
+        Ok(report) => {
+            println!("{report}");
+            std::process::exit(if report.passed() { 0 } else { 1 });
+        },
+        Err(err) => {
+            println!("连接数据库失败: {err}");
+            std::process::exit(1);
+        }
+    }
+}
 
 fn serve(cfg_path: &String) {
     let config = Config::load_from(cfg_path.to_string());
     let mut mq = MessageQueues::new();
     let posMng = PositionMng::thread_safe_new();
-    mq.start_message_queue_from_config(config.clone().mqs, posMng.clone());
-    let if_pos_loaded = load_from_file(posMng.clone());
-    info!("before connection");
-    let mut conn = MySQLConnection::get_connection(config.clone().db_ip.as_str(), config.clone().db_port as u32, config.clone().max_packages as u32, config.clone().user_name, config.clone().passwd);
-    let query = ComQuery{query: "set @master_binlog_checksum= @@global.binlog_checksum".to_string()};
-    conn.write_package(0, &query).unwrap();
-    let (i, resp) = conn.read_package::<OkPacket>().unwrap();
-    //println!("ok resp:{:?}", resp);
-    let query: ComQuery = "show master status".into();
-    conn.write_package(0, &query).unwrap();
-    let text_resp = conn.read_text_result_set().unwrap();
-    //println!("text result is :{:?}", text_resp);
-    let (file, pos) = check_valid_pos(posMng.clone(), text_resp, config.from_start.is_some_and(|b|b));
-    info!("{file} {pos}");
-    update_name_pos(posMng.clone(), &file, pos);
-
-    let dump = ComBinLogDump {
-        pos,
-        flags: 0u16,
-        server_id: 100,
-        filename: file,
-    };
-    conn.write_package(0, &dump).unwrap();
-    let mut current_packet: Option<RowEvents> = None;
-
-    conn.start_keepalive();
+    let tracker = CommitTracker::thread_safe_new(posMng.clone());
+    mq.start_message_queue_from_config(config.clone().mqs, tracker.clone());
+    if let Err(err) = load_from_file(posMng.clone()) {
+        error!("位置文件无效: {err}");
+        std::process::exit(1);
+    }
 
     let mut worker = Workers::new();
-    worker.start(config.workers as usize, mq.clone(), config.clone().instances, config.clone());
+    worker.start(config.workers as usize, mq.clone(), config.clone().instances, config.clone(), tracker.clone());
+    let max_attempts = config.max_reconnect_attempts.unwrap_or(10);
     let mut seq_idx:u64 = 0;
     let mut statistics= Statistics::new();
+    let mut failures: u32 = 0;
+    let mut first_connect = true;
     loop {
-        if let Ok((_, buf)) = conn.read_package::<Vec<u8>>(){
-            statistics.feed_bytes(seq_idx, buf.payload.len());
-            let event_result = EventRaw::decode(buf.payload.as_bytes());
-            if let Ok((_, ev)) = event_result {
-                //println!("meet event: {:?}", &ev.header.event_type);
-                if ev.header.event_type == 19 {
-                    current_packet = Some(RowEvents::new(ev.clone()))
-                }
-                if vec![30u8, 31u8, 32u8, 4u8].contains(&ev.header.event_type) {
-                    if let Some(ref mut cp) = current_packet {
-                        cp.append(ev.clone(), seq_idx);
-                        &worker.push(cp);
-                        seq_idx += 1;
-                    }
+        info!("before connection");
+        match start_dump(&config, posMng.clone(), first_connect) {
+            Ok((conn, gtid_set, current_binlog, checksum)) => {
+                first_connect = false;
+                // 收到过事件说明连接是正常的，重新计算重连次数
+                if read_events(conn, &config, &mut worker, tracker.clone(), gtid_set, current_binlog, checksum, &mut seq_idx, &mut statistics) {
+                    failures = 0;
                 }
+                // 服务器可能已经重启，table_id对应的表会变化
+                worker.reset_table_meta();
+            },
+            Err(err) if err.is_fatal() => {
+                error!("启动binlog复制失败: {err}");
+                std::process::exit(1);
+            },
+            Err(err) => {
+                error!("启动binlog复制失败: {err}");
             }
-        }else{
-            error!("读取包失败或者遇到非预期的包");
         }
+        if let Some(reason) = pipeline_failure(tracker.clone()) {
+            error!("同步已停止: {reason}");
+            std::process::exit(1);
+        }
+        failures += 1;
+        if failures > max_attempts {
+            error!("重连{max_attempts}次仍然失败，退出");
+            std::process::exit(1);
+        }
+        let backoff = (1u64 << (failures - 1).min(6)).min(60);
+        warn!("{backoff}秒后进行第{failures}次重连，从已提交的位置继续");
+        std::thread::sleep(std::time::Duration::from_secs(backoff));
+    }
+}
+
+/// 连接数据库并发送dump命令，重连时from_start不再生效，从PositionMng中已提交的位置继续
+fn start_dump(config: &Config, posMng: Arc<Mutex<PositionMng>>, first_connect: bool) -> Result<(MySQLConnection, GtidSet, String, ChecksumAlg), ConnectionError> {
+    let mut conn = MySQLConnection::get_connection(config.db_ip.as_str(), config.db_port as u32, config.max_packages as u32, config.user_name.clone(), config.passwd.clone(), &config.ssl)?;
+    let from_start = first_connect && config.from_start.is_some_and(|b|b);
+    // 收到第一个FORMAT_DESCRIPTION_EVENT之前的事件(如开头的Rotate)按服务器当前的算法校验
+    let query: ComQuery = "select @@global.binlog_checksum".into();
+    conn.write_package(0, &query)?;
+    let text_resp = conn.read_text_result_set().map_err(|err| ConnectionError::Protocol(format!("查询binlog_checksum失败: {err}")))?;
+    let checksum = match text_resp.rows.first() {
+        Some(row) => ChecksumAlg::from_name(String::from_utf8_lossy(row.columns[0].as_slice()).as_ref()),
+        None => ChecksumAlg::OFF
+    };
+    let query = ComQuery{query: "set @master_binlog_checksum= @@global.binlog_checksum".to_string()};
+    conn.write_package(0, &query)?;
+    conn.read_package::<OkPacket>().map_err(|err| ConnectionError::Protocol(format!("设置checksum失败: {err:?}")))?;
+    // 主库空闲时定期发送心跳，超过3个周期收不到任何事件就认为连接已经失效
+    let heartbeat_period = config.heartbeat_period.unwrap_or(15).max(1) as u64;
+    let query = ComQuery{query: format!("set @master_heartbeat_period = {}", heartbeat_period * 1_000_000_000)};
+    conn.write_package(0, &query)?;
+    conn.read_package::<OkPacket>().map_err(|err| ConnectionError::Protocol(format!("设置心跳周期失败: {err:?}")))?;
+    conn.set_read_timeout(Some(std::time::Duration::from_secs(heartbeat_period * 3)))?;
+    let server_id = config.server_id.unwrap_or(100);
+    // 重连时旧的dump线程可能还没退出，服务器会用新连接替换掉同一个server_id，所以只在启动时检查
+    if first_connect {
+        let report = preflight::check(&mut conn, config)?;
+        if !report.passed() {
+            return Err(ConnectionError::Preflight(report.to_string()));
+        }
+        info!("启动前检查:\n{report}");
+    }
+    let register = ComRegisterSlave {
+        server_id,
+        hostname: config.report_host.clone().unwrap_or_default(),
+        user: "".to_string(),
+        password: "".to_string(),
+        port: config.report_port.unwrap_or(0),
+    };
+    if let Some(field) = register.oversized_field() {
+        return Err(ConnectionError::Config(format!("{field}超过255字节")));
+    }
+    conn.write_package(0, &register)?;
+    conn.read_package::<OkPacket>().map_err(|err| ConnectionError::Protocol(format!("注册从库失败: {err:?}")))?;
+    let gtid_mode = config.gtid_mode.is_some_and(|b|b);
+    let mut gtid_set = GtidSet::new();
+    let mut current_binlog = "".to_string();
+    if gtid_mode {
+        let query: ComQuery = "select @@global.gtid_executed".into();
+        conn.write_package(0, &query)?;
+        let text_resp = conn.read_text_result_set().map_err(|err| ConnectionError::Protocol(format!("查询gtid_executed失败: {err}")))?;
+        gtid_set = check_valid_gtid(posMng.clone(), text_resp, from_start).map_err(|err| ConnectionError::Protocol(err.to_string()))?;
+        info!("GTID: {gtid_set}");
+        update_gtid(posMng.clone(), &gtid_set.to_string());
+
+        let dump = ComBinLogDumpGtid {
+            flags: 0u16,
+            server_id,
+            filename: "".to_string(),
+            pos: 4,
+            gtid_set: gtid_set.clone(),
+        };
+        conn.write_package(0, &dump)?;
+    }else{
+        let query: ComQuery = "show master status".into();
+        conn.write_package(0, &query)?;
+        let text_resp = conn.read_text_result_set().map_err(|err| ConnectionError::Protocol(format!("查询master status失败: {err}")))?;
+        let (file, pos) = check_valid_pos(posMng.clone(), text_resp, from_start);
+        info!("{file} {pos}");
+        update_name_pos(posMng.clone(), &file, pos);
+        current_binlog = file.clone();
 
+        let dump = ComBinLogDump {
+            pos,
+            flags: 0u16,
+            server_id,
+            filename: file,
+        };
+        conn.write_package(0, &dump)?;
     }
+    Ok((conn, gtid_set, current_binlog, checksum))
 }
 
+/// 读取binlog事件直到连接断开，未提交的事务和表映射都会丢弃，返回是否收到过事件
+fn read_events(mut conn: MySQLConnection, config: &Config, worker: &mut Workers, tracker: Arc<Mutex<CommitTracker>>, mut gtid_set: GtidSet, mut current_binlog: String, mut checksum: ChecksumAlg, seq_idx: &mut u64, statistics: &mut Statistics) -> bool {
+    let txn_mode = config.transaction_mode.clone().unwrap_or(TransactionMode::NONE);
+    let mut pending_gtid: Option<GtidEvent> = None;
+    let mut current_packet: Option<RowEvents> = None;
+    let mut current_txn: Option<TransactionEvents> = None;
+    let mut in_txn = false;
+    let mut received = false;
+    // 最近一条 ROWS_QUERY 事件的SQL，之后的行事件都由它产生
+    let mut current_sql: Option<String> = None;
+
+    loop {
+        // 消息无法送达时位置不会再前进，继续读取没有意义
+        if pipeline_failure(tracker.clone()).is_some() {
+            conn.close();
+            return received;
+        }
+        let buf = match conn.read_package::<Vec<u8>>() {
+            Ok((_, buf)) => buf,
+            Err(err) => {
+                error!("读取binlog失败，连接已断开或者超时未收到事件和心跳: {err:?}");
+                conn.close();
+                return received;
+            }
+        };
+        // 0xfe开头的短包是EOF，服务器结束了dump
+        if buf.payload.first() == Some(&0xfe) && buf.payload.len() < 9 {
+            warn!("服务器结束了binlog dump");
+            conn.close();
+            return received;
+        }
+        received = true;
+        statistics.feed_bytes(*seq_idx, buf.payload.len());
+        let ev = match EventRaw::decode_checked(buf.payload.as_bytes(), checksum) {
+            Ok(ev) => ev,
+            Err(err) => {
+                // 数据已经损坏，断开后从已提交的位置重新读取
+                error!("binlog事件无效: {err}");
+                conn.close();
+                return received;
+            }
+        };
+        if ev.header.event_type == FormatDescriptionEvent::EVENT_TYPE {
+            match FormatDescriptionEvent::decode(ev.payload.as_bytes()) {
+                Ok((_, fde)) => {
+                    info!("binlog版本:{} 服务器版本:{} checksum:{:?}", fde.binlog_version, fde.server_version, fde.checksum_alg);
+                    checksum = fde.checksum_alg;
+                },
+                Err(err) => { error!("解码FORMAT_DESCRIPTION包失败:{err:?} data:{:?}", ev.payload); }
+            }
+        }
+        if ev.header.event_type == RowsQueryEvent::EVENT_TYPE {
+            match RowsQueryEvent::decode(ev.payload.as_bytes()) {
+                Ok((_, rows_query)) => { current_sql = Some(rows_query.query); },
+                Err(err) => { error!("解码ROWS_QUERY包失败:{err:?} data:{:?}", ev.payload); }
+            }
+        }
+        if ev.header.event_type == 19 {
+            current_packet = Some(RowEvents::new(ev.clone(), current_binlog.clone(), pending_gtid.as_ref().map(|g| g.gtid()), current_sql.clone()))
+        }
+        if ev.header.event_type == RotateEvent::EVENT_TYPE {
+            match RotateEvent::decode(ev.payload.as_bytes()) {
+                Ok((_, rotate))=>{
+                    info!("binlog切换到:{} {}", &rotate.binlog_name, rotate.position);
+                    let checkpoint = Checkpoint{ binlog: rotate.binlog_name.clone(), position: rotate.position as u32, gtid_set: gtid_set.to_string() };
+                    track_checkpoint(tracker.clone(), *seq_idx, checkpoint);
+                    *seq_idx += 1;
+                    current_binlog = rotate.binlog_name;
+                },
+                Err(err)=>{
+                    error!("解码Rotate包失败:{err:?} data:{:?}", ev.payload);
+                }
+            }
+        }
+        if ev.header.event_type == HeartbeatEvent::EVENT_TYPE {
+            if let Ok((_, heartbeat)) = HeartbeatEvent::decode(ev.payload.as_bytes()) {
+                debug!("心跳: {} {}", heartbeat.binlog_name, ev.header.log_pos);
+            }
+        }
+        if ev.header.event_type == PreviousGtidsEvent::EVENT_TYPE {
+            if let Ok((_, prev)) = PreviousGtidsEvent::decode(ev.payload.as_bytes()) {
+                info!("Previous GTIDs: {}", prev.gtid_set);
+            }
+        }
+        if ev.header.event_type == GtidEvent::EVENT_TYPE {
+            match GtidEvent::decode(ev.payload.as_bytes()) {
+                Ok((_, gtid))=>{ pending_gtid = Some(gtid); },
+                Err(err)=>{ error!("解码GTID包失败:{err:?} data:{:?}", ev.payload); }
+            }
+        }
+        let query_event = if ev.header.event_type == QueryEvent::EVENT_TYPE {
+            QueryEvent::decode(ev.payload.as_bytes()).ok().map(|(_, q)| q)
+        } else { None };
+        if query_event.as_ref().is_some_and(|q| q.parse_ddl().is_some()) {
+            worker.push_ddl(DdlEvent{ query: ev.clone(), seq_idx: *seq_idx });
+            *seq_idx += 1;
+        }
+        // XID、COMMIT、ROLLBACK表示事务结束，记录已完成的GTID。事务中的SAVEPOINT、ROLLBACK TO SAVEPOINT
+        // 等语句不结束事务；BEGIN之外的Query(DDL等)自己就是一个事务
+        let is_statement = |stmt: &str| query_event.as_ref().is_some_and(|q| q.statement.trim().eq_ignore_ascii_case(stmt));
+        let is_begin = is_statement("BEGIN");
+        let is_commit = ev.header.event_type == XidEvent::EVENT_TYPE
+            || is_statement("COMMIT")
+            || is_statement("ROLLBACK")
+            || (query_event.is_some() && !is_begin && !in_txn);
+        if is_begin {
+            in_txn = true;
+            if txn_mode != TransactionMode::NONE {
+                current_txn = Some(TransactionEvents::new());
+            }
+        }
+        if is_begin || is_commit {
+            current_sql = None;
+        }
+        if is_commit {
+            in_txn = false;
+            if let Some(gtid) = pending_gtid.take() {
+                gtid_set.add(&gtid.sid, gtid.gno);
+            }
+            if let Some(mut txn) = current_txn.take() {
+                let xid = if ev.header.event_type == XidEvent::EVENT_TYPE {
+                    XidEvent::decode(ev.payload.as_bytes()).map(|(_, x)| x.xid).unwrap_or(0)
+                } else { 0 };
+                txn.commit(xid, ev.header.timestamp as u64 * 1000, *seq_idx);
+                worker.push_transaction(txn);
+                *seq_idx += 1;
+            }
+            // 提交点之前的事件全部送达后才会保存这个位置
+            let checkpoint = Checkpoint{ binlog: current_binlog.clone(), position: ev.header.log_pos, gtid_set: gtid_set.to_string() };
+            track_checkpoint(tracker.clone(), *seq_idx, checkpoint);
+            *seq_idx += 1;
+        }
+        if [30u8, 31u8, 32u8, 39u8].contains(&ev.header.event_type) {
+            if let Some(ref mut cp) = current_packet {
+                cp.append(ev.clone(), *seq_idx);
+                if let Some(ref mut txn) = current_txn {
+                    txn.rows.push(cp.clone());
+                }else{
+                    worker.push(cp);
+                }
+                *seq_idx += 1;
+            }
+        }
+    }
+}
@@ -1,20 +1,27 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use rdkafka::ClientConfig;
-use rdkafka::producer::{BaseRecord, ThreadedProducer, DefaultProducerContext};
+use rdkafka::{ClientConfig, ClientContext};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer};
 use crate::config::{KafkaConfig, Mq, MqConfig, RedisConfig};
-use redis::{AsyncCommands, Client, Commands};
-use crate::executor::generate_random_number;
+use redis::{Client, Commands};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use crate::position_manager::{PositionMng, update_pos};
+use std::time::Duration;
+use crate::position_manager::{abort_pipeline, ack_delivery, CommitTracker, PanicGuard};
+
+/// 一条消息最多发送的次数，仍然失败就停止整个流程，不能跳过
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
 
 
 #[derive(Debug, Clone)]
 pub struct QueueMessage {
     pub topic: String,
-    pub payload: String,
-    pub pos: u32
+    pub payload: Vec<u8>,
+    pub seq: u64,
+    pub key: Option<String>,
+    /// 指定分区时的哈希值，按分区数取模
+    pub partition_hash: Option<u64>
 }
 
 
@@ -30,55 +37,55 @@ impl MessageQueues {
         }
     }
 
-    pub fn register_tx(&mut self, chn: &String, tx: Arc<Mutex<Sender<QueueMessage>>>){
-        self.chanels.insert(chn.clone(), tx);
+    pub fn register_tx(&mut self, chn: &str, tx: Arc<Mutex<Sender<QueueMessage>>>){
+        self.chanels.insert(chn.to_string(), tx);
     }
 
-    pub fn push(&mut self, chn: &String, msg: QueueMessage) {
+    /// 找不到消息队列时消息不会被确认，位置永远无法推进，只能停止整个流程
+    pub fn push(&mut self, chn: &String, msg: QueueMessage, tracker: &Arc<Mutex<CommitTracker>>) {
         if let Some(chn_ref) = self.chanels.get_mut(chn) {
             if let Ok(chn) = chn_ref.lock(){
                 chn.send(msg).expect("send error");
             }else{
-                println!("==========>(夭寿啦，获取锁失败了)");
+                error!("==========>(夭寿啦，获取锁失败了)");
             }
+        } else {
+            abort_pipeline(tracker.clone(), format!("消息队列[{chn}]未配置，消息无法发送 topic:{}", msg.topic));
         }
     }
 
-    pub fn start_message_queue_from_config(&mut self, queue_cfg: Vec<Mq>, posMng: Arc<Mutex<PositionMng>>) {
+    pub fn start_message_queue_from_config(&mut self, queue_cfg: Vec<Mq>, tracker: Arc<Mutex<CommitTracker>>) {
         let queue_cfg = queue_cfg.clone();
         for cfg in queue_cfg {
             let (tx, rx) = channel();
             let tx = Arc::new(Mutex::new(tx));
             self.register_tx(&cfg.mq_name, tx);
             let config = cfg.clone();
-            let posMng = posMng.clone();
+            let tracker = tracker.clone();
             thread::spawn(move || {
-                println!("Outgiving thread [{}]", &cfg.mq_name);
+                info!("Outgiving thread [{}]", &cfg.mq_name);
+                let _guard = PanicGuard{ name: format!("发送线程[{}]", &cfg.mq_name), tracker: tracker.clone() };
                 let mut mq_ins: Box<dyn QueueClient> = match config.mq_cfg {
                     MqConfig::KAFKA(kfk)=>{
-                        let kc = KafkaClient::init_from_config(&kfk);
+                        let kc = KafkaClient::init_from_config(&kfk, tracker);
                         Box::new(kc)
                     },
                     MqConfig::REDIS(rds)=>{
-                        let rd = RedisClient::init_from_config(&rds);
+                        let rd = RedisClient::init_from_config(&rds, tracker);
                         Box::new(rd)
                     }
                 };
-                outgiving_body(rx, mq_ins.as_mut(), posMng);
+                outgiving_body(rx, mq_ins.as_mut());
             });
 
         }
     }
 }
 
-fn outgiving_body(rx: Receiver<QueueMessage>, mq_ins: &mut dyn QueueClient, posMng: Arc<Mutex<PositionMng>>) {
-    loop{
-        if let Ok(msg) = rx.recv() {
-            mq_ins.queue_message(&msg);
-            update_pos(posMng.clone(), msg.pos);
-            //println!("msg sent!");
-            //println!("{}", &msg.payload);
-        }
+/// 位置由CommitTracker在消息确认送达后推进，这里只负责发送
+fn outgiving_body(rx: Receiver<QueueMessage>, mq_ins: &mut dyn QueueClient) {
+    while let Ok(msg) = rx.recv() {
+        mq_ins.queue_message(&msg);
     }
 }
 
@@ -87,86 +94,193 @@ trait QueueClient : Send{
 }
 
 
+/// 发送中的消息，attempt为放入本地队列失败的次数
+struct Delivery {
+    message: QueueMessage,
+    attempt: u32
+}
+
+/// Kafka投递回调，broker确认后才算送达。
+/// 开启幂等后librdkafka按顺序重试，回调中的失败已经是重试不了的错误，
+/// 这时再重发会排在之后的消息后面打乱顺序，只能停止整个流程，从已保存的位置重新开始
+struct DeliveryContext {
+    tracker: Arc<Mutex<CommitTracker>>
+}
+
+impl ClientContext for DeliveryContext {}
+
+impl ProducerContext for DeliveryContext {
+    type DeliveryOpaque = Box<Delivery>;
+
+    fn delivery(&self, delivery_result: &DeliveryResult<'_>, delivery: Self::DeliveryOpaque) {
+        match delivery_result {
+            Ok(_)=>{
+                ack_delivery(self.tracker.clone(), delivery.message.seq);
+            },
+            Err((err, _))=>{
+                abort_pipeline(self.tracker.clone(), format!("Kafka消息投递失败 topic:{} :{:?}", delivery.message.topic, err));
+            }
+        }
+    }
+}
 
 struct KafkaClient {
-    producer: Option<ThreadedProducer<DefaultProducerContext>>
+    producer: Option<ThreadedProducer<DeliveryContext>>,
+    partitions: HashMap<String, i32>,
+    tracker: Arc<Mutex<CommitTracker>>
 }
 
 impl KafkaClient{
-    fn init_from_config(config: &KafkaConfig) -> Self {
+    fn init_from_config(config: &KafkaConfig, tracker: Arc<Mutex<CommitTracker>>) -> Self {
         let servers = config.brokers.clone();
         let producer = if servers.is_empty() {
             None
         } else{
-            let pd: Option<ThreadedProducer<DefaultProducerContext>> = match ClientConfig::new()
+            let pd: Option<ThreadedProducer<DeliveryContext>> = match ClientConfig::new()
                 .set("bootstrap.servers", servers)
-                .set("message.timeout.ms", "5000")
+                // 幂等发送：librdkafka按顺序重试，不会重复也不会乱序；0为不限制投递超时，broker恢复前一直重试
+                .set("enable.idempotence", "true")
+                .set("max.in.flight.requests.per.connection", "5")
+                .set("message.timeout.ms", "0")
                 .set("queue.buffering.max.ms", format!("{}", config.queue_buffering_max))
-                .create() {
+                .create_with_context(DeliveryContext{ tracker: tracker.clone() }) {
                 Ok(p)=>{
                     Some(p)
                 },
                 Err(err)=>{
-                    println!("kafka producer error:{:?}", err);
+                    error!("kafka producer error:{:?}", err);
                     None
                 }
             };
             pd
         };
-        Self{ producer }
+        Self{ producer, partitions: HashMap::new(), tracker }
     }
-}
 
-impl QueueClient for KafkaClient {
-    fn queue_message(&mut self, message: &QueueMessage) {
-        if let Some(producer) = &self.producer {
-            match producer.send(BaseRecord::<String, String>::to(message.topic.as_str()).payload(&message.payload)) {
+    /// topic的分区数，从broker元数据获取后缓存
+    fn partition_count(&mut self, topic: &String) -> Option<i32> {
+        if let Some(count) = self.partitions.get(topic) {
+            return Some(*count);
+        }
+        let producer = self.producer.as_ref()?;
+        match producer.client().fetch_metadata(Some(topic.as_str()), std::time::Duration::from_secs(5)) {
+            Ok(metadata) => {
+                let count = metadata.topics().iter().find(|t| t.name() == topic.as_str()).map(|t| t.partitions().len() as i32)?;
+                if count > 0 {
+                    self.partitions.insert(topic.clone(), count);
+                    Some(count)
+                }else{
+                    None
+                }
+            },
+            Err(err) => {
+                warn!("Kafka 获取分区数失败:{:?}", err);
+                None
+            }
+        }
+    }
+
+    /// 放入本地队列失败时等待后重试，超过次数就停止整个流程。这时之后的消息还没有发送，重试不会打乱顺序
+    fn send(&mut self, delivery: Delivery) {
+        let message = delivery.message.clone();
+        let partition = match message.partition_hash {
+            Some(hash) => self.partition_count(&message.topic).map(|count| (hash % count as u64) as i32),
+            None => None
+        };
+        let producer = match &self.producer {
+            Some(producer) => producer,
+            None => {
+                abort_pipeline(self.tracker.clone(), format!("Kafka未连接，消息无法发送 topic:{}", message.topic));
+                return;
+            }
+        };
+        let mut record = BaseRecord::<String, Vec<u8>, Box<Delivery>>::with_opaque_to(message.topic.as_str(), Box::new(delivery)).payload(&message.payload);
+        if let Some(key) = &message.key {
+            record = record.key(key);
+        }
+        if let Some(partition) = partition {
+            record = record.partition(partition);
+        }
+        loop {
+            match producer.send(record) {
                 Ok(_)=>{
-                    //println!("Kafka Message Sent!");
+                    break;
                 }
-                Err((err, _))=>{
-                    println!("Kafka sent error:{:?}", err);
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), rec))=>{
+                    // 本地队列满了就等待后重试，不能丢消息
+                    record = rec;
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err((err, rec))=>{
+                    record = rec;
+                    if record.delivery_opaque.attempt >= MAX_DELIVERY_ATTEMPTS {
+                        abort_pipeline(self.tracker.clone(), format!("Kafka消息发送{MAX_DELIVERY_ATTEMPTS}次仍然失败 topic:{} :{:?}", message.topic, err));
+                        break;
+                    }
+                    warn!("Kafka发送失败(第{}次) topic:{} :{:?}", record.delivery_opaque.attempt, message.topic, err);
+                    thread::sleep(Duration::from_secs(record.delivery_opaque.attempt as u64));
+                    record.delivery_opaque.attempt += 1;
                 }
             }
-        }else{
-            println!("Kafka Not Connected");
         }
     }
 }
 
+impl QueueClient for KafkaClient {
+    fn queue_message(&mut self, message: &QueueMessage) {
+        self.send(Delivery{ message: message.clone(), attempt: 1 });
+    }
+}
+
 struct RedisClient {
-    redis: Option<Client>
+    redis: Option<Client>,
+    tracker: Arc<Mutex<CommitTracker>>
 }
 
 impl RedisClient {
-    fn init_from_config(config: &RedisConfig) -> Self {
+    fn init_from_config(config: &RedisConfig, tracker: Arc<Mutex<CommitTracker>>) -> Self {
         let (ip, port) = (config.ip.clone(), config.port);
-        let redis = if let Ok(client) = redis::Client::open(format!("redis://{ip}:{port}")){
-            Some(client)
-        }else{ None };
-        Self{redis}
+        let redis = match redis::Client::open(format!("redis://{ip}:{port}")) {
+            Ok(client) => Some(client),
+            Err(err) => {
+                error!("Redis配置错误:{err}");
+                None
+            }
+        };
+        Self{redis, tracker}
+    }
+
+    fn rpush(&self, redis: &Client, message: &QueueMessage) -> redis::RedisResult<()> {
+        let mut conn = redis.get_connection()?;
+        conn.rpush::<String, Vec<u8>, ()>(message.topic.clone(), message.payload.clone())
     }
 }
 
 impl QueueClient for RedisClient {
 
+    /// 同步发送，失败时等待后重试，保持消息的顺序
     fn queue_message(&mut self, message: &QueueMessage) {
-        if let Some(redis) = &self.redis {
-            if let Ok(mut conn) = redis.get_connection() {
-                match conn.rpush::<String, String, ()>(message.topic.clone(), message.payload.clone()) {
-                    Ok(_)=>{
-                        //println!("Redis Message Sent!");
-                    },
-                    Err(err)=>{
-                        println!("Redis sent error:{:?}", err);
+        let redis = match &self.redis {
+            Some(redis) => redis,
+            None => {
+                abort_pipeline(self.tracker.clone(), format!("Redis未配置，消息无法发送 topic:{}", message.topic));
+                return;
+            }
+        };
+        for attempt in 1..=MAX_DELIVERY_ATTEMPTS {
+            match self.rpush(redis, message) {
+                Ok(_)=>{
+                    ack_delivery(self.tracker.clone(), message.seq);
+                    return;
+                },
+                Err(err)=>{
+                    warn!("Redis发送失败(第{attempt}次) topic:{} :{:?}", message.topic, err);
+                    if attempt < MAX_DELIVERY_ATTEMPTS {
+                        thread::sleep(Duration::from_secs(attempt as u64));
                     }
                 }
-            }else{
-                println!("Redis Connect Error");
             }
-        }else{
-            println!("Redis Not Configured");
         }
-
+        abort_pipeline(self.tracker.clone(), format!("Redis消息发送{MAX_DELIVERY_ATTEMPTS}次仍然失败 topic:{}", message.topic));
     }
-}
\ No newline at end of file
+}
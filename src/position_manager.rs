@@ -1,8 +1,10 @@
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 use std::{fs, thread};
 use std::path::Path;
 use std::sync::mpsc::{channel, Sender};
 use serde::{Deserialize, Serialize};
+use crate::binlog::{GtidError, GtidSet};
 use crate::config::get_abs_path;
 use crate::executor::current_ts;
 use crate::protocal::{TextResult, TextResultSet};
@@ -11,19 +13,22 @@ use crate::protocal::{TextResult, TextResultSet};
 pub struct PositionSet {
     pub binlog: String,
     pub position: u32,
+    #[serde(default)]
+    pub gtid_set: String,
     pub saving_ts: u64
 }
 
 pub struct PositionMng {
     pub binlog: String,
     pub position: u32,
+    pub gtid_set: String,
     pub loaded: bool,
     pub tx: Sender<PositionSet>,
 }
 
 impl PositionMng {
     fn new(tx: Sender<PositionSet>)->Self {
-        Self{ binlog: "".to_string(), position: 0, loaded: false, tx }
+        Self{ binlog: "".to_string(), position: 0, gtid_set: "".to_string(), loaded: false, tx }
     }
 
     pub fn thread_safe_new() -> Arc<Mutex<Self>> {
@@ -50,24 +55,149 @@ impl PositionMng {
     fn new_pos(&mut self, position: u32) {
         self.position = position;
     }
+
+    fn new_gtid(&mut self, gtid_set: &String) {
+        self.gtid_set = gtid_set.to_string();
+    }
+}
+
+/// 事务提交点(XID、COMMIT、ROLLBACK、事务外的Query、Rotate)之后可以安全续传的位置
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub binlog: String,
+    pub position: u32,
+    pub gtid_set: String
+}
+
+/// 已分发事件的送达状态，parts为还未确定消息数的worker数量(按主键分发时一个事件可能拆给多个worker)
+struct PendingEvent {
+    remaining: usize,
+    parts: usize,
+    checkpoint: Option<Checkpoint>
+}
+
+/// 记录已分发但还未送达的事件，只保存连续送达的最高提交点
+/// 进程崩溃后会重放未确认的事件，不会跳过
+pub struct CommitTracker {
+    pending: BTreeMap<u64, PendingEvent>,
+    posMng: Arc<Mutex<PositionMng>>,
+    /// 消息无法送达或者线程异常退出，位置不会再前进，主线程看到后停止同步
+    failure: Option<String>
+}
+
+impl CommitTracker {
+    pub fn thread_safe_new(posMng: Arc<Mutex<PositionMng>>) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self{ pending: BTreeMap::new(), posMng, failure: None }))
+    }
+
+    fn track(&mut self, seq: u64, parts: usize, checkpoint: Option<Checkpoint>) {
+        self.pending.insert(seq, PendingEvent{ remaining: 0, parts, checkpoint });
+        self.advance();
+    }
+
+    fn expect(&mut self, seq: u64, count: usize) {
+        if let Some(ev) = self.pending.get_mut(&seq) {
+            ev.remaining += count;
+            ev.parts = ev.parts.saturating_sub(1);
+        }
+        self.advance();
+    }
+
+    fn ack(&mut self, seq: u64) {
+        if let Some(ev) = self.pending.get_mut(&seq) {
+            ev.remaining = ev.remaining.saturating_sub(1);
+        }
+        self.advance();
+    }
+
+    fn advance(&mut self) {
+        let mut last: Option<Checkpoint> = None;
+        while let Some(entry) = self.pending.first_entry() {
+            if entry.get().parts > 0 || entry.get().remaining > 0 {
+                break;
+            }
+            if let Some(cp) = entry.remove().checkpoint {
+                last = Some(cp);
+            }
+        }
+        if let Some(cp) = last {
+            save_checkpoint(self.posMng.clone(), &cp);
+        }
+    }
+}
+
+/// 持有锁的线程panic之后锁会被标记为poisoned，状态本身没有问题，继续使用
+fn with_tracker<R, F: FnOnce(&mut CommitTracker) -> R>(t: Arc<Mutex<CommitTracker>>, f: F) -> R {
+    let mut t = t.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    f(&mut t)
 }
 
-pub fn load_from_file(p: Arc<Mutex<PositionMng>>) -> bool {
+/// 分发事件前登记，parts个worker随后分别通过expect_acks告知需要确认的消息数
+pub fn track_event(t: Arc<Mutex<CommitTracker>>, seq: u64, parts: usize) {
+    with_tracker(t, |t| t.track(seq, parts, None));
+}
+
+/// 登记提交点，之前的事件全部送达后保存该位置
+pub fn track_checkpoint(t: Arc<Mutex<CommitTracker>>, seq: u64, checkpoint: Checkpoint) {
+    with_tracker(t, |t| t.track(seq, 0, Some(checkpoint)));
+}
+
+pub fn expect_acks(t: Arc<Mutex<CommitTracker>>, seq: u64, count: usize) {
+    with_tracker(t, |t| t.expect(seq, count));
+}
+
+pub fn ack_delivery(t: Arc<Mutex<CommitTracker>>, seq: u64) {
+    with_tracker(t, |t| t.ack(seq));
+}
+
+/// 出现无法恢复的错误，停止整个流程，只保留第一个原因
+pub fn abort_pipeline(t: Arc<Mutex<CommitTracker>>, reason: String) {
+    error!("{reason}，停止同步");
+    with_tracker(t, |t| {
+        if t.failure.is_none() {
+            t.failure = Some(reason);
+        }
+    });
+}
+
+pub fn pipeline_failure(t: Arc<Mutex<CommitTracker>>) -> Option<String> {
+    with_tracker(t, |t| t.failure.clone())
+}
+
+/// 放在worker和发送线程中，线程panic时它负责的消息永远不会确认，直接停止整个流程
+pub struct PanicGuard {
+    pub name: String,
+    pub tracker: Arc<Mutex<CommitTracker>>
+}
+
+impl Drop for PanicGuard {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            abort_pipeline(self.tracker.clone(), format!("{}异常退出", self.name));
+        }
+    }
+}
+
+/// 读取保存的位置，文件中的GTID集合无效时返回错误，不能从错误的位置继续
+pub fn load_from_file(p: Arc<Mutex<PositionMng>>) -> Result<bool, GtidError> {
     let abs_path = get_abs_path("~/.ru_cdc/meta.json".to_string());
     let position = match read_file_content(abs_path) {
         Ok(s)=> {
-            println!("load meta:{}", &s);
+            info!("load meta:{}", &s);
             serde_json::from_str::<PositionSet>(s.as_str())
         },
         Err(err)=>{
-            println!("读取索引meta失败：{err:?}");
-            return false;
+            warn!("读取索引meta失败：{err:?}");
+            return Ok(false);
         }
     };
     if let Ok(pos) = position{
+        GtidSet::parse(pos.gtid_set.as_str())?;
+        update_gtid(p.clone(), &pos.gtid_set);
         update_name_pos(p.clone(), &pos.binlog, pos.position);
+        return Ok(true);
     }
-    return false;
+    Ok(false)
 }
 
 pub fn update_name_pos(p: Arc<Mutex<PositionMng>>, binlog: &String, position: u32) {
@@ -77,6 +207,7 @@ pub fn update_name_pos(p: Arc<Mutex<PositionMng>>, binlog: &String, position: u3
             p.tx.send(PositionSet{
                 binlog: p.binlog.clone(),
                 position: p.position,
+                gtid_set: p.gtid_set.clone(),
                 saving_ts: current_ts(),
             }).expect("发送到队列错误");
             break
@@ -86,6 +217,24 @@ pub fn update_name_pos(p: Arc<Mutex<PositionMng>>, binlog: &String, position: u3
     }
 }
 
+pub fn save_checkpoint(p: Arc<Mutex<PositionMng>>, checkpoint: &Checkpoint) {
+    loop {
+        if let Ok(mut p) = p.lock() {
+            p.update(&checkpoint.binlog, checkpoint.position);
+            p.new_gtid(&checkpoint.gtid_set);
+            p.tx.send(PositionSet{
+                binlog: p.binlog.clone(),
+                position: p.position,
+                gtid_set: p.gtid_set.clone(),
+                saving_ts: current_ts(),
+            }).expect("发送到队列错误");
+            break
+        }else{
+            thread::sleep(std::time::Duration::from_micros(31));
+        }
+    }
+}
+
 pub fn update_pos(p: Arc<Mutex<PositionMng>>, position: u32) {
     loop {
         if let Ok(mut p) = p.lock() {
@@ -93,6 +242,7 @@ pub fn update_pos(p: Arc<Mutex<PositionMng>>, position: u32) {
             p.tx.send(PositionSet{
                 binlog: p.binlog.clone(),
                 position: p.position,
+                gtid_set: p.gtid_set.clone(),
                 saving_ts: current_ts(),
             }).expect("发送到队列错误");
             break
@@ -102,6 +252,23 @@ pub fn update_pos(p: Arc<Mutex<PositionMng>>, position: u32) {
     }
 }
 
+pub fn update_gtid(p: Arc<Mutex<PositionMng>>, gtid_set: &String) {
+    loop {
+        if let Ok(mut p) = p.lock() {
+            p.new_gtid(gtid_set);
+            p.tx.send(PositionSet{
+                binlog: p.binlog.clone(),
+                position: p.position,
+                gtid_set: p.gtid_set.clone(),
+                saving_ts: current_ts(),
+            }).expect("发送到队列错误");
+            break
+        }else{
+            thread::sleep(std::time::Duration::from_micros(29));
+        }
+    }
+}
+
 fn read_from_row(row: &TextResult) -> (String, u32) {
     let file = String::from_utf8(row.columns[0].clone()).unwrap();
     let pos: u32 = String::from_utf8(row.columns[1].clone())
@@ -114,29 +281,29 @@ fn read_from_row(row: &TextResult) -> (String, u32) {
 pub fn check_valid_pos(p: Arc<Mutex<PositionMng>>, rd: TextResultSet, from_start: bool) -> (String, u32) {
     let record_count = rd.rows.len();
     for row in &rd.rows {
-        println!("rcd: {:?} ", read_from_row(row))
+        debug!("rcd: {:?} ", read_from_row(row))
     }
     loop {
-        if let Ok(mut pm) = p.lock() {
+        if let Ok(pm) = p.lock() {
             if pm.loaded {
                 //如果加载了状态文件，，就检测 from_start标识是否强制覆盖
                 if from_start{
                     let log_name = read_from_row(&rd.rows[0]).0;
-                    println!("已加载meta file，从头读:{} 4", &log_name);
+                    info!("已加载meta file，从头读:{} 4", &log_name);
                     return (log_name, 4);
                 }else{
-                    println!("已加载meta file， 从加载位置开始:{} {}", &pm.binlog, pm.position);
+                    info!("已加载meta file， 从加载位置开始:{} {}", &pm.binlog, pm.position);
                     return (pm.binlog.clone(), pm.position);
                 }
             }else{
                 // 如果没有状态文件，就要根据 from_start标识来判断是从头加载还是加载最后一段
                 if from_start {
                     let log_name = read_from_row(&rd.rows[0]).0;
-                    println!("未加载meta file，从头读:{} 4", &log_name);
+                    info!("未加载meta file，从头读:{} 4", &log_name);
                     return (log_name, 4);
                 }else{
                     let meta_record = read_from_row(&rd.rows[record_count-1]);
-                    println!("未加载meta file， 从最新索引开始:{} {}", &meta_record.0, meta_record.1);
+                    info!("未加载meta file， 从最新索引开始:{} {}", &meta_record.0, meta_record.1);
                     return meta_record;
                 }
             }
@@ -144,11 +311,31 @@ pub fn check_valid_pos(p: Arc<Mutex<PositionMng>>, rd: TextResultSet, from_start
             thread::sleep(std::time::Duration::from_micros(23));
         }
     }
-
-
-    ("".to_string(), 0)
 }
 
+pub fn check_valid_gtid(p: Arc<Mutex<PositionMng>>, rd: TextResultSet, from_start: bool) -> Result<GtidSet, GtidError> {
+    loop {
+        if let Ok(pm) = p.lock() {
+            if from_start {
+                info!("GTID模式，从头读");
+                return Ok(GtidSet::new());
+            }
+            if !pm.gtid_set.is_empty() {
+                info!("已加载meta file， 从加载GTID开始:{}", &pm.gtid_set);
+                return GtidSet::parse(pm.gtid_set.as_str());
+            }
+            // 没有保存GTID就从服务器当前已执行的GTID开始
+            let executed = match rd.rows.first() {
+                Some(row) => String::from_utf8_lossy(row.columns[0].as_slice()).to_string(),
+                None => "".to_string()
+            };
+            info!("未加载GTID， 从最新GTID开始:{}", &executed);
+            return GtidSet::parse(executed.as_str());
+        }else{
+            thread::sleep(std::time::Duration::from_micros(23));
+        }
+    }
+}
 
 fn read_file_content(file_path: String) -> Result<String, std::io::Error> {
     let path = Path::new(&file_path);
@@ -170,4 +357,67 @@ fn read_file_content(file_path: String) -> Result<String, std::io::Error> {
 fn write_file_content(file_path: String, file_data: &[u8]) -> Result<(), std::io::Error> {
     let path = Path::new(&file_path);
     fs::write(path, file_data)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+    use super::*;
+
+    fn tracker() -> (Arc<Mutex<CommitTracker>>, Receiver<PositionSet>) {
+        let (tx, rx) = channel::<PositionSet>();
+        let pos_mng = Arc::new(Mutex::new(PositionMng::new(tx)));
+        (CommitTracker::thread_safe_new(pos_mng), rx)
+    }
+
+    fn checkpoint(position: u32) -> Checkpoint {
+        Checkpoint{ binlog: "binlog.000001".to_string(), position, gtid_set: "".to_string() }
+    }
+
+    #[test]
+    fn checkpoint_waits_for_earlier_deliveries() {
+        let (t, rx) = tracker();
+        track_event(t.clone(), 0, 1);
+        track_checkpoint(t.clone(), 1, checkpoint(100));
+        track_event(t.clone(), 2, 1);
+        track_checkpoint(t.clone(), 3, checkpoint(200));
+        expect_acks(t.clone(), 2, 1);
+        ack_delivery(t.clone(), 2);
+        assert!(rx.try_recv().is_err());
+        expect_acks(t.clone(), 0, 2);
+        ack_delivery(t.clone(), 0);
+        assert!(rx.try_recv().is_err());
+        ack_delivery(t.clone(), 0);
+        // 一次前进到最高的连续提交点
+        assert_eq!(rx.try_recv().unwrap().position, 200);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn failed_delivery_stops_the_pipeline() {
+        let (t, rx) = tracker();
+        track_event(t.clone(), 0, 1);
+        track_checkpoint(t.clone(), 1, checkpoint(100));
+        expect_acks(t.clone(), 0, 1);
+        assert_eq!(pipeline_failure(t.clone()), None);
+        abort_pipeline(t.clone(), "first".to_string());
+        abort_pipeline(t.clone(), "second".to_string());
+        assert_eq!(pipeline_failure(t.clone()), Some("first".to_string()));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn panicking_thread_stops_the_pipeline() {
+        let (t, _rx) = tracker();
+        let the_tracker = t.clone();
+        let handle = thread::spawn(move || {
+            let _guard = PanicGuard{ name: "worker[0]".to_string(), tracker: the_tracker.clone() };
+            // 持有锁时panic，锁会变成poisoned
+            let _lock = the_tracker.lock().unwrap();
+            panic!("decode error");
+        });
+        assert!(handle.join().is_err());
+        assert_eq!(pipeline_failure(t.clone()), Some("worker[0]异常退出".to_string()));
+        track_event(t.clone(), 0, 1);
+    }
+}
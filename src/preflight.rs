@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use crate::config::{Config, MessageFormat, PartitionStrategy, TransactionMode};
+use crate::mysql::{ConnectionError, MySQLConnection};
+use crate::protocal::ComQuery;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckStatus {
+    PASS,
+    /// 可以运行，但部分功能会降级
+    WARN,
+    FAIL,
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckItem {
+    pub name: String,
+    pub actual: String,
+    pub status: CheckStatus,
+    /// 检查不通过时的修改建议
+    pub advice: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    pub items: Vec<CheckItem>
+}
+
+impl CheckReport {
+    pub fn passed(&self) -> bool {
+        self.items.iter().all(|item| item.status != CheckStatus::FAIL)
+    }
+
+    fn add(&mut self, name: &str, actual: &str, status: CheckStatus, advice: &str) {
+        self.items.push(CheckItem{
+            name: name.to_string(),
+            actual: actual.to_string(),
+            status,
+            advice: advice.to_string(),
+        });
+    }
+
+    fn expect(&mut self, name: &str, actual: Option<&String>, expected: &str, status_on_mismatch: CheckStatus, advice: &str) {
+        match actual {
+            Some(val) if val.eq_ignore_ascii_case(expected) => self.add(name, val, CheckStatus::PASS, ""),
+            Some(val) => self.add(name, val, status_on_mismatch, advice),
+            None => self.add(name, "<不支持>", status_on_mismatch, advice),
+        }
+    }
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for item in self.items.iter() {
+            write!(f, "[{:?}] {} = {}", item.status, item.name, item.actual)?;
+            if item.status != CheckStatus::PASS {
+                write!(f, "，{}", item.advice)?;
+            }
+            writeln!(f)?;
+        }
+        if self.passed() {
+            write!(f, "检查通过")
+        } else {
+            write!(f, "检查未通过，请按上面的提示修改后再启动")
+        }
+    }
+}
+
+/// 只检查配置文件本身，不需要连接数据库
+pub fn check_config(config: &Config, report: &mut CheckReport) {
+    if let Some(host) = config.report_host.as_ref().filter(|host| host.len() > u8::MAX as usize) {
+        report.add("report_host", host, CheckStatus::FAIL, "注册从库时主机名最长255字节");
+    }
+    let batch = config.transaction_mode.as_ref().is_some_and(|mode| *mode == TransactionMode::BATCH);
+    for instance in config.instances.iter() {
+        let name = format!("instance[{}].partition", instance.topic);
+        let partition = instance.partition.clone().unwrap_or(PartitionStrategy::DEFAULT);
+        // BATCH模式一个事务合并为一条消息，只能用第一行的key和分区，按主键分区没有意义
+        if batch && partition == PartitionStrategy::PRIMARY_KEY {
+            report.add(name.as_str(), "PRIMARY_KEY", CheckStatus::FAIL, "transaction_mode为BATCH时整个事务是一条消息，不能按主键分区，请改用TABLE或DEFAULT");
+        }
+        if !config.mqs.iter().any(|mq| mq.mq_name == instance.mq) {
+            let name = format!("instance[{}].mq", instance.topic);
+            report.add(name.as_str(), instance.mq.as_str(), CheckStatus::FAIL, "mqs中没有这个名字的消息队列");
+        }
+        if instance.format == Some(MessageFormat::AVRO) && config.schema_registry.is_none() {
+            let name = format!("instance[{}].format", instance.topic);
+            report.add(name.as_str(), "AVRO", CheckStatus::FAIL, "AVRO格式需要配置schema_registry");
+        }
+    }
+}
+
+/// 检查服务器的binlog配置和当前用户的复制权限
+pub fn check(conn: &mut MySQLConnection, config: &Config) -> Result<CheckReport, ConnectionError> {
+    let mut report = CheckReport::default();
+    check_config(config, &mut report);
+    let vars = server_variables(conn)?;
+    check_variables(&vars, config, &mut report);
+    let server_id = config.server_id.unwrap_or(100);
+
+    let grants = current_grants(conn)?;
+    for privilege in ["REPLICATION SLAVE", "REPLICATION CLIENT"] {
+        if grants.iter().any(|g| g.contains(privilege) || g.contains("ALL PRIVILEGES")) {
+            report.add(privilege, "已授权", CheckStatus::PASS, "");
+        } else {
+            let advice = format!("GRANT {privilege} ON *.* TO '{}'@'%'(通过角色授予的权限不会被识别)", config.user_name);
+            report.add(privilege, "未授权", CheckStatus::FAIL, advice.as_str());
+        }
+    }
+
+    match replica_server_ids(conn)? {
+        Some(ids) => {
+            match ids.iter().find(|(id, _)| *id == server_id.to_string()) {
+                Some((_, host)) => {
+                    let advice = format!("已经被从库{host}使用，请修改配置中的server_id");
+                    report.add("replica server_id", server_id.to_string().as_str(), CheckStatus::FAIL, advice.as_str());
+                },
+                None => report.add("replica server_id", server_id.to_string().as_str(), CheckStatus::PASS, ""),
+            }
+        },
+        None => report.add("replica server_id", server_id.to_string().as_str(), CheckStatus::WARN, "无法查询从库列表，不能确认server_id是否重复"),
+    }
+    Ok(report)
+}
+
+/// 检查binlog相关的服务器变量
+fn check_variables(vars: &HashMap<String, String>, config: &Config, report: &mut CheckReport) {
+    report.expect("log_bin", vars.get("log_bin"), "ON", CheckStatus::FAIL, "需要开启binlog: log_bin=ON");
+    report.expect("binlog_format", vars.get("binlog_format"), "ROW", CheckStatus::FAIL, "只支持行格式: SET GLOBAL binlog_format='ROW'");
+    // MINIMAL/NOBLOB 可以解析，但是消息中只有镜像中的列，Debezium的before/after也不完整
+    report.expect("binlog_row_image", vars.get("binlog_row_image"), "FULL", CheckStatus::WARN, "行镜像不完整时消息中只有镜像中的列，建议 SET GLOBAL binlog_row_image='FULL'");
+    report.expect("binlog_row_metadata", vars.get("binlog_row_metadata"), "FULL", CheckStatus::WARN, "建议 SET GLOBAL binlog_row_metadata='FULL'(8.0.1+)，否则列名从表结构查询，DDL之前的事件可能对不上列名");
+    if config.gtid_mode.is_some_and(|b| b) {
+        report.expect("gtid_mode", vars.get("gtid_mode"), "ON", CheckStatus::FAIL, "配置开启了gtid_mode，服务器也需要 gtid_mode=ON");
+    } else {
+        let gtid_mode = vars.get("gtid_mode").cloned().unwrap_or("<不支持>".to_string());
+        report.add("gtid_mode", gtid_mode.as_str(), CheckStatus::PASS, "");
+    }
+
+    let server_id = config.server_id.unwrap_or(100);
+    match vars.get("server_id") {
+        Some(id) if id == "0" => report.add("server_id", id, CheckStatus::FAIL, "主库server_id为0时不能复制，需要设置一个非0的server_id"),
+        Some(id) if *id == server_id.to_string() => report.add("server_id", id, CheckStatus::FAIL, "和配置中的server_id相同，请修改配置中的server_id"),
+        Some(id) => report.add("server_id", id, CheckStatus::PASS, ""),
+        None => report.add("server_id", "<未知>", CheckStatus::WARN, "无法读取主库server_id"),
+    }
+}
+
+fn server_variables(conn: &mut MySQLConnection) -> Result<HashMap<String, String>, ConnectionError> {
+    let query: ComQuery = "show global variables where Variable_name in ('log_bin', 'binlog_format', 'binlog_row_image', 'binlog_row_metadata', 'gtid_mode', 'server_id')".into();
+    conn.write_package(0, &query)?;
+    let text_resp = conn.read_text_result_set().map_err(|err| ConnectionError::Protocol(format!("查询服务器变量失败: {err}")))?;
+    let mut vars = HashMap::new();
+    for row in text_resp.rows.iter() {
+        if row.columns.len() >= 2 {
+            let name = String::from_utf8_lossy(row.columns[0].as_slice()).to_lowercase();
+            let value = String::from_utf8_lossy(row.columns[1].as_slice()).to_string();
+            vars.insert(name, value);
+        }
+    }
+    Ok(vars)
+}
+
+/// 只看全局权限，每一行形如 GRANT REPLICATION SLAVE, REPLICATION CLIENT ON *.* TO `u`@`%`
+fn current_grants(conn: &mut MySQLConnection) -> Result<Vec<String>, ConnectionError> {
+    let query: ComQuery = "show grants".into();
+    conn.write_package(0, &query)?;
+    let text_resp = conn.read_text_result_set().map_err(|err| ConnectionError::Protocol(format!("查询用户权限失败: {err}")))?;
+    Ok(text_resp.rows.iter()
+        .map(|row| String::from_utf8_lossy(row.columns[0].as_slice()).to_uppercase())
+        .filter(|grant| grant.contains(" ON *.* "))
+        .collect())
+}
+
+/// 已注册从库的 (server_id, host)，没有权限时返回None
+fn replica_server_ids(conn: &mut MySQLConnection) -> Result<Option<Vec<(String, String)>>, ConnectionError> {
+    // 8.0.22之前没有 SHOW REPLICAS，8.4之后没有 SHOW SLAVE HOSTS
+    for sql in ["show replicas", "show slave hosts"] {
+        let query: ComQuery = sql.into();
+        conn.write_package(0, &query)?;
+        match conn.read_text_result_set() {
+            Ok(text_resp) => return Ok(Some(text_resp.rows.iter().map(|row| {
+                let id = String::from_utf8_lossy(row.columns[0].as_slice()).to_string();
+                let host = String::from_utf8_lossy(row.columns.get(1).map(|c| c.as_slice()).unwrap_or_default()).to_string();
+                (id, host)
+            }).collect())),
+            // 语句不支持或者没有权限时服务器返回ERR包，换下一种写法
+            Err(err @ ConnectionError::Io(_)) => return Err(err),
+            Err(_) => {}
+        }
+    }
+    Ok(None)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SchemaRegistryConfig;
+
+    #[test]
+    fn batch_rejects_primary_key_partition() {
+        let mut config = Config::gen_default();
+        let mut report = CheckReport::default();
+        check_config(&config, &mut report);
+        assert!(report.passed());
+
+        config.transaction_mode = Some(TransactionMode::BATCH);
+        config.instances[0].partition = Some(PartitionStrategy::TABLE);
+        let mut report = CheckReport::default();
+        check_config(&config, &mut report);
+        assert!(report.passed());
+
+        config.instances[0].partition = Some(PartitionStrategy::PRIMARY_KEY);
+        let mut report = CheckReport::default();
+        check_config(&config, &mut report);
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn instance_mq_must_be_configured() {
+        let mut config = Config::gen_default();
+        config.instances[0].mq = "not_exists".to_string();
+        let mut report = CheckReport::default();
+        check_config(&config, &mut report);
+        assert!(report.items.iter().any(|item| item.name == "instance[db_change].mq" && item.status == CheckStatus::FAIL));
+    }
+
+    #[test]
+    fn avro_requires_schema_registry() {
+        let mut config = Config::gen_default();
+        config.instances[0].format = Some(MessageFormat::AVRO);
+        let mut report = CheckReport::default();
+        check_config(&config, &mut report);
+        assert!(!report.passed());
+
+        config.schema_registry = Some(SchemaRegistryConfig{ url: "http://127.0.0.1:8081".to_string(), username: None, password: None });
+        let mut report = CheckReport::default();
+        check_config(&config, &mut report);
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn partial_row_image_is_a_warning() {
+        let config = Config::gen_default();
+        let mut vars: HashMap<String, String> = [("log_bin", "ON"), ("binlog_format", "ROW"), ("binlog_row_image", "MINIMAL"), ("binlog_row_metadata", "FULL"), ("gtid_mode", "OFF"), ("server_id", "1")]
+            .into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let mut report = CheckReport::default();
+        check_variables(&vars, &config, &mut report);
+        assert!(report.passed());
+        assert!(report.items.iter().any(|item| item.name == "binlog_row_image" && item.status == CheckStatus::WARN));
+
+        vars.insert("binlog_format".to_string(), "MIXED".to_string());
+        let mut report = CheckReport::default();
+        check_variables(&vars, &config, &mut report);
+        assert!(!report.passed());
+    }
+}
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 use bytes::{BufMut, BytesMut};
 use nom::{IResult, AsBytes};
-use nom::error::{Error, ErrorKind, VerboseError, VerboseErrorKind};
+use nom::error::{Error, ErrorKind};
 use nom::Err as NomErr;
-use crate::mysql::{Decoder, take_int1, take_int2, take_int4, take_bytes, take_utf8_end_of_null, Encoder, take_int3, write_var_bytes, write_null_term_str, write_var_str, take_var_string, take_var_bytes};
+use log::error;
+use crate::binlog::GtidSet;
+use crate::mysql::{Decoder, take_int1, take_int2, take_int4, take_int8, take_bytes, take_utf8_end_of_null, Encoder, take_int3, write_var_bytes, write_null_term_str, write_var_str, take_var_string, take_var_bytes};
 
 
 #[derive(Debug, Clone)]
@@ -24,11 +26,8 @@ impl Decoder for HandshakeV10 {
         if protocol_version != 10 {
             return Err(NomErr::Error(Error::new("".as_ref(), ErrorKind::Not)));
         }
-        //println!("server_version: {:?}\n", i.to_vec());
         let (i, server_version) = take_utf8_end_of_null(i)?;
-        //println!("thread_id: {:?}\n", i.to_vec());
         let (i, thread_id) = take_int4(i)?;
-        //println!("auth_plugin_data_bytes: {:?}\n", i.to_vec());
         let (i, auth_plugin_data_bytes) = take_bytes(i, 8)?;
         let (i, _) = take_bytes(i, 1)?;
         let mut auth_plugin_data = BytesMut::from_iter(auth_plugin_data_bytes);
@@ -37,13 +36,10 @@ impl Decoder for HandshakeV10 {
         let (i, charset) = take_int1(i)?;
         let (i, status) = take_int2(i)?;
         let (i, h_cap) = take_bytes(i, 2usize)?;
-        //println!("auth_data_len: {:?}\n", i.to_vec());
         let (i, auth_data_len) = take_int1(i)?;
-        //println!("take_bytes 10: {:?}\n", i.to_vec());
         let (i, _) = take_bytes(i,10usize)?;
         let i = if auth_data_len > 0{
             let len = 13.max(auth_data_len - 8) as usize;
-            //println!("auth_data_len大于0: {}  {}", auth_data_len, len);
             let (ip, bs) = take_bytes(i, len)?;
             auth_plugin_data.extend_from_slice(bs);
             ip
@@ -55,7 +51,7 @@ impl Decoder for HandshakeV10 {
         caps_num[2..].copy_from_slice(h_cap);
         let caps = Capabilities::from_bits(u32::from_le_bytes(caps_num)).unwrap();
         let auth_plugin_name = if caps.contains(Capabilities::CLIENT_PLUGIN_AUTH) {
-            let (i, name) = take_utf8_end_of_null(i)?;
+            let (_i, name) = take_utf8_end_of_null(i)?;
             name
         } else {
             Default::default()
@@ -94,7 +90,7 @@ impl Encoder for HandshakeResponse41 {
         buffer.extend_from_slice(&self.caps.bits().to_le_bytes());
         buffer.extend_from_slice(&self.max_packet_size.to_le_bytes());
         buffer.extend_from_slice(&[self.charset]);
-        buffer.extend_from_slice(&vec![0].repeat(23));
+        buffer.extend_from_slice(&[0].repeat(23));
         buffer.extend_from_slice(self.user_name.as_bytes());
         buffer.put_u8(b'\0');
         if self
@@ -125,6 +121,23 @@ impl Encoder for HandshakeResponse41 {
     }
 }
 
+/// 握手响应之前发送，之后客户端开始TLS握手
+#[derive(Debug, Clone)]
+pub struct SslRequest {
+    pub caps: Capabilities,
+    pub max_packet_size: u32, //int4
+    pub charset: u8,         //int1
+}
+
+impl Encoder for SslRequest {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.extend_from_slice(&self.caps.bits().to_le_bytes());
+        buffer.extend_from_slice(&self.max_packet_size.to_le_bytes());
+        buffer.extend_from_slice(&[self.charset]);
+        buffer.extend_from_slice(&[0].repeat(23));
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AuthSwitchReq {
     pub plugin_name: String,
@@ -138,15 +151,11 @@ impl AuthSwitchReq {
 impl Decoder for AuthSwitchReq {
     fn decode(input: &[u8]) -> IResult<&[u8], Self> where Self: Sized {
         let (i, tag) =take_int1(input)?;
-        //println!("auth switch: {:?}", input);
-        if tag != 0xfe {
-            //let (_, err_pack) = ErrPacket::decode(input).expect("bhbhbhbh");
-            let (_, ok_pack) = OkPacket::decode(input).expect("decode ok error");
-            println!("err pack:{:?}", ok_pack);
-            return Err(NomErr::Error(Error::new("".as_ref(), ErrorKind::Fail)));
+        if tag != Self::STATUS {
+            return Err(NomErr::Error(Error::new(input, ErrorKind::Tag)));
         }
         let (i, plugin_name) = take_utf8_end_of_null(i)?;
-        let plugin_data = if i.len()>0 {
+        let plugin_data = if !i.is_empty() {
             BytesMut::from_iter(i)
         }else{
             BytesMut::new()
@@ -158,6 +167,32 @@ impl Decoder for AuthSwitchReq {
     }
 }
 
+/// 认证过程中服务器发送的额外数据，caching_sha2_password 用它通知快速认证结果或者发送公钥
+#[derive(Debug, Clone)]
+pub struct AuthMoreData {
+    pub data: BytesMut,
+}
+
+impl AuthMoreData {
+    pub const STATUS: u8 = 0x01;
+    /// caching_sha2_password: 客户端请求服务器公钥
+    pub const REQUEST_PUBLIC_KEY: u8 = 0x02;
+    pub const FAST_AUTH_SUCCESS: u8 = 0x03;
+    pub const PERFORM_FULL_AUTH: u8 = 0x04;
+}
+
+impl Decoder for AuthMoreData {
+    fn decode(input: &[u8]) -> IResult<&[u8], Self> where Self: Sized {
+        let (i, tag) = take_int1(input)?;
+        if tag != Self::STATUS {
+            return Err(NomErr::Error(Error::new(input, ErrorKind::Tag)));
+        }
+        Ok((&[], AuthMoreData {
+            data: BytesMut::from_iter(i),
+        }))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AuthSwitchResp {
     pub data: BytesMut,
@@ -207,7 +242,7 @@ impl Decoder for TextResult {
     fn decode(input: &[u8]) -> IResult<&[u8], Self> where Self: Sized {
         let mut columns = vec![];
         let mut i = input;
-        while i.len() > 0 {
+        while !i.is_empty() {
             if i[0] != 0x00 {
                 if i[0] == 0xfb {
                     i = &i[1..];
@@ -264,11 +299,11 @@ impl Decoder for ColDef {
             name,
             original_name,
             length_of_fixed_length_fields,
-            charset: charset as u16,
+            charset,
             column_length,
-            ty: ty as u8,
-            flags: flags as u16,
-            decimals: decimals as u8,
+            ty,
+            flags,
+            decimals,
         }))
     }
 }
@@ -292,6 +327,68 @@ impl Encoder for ComBinLogDump {
     }
 }
 
+/// 注册为从库，注册后会出现在 SHOW REPLICAS 中
+#[derive(Debug, Clone)]
+pub struct ComRegisterSlave {
+    pub server_id: u32,
+    pub hostname: String,
+    pub user: String,
+    pub password: String,
+    pub port: u16,
+}
+
+impl ComRegisterSlave {
+    /// 主机名、用户和密码的长度只有1字节，超过255字节时返回超长的字段名
+    pub fn oversized_field(&self) -> Option<&'static str> {
+        [("report_host", &self.hostname), ("user", &self.user), ("password", &self.password)].into_iter()
+            .find(|(_, field)| field.len() > u8::MAX as usize)
+            .map(|(name, _)| name)
+    }
+}
+
+impl Encoder for ComRegisterSlave {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u8(0x15);
+        buf.extend_from_slice(u32::to_le_bytes(self.server_id).as_bytes());
+        for field in [&self.hostname, &self.user, &self.password] {
+            buf.put_u8(field.len() as u8);
+            buf.extend_from_slice(field.as_bytes());
+        }
+        buf.extend_from_slice(u16::to_le_bytes(self.port).as_bytes());
+        // replication_rank和master_id，服务器会忽略
+        buf.extend_from_slice(u32::to_le_bytes(0).as_bytes());
+        buf.extend_from_slice(u32::to_le_bytes(0).as_bytes());
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ComBinLogDumpGtid {
+    pub flags: u16,
+    pub server_id: u32,
+    pub filename: String,
+    pub pos: u64,
+    pub gtid_set: GtidSet,
+}
+
+impl ComBinLogDumpGtid {
+    pub const BINLOG_THROUGH_GTID: u16 = 0x04;
+}
+
+impl Encoder for ComBinLogDumpGtid {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u8(0x1e);
+        buf.extend_from_slice(u16::to_le_bytes(self.flags | Self::BINLOG_THROUGH_GTID).as_bytes());
+        buf.extend_from_slice(u32::to_le_bytes(self.server_id).as_bytes());
+        buf.extend_from_slice(u32::to_le_bytes(self.filename.len() as u32).as_bytes());
+        buf.extend_from_slice(self.filename.as_bytes());
+        buf.extend_from_slice(u64::to_le_bytes(self.pos).as_bytes());
+        let mut data = BytesMut::new();
+        self.gtid_set.encode(&mut data);
+        buf.extend_from_slice(u32::to_le_bytes(data.len() as u32).as_bytes());
+        buf.extend_from_slice(data.as_bytes());
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ComPing {
 }
@@ -327,21 +424,22 @@ impl Decoder for OkPacket {
     fn decode(input: &[u8]) -> IResult<&[u8], Self> where Self: Sized {
         let (i, header) = take_int1(input)?;
         if header == 0xff {
-            let (_, err_pack) = ErrPacket::decode(input).expect("Error Pack");
-            println!("err pack:{:?}", err_pack);
+            if let Ok((_, err_pack)) = ErrPacket::decode(input) {
+                error!("服务器返回错误包:{err_pack:?}");
+            }
             return Err(NomErr::Error(Error::new(input, ErrorKind::Fail)));
         }
         let (i, affected_rows) = VLenInt::decode(i)?;
         let (i, last_insert_id) = VLenInt::decode(i)?;
         let (i, status_flags) = take_int2(i)?;
-        let (i, warnings) = take_int2(i)?;
+        let (_i, warnings) = take_int2(i)?;
         let info = String::new();
         Ok((&[], Self {
-            header: header as u8,
+            header,
             affected_rows,
             last_insert_id,
-            status_flags: status_flags as u16,
-            warnings: warnings as u16,
+            status_flags,
+            warnings,
             info,
         }))
 
@@ -366,10 +464,10 @@ impl Decoder for ErrPacket {
         let sql_state = String::from_utf8(Vec::from(state)).expect("err state");
         let error_msg = String::from_utf8(Vec::from(i)).expect("err msg");
         Ok((i, Self {
-            header: header as u8,
-            code: code as u16,
+            header,
+            code,
             sql_state,
-            sql_state_marker:marker as u8,
+            sql_state_marker:marker,
             error_msg,
         }))
     }
@@ -500,18 +598,18 @@ impl VLenInt {
 impl Decoder for VLenInt {
     fn decode(input: &[u8]) -> IResult<&[u8], Self> where Self: Sized {
         match input[0] {
-            val @ 0..=0xfb => Ok((&input[1..], Self(input[0] as u64))),
+            _val @ 0..=0xfb => Ok((&input[1..], Self(input[0] as u64))),
             0xfc => {
-                let (i, val) = take_int2(input)?;
-                Ok((&[], Self(val as u64)))
+                let (i, val) = take_int2(&input[1..])?;
+                Ok((i, Self(val as u64)))
             }
             0xfd => {
-                let (i, val) = take_int3(input)?;
-                Ok((&[], Self(val as u64)))
+                let (i, val) = take_int3(&input[1..])?;
+                Ok((i, Self(val as u64)))
             }
             0xfe => {
-                let (i, val) = take_int4(input)?;
-                Ok((&[], Self(val as u64)))
+                let (i, val) = take_int8(&input[1..])?;
+                Ok((i, Self(val)))
             },
             0xff => Err(NomErr::Error(Error::new("".as_ref(), ErrorKind::Fail))),
         }
@@ -536,4 +634,20 @@ impl Encoder for VLenInt {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_slave_encoding() {
+        let mut register = ComRegisterSlave{ server_id: 100, hostname: "cdc".to_string(), user: "".to_string(), password: "".to_string(), port: 3306 };
+        assert_eq!(register.oversized_field(), None);
+        let mut buf = BytesMut::new();
+        register.encode(&mut buf);
+        assert_eq!(buf.as_bytes(), &[0x15, 100, 0, 0, 0, 3, b'c', b'd', b'c', 0, 0, 0xea, 0x0c, 0, 0, 0, 0, 0, 0, 0, 0]);
+        register.hostname = "h".repeat(256);
+        assert_eq!(register.oversized_field(), Some("report_host"));
+    }
+}
@@ -29,8 +29,6 @@ impl Statistics {
             self.last_checkpoint = ts;
             let dts = local_time.format("%Y/%m/%d %H:%M:%S");
             println!("{dts} |=> 处理包计数:{seq_idx}，总流量:{total:02}MB 当前速率:{mb_rate:0.2} MB/s");
-        }else {
-            //println!("AAA: {} {} {}", ts, self.last_checkpoint, ts - self.last_checkpoint)
         }
     }
 }
\ No newline at end of file